@@ -0,0 +1,49 @@
+CREATE TABLE IF NOT EXISTS `users` (
+    `id` VARCHAR(64) NOT NULL,
+    `name` VARCHAR(64) NOT NULL,
+    `created_at` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (`id`)
+);
+
+CREATE TABLE IF NOT EXISTS `sessions` (
+    `token` CHAR(64) NOT NULL,
+    `user_id` VARCHAR(64) NOT NULL,
+    `created_at` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (`token`),
+    KEY `sessions_user` (`user_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `channels` (
+    `id` VARCHAR(64) NOT NULL,
+    `name` VARCHAR(100) NOT NULL,
+    `owner` VARCHAR(64) NOT NULL,
+    `icon` CHAR(64) NULL,
+    PRIMARY KEY (`id`)
+);
+
+CREATE TABLE IF NOT EXISTS `messages` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` VARCHAR(64) NOT NULL,
+    `author_id` VARCHAR(64) NOT NULL,
+    `content` TEXT NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    `edited_at` DATETIME NULL,
+    `deleted_at` DATETIME NULL,
+    PRIMARY KEY (`id`),
+    KEY `messages_channel` (`channel_id`, `id`)
+);
+
+CREATE TABLE IF NOT EXISTS `message_attachments` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `position` INT UNSIGNED NOT NULL,
+    `hash` CHAR(64) NOT NULL,
+    PRIMARY KEY (`message_id`, `position`),
+    KEY `message_attachments_hash` (`hash`)
+);
+
+CREATE TABLE IF NOT EXISTS `message_edits` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `content` TEXT NOT NULL,
+    `edited_at` DATETIME NOT NULL,
+    KEY `message_edits_message` (`message_id`, `edited_at`)
+);
@@ -0,0 +1,36 @@
+-- READ | SEND | UPLOAD
+ALTER TABLE `channels` ADD COLUMN `default_permissions` BIGINT UNSIGNED NOT NULL DEFAULT 67;
+
+CREATE TABLE IF NOT EXISTS `channel_members` (
+    `channel_id` VARCHAR(64) NOT NULL,
+    `user_id` VARCHAR(64) NOT NULL,
+    `joined_at` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (`channel_id`, `user_id`),
+    KEY `channel_members_user` (`user_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `channel_roles` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` VARCHAR(64) NOT NULL,
+    `name` VARCHAR(64) NOT NULL,
+    `permissions` BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    PRIMARY KEY (`id`),
+    KEY `channel_roles_channel` (`channel_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `member_roles` (
+    `channel_id` VARCHAR(64) NOT NULL,
+    `user_id` VARCHAR(64) NOT NULL,
+    `role_id` BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`channel_id`, `user_id`, `role_id`),
+    KEY `member_roles_role` (`role_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `channel_overwrites` (
+    `channel_id` VARCHAR(64) NOT NULL,
+    `target_type` ENUM('role', 'member') NOT NULL,
+    `target_id` VARCHAR(64) NOT NULL,
+    `allow` BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    `deny` BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    PRIMARY KEY (`channel_id`, `target_type`, `target_id`)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS `invites` (
+    `code` VARCHAR(16) NOT NULL,
+    `channel_id` VARCHAR(64) NOT NULL,
+    `creator_id` VARCHAR(64) NOT NULL,
+    `role_id` BIGINT UNSIGNED NULL,
+    `max_uses` INT UNSIGNED NULL,
+    `uses` INT UNSIGNED NOT NULL DEFAULT 0,
+    `expires_at` DATETIME NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`code`),
+    KEY `invites_channel` (`channel_id`)
+);
@@ -0,0 +1,9 @@
+ALTER TABLE `users` MODIFY `id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `sessions` MODIFY `user_id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `channels` MODIFY `id` BIGINT UNSIGNED NOT NULL, MODIFY `owner` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `messages` MODIFY `channel_id` BIGINT UNSIGNED NOT NULL, MODIFY `author_id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `channel_members` MODIFY `channel_id` BIGINT UNSIGNED NOT NULL, MODIFY `user_id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `channel_roles` MODIFY `channel_id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `member_roles` MODIFY `channel_id` BIGINT UNSIGNED NOT NULL, MODIFY `user_id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `channel_overwrites` MODIFY `channel_id` BIGINT UNSIGNED NOT NULL, MODIFY `target_id` BIGINT UNSIGNED NOT NULL;
+ALTER TABLE `invites` MODIFY `channel_id` BIGINT UNSIGNED NOT NULL, MODIFY `creator_id` BIGINT UNSIGNED NOT NULL;
@@ -0,0 +1,18 @@
+ALTER TABLE `channels` ADD COLUMN `kind` ENUM('text', 'dm', 'group_dm') NOT NULL DEFAULT 'text';
+
+-- one row per DM, `user_a` < `user_b` so a pair can only exist once
+CREATE TABLE IF NOT EXISTS `dm_pairs` (
+    `user_a` BIGINT UNSIGNED NOT NULL,
+    `user_b` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`user_a`, `user_b`),
+    UNIQUE KEY `dm_pairs_channel` (`channel_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `user_blocks` (
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `blocked_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (`user_id`, `blocked_id`),
+    KEY `user_blocks_blocked` (`blocked_id`)
+);
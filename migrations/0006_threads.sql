@@ -0,0 +1,18 @@
+ALTER TABLE `messages` ADD COLUMN `reply_to` BIGINT UNSIGNED NULL;
+
+ALTER TABLE `channels`
+    MODIFY `kind` ENUM('text', 'dm', 'group_dm', 'thread') NOT NULL DEFAULT 'text',
+    ADD COLUMN `parent_id` BIGINT UNSIGNED NULL,
+    ADD KEY `channels_parent` (`parent_id`);
+
+CREATE TABLE IF NOT EXISTS `threads` (
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `parent_id` BIGINT UNSIGNED NOT NULL,
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `archived` BOOLEAN NOT NULL DEFAULT FALSE,
+    `auto_archive_minutes` INT UNSIGNED NOT NULL DEFAULT 1440,
+    `last_activity_at` DATETIME NOT NULL,
+    PRIMARY KEY (`channel_id`),
+    UNIQUE KEY `threads_message` (`message_id`),
+    KEY `threads_parent` (`parent_id`, `archived`)
+);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS `custom_emojis` (
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `hash` CHAR(64) NOT NULL,
+    `name` VARCHAR(32) NOT NULL,
+    `creator_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`channel_id`, `hash`),
+    UNIQUE KEY `custom_emojis_name` (`channel_id`, `name`)
+);
+
+-- `emoji` is the Unicode emoji itself, or the hash of a custom emoji
+CREATE TABLE IF NOT EXISTS `message_reactions` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `emoji` VARCHAR(64) NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`message_id`, `emoji`, `user_id`)
+);
@@ -0,0 +1 @@
+ALTER TABLE `messages` ADD FULLTEXT KEY `messages_content` (`content`);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS `message_mentions` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`message_id`, `user_id`),
+    KEY `message_mentions_user` (`user_id`, `channel_id`, `message_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `read_states` (
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `last_read_id` BIGINT UNSIGNED NOT NULL,
+    `updated_at` DATETIME NOT NULL,
+    PRIMARY KEY (`user_id`, `channel_id`)
+);
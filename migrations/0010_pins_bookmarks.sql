@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS `message_pins` (
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `pinned_by` BIGINT UNSIGNED NOT NULL,
+    `pinned_at` DATETIME NOT NULL,
+    PRIMARY KEY (`channel_id`, `message_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `bookmarks` (
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `note` VARCHAR(500) NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`user_id`, `message_id`)
+);
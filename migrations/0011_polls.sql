@@ -0,0 +1,30 @@
+ALTER TABLE `messages` ADD COLUMN `kind` ENUM('default', 'poll') NOT NULL DEFAULT 'default';
+
+CREATE TABLE IF NOT EXISTS `polls` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `question` VARCHAR(300) NOT NULL,
+    `allow_multiple` BOOLEAN NOT NULL DEFAULT FALSE,
+    `ends_at` DATETIME NOT NULL,
+    `closed_at` DATETIME NULL,
+    -- results frozen when the poll closes, as JSON
+    `results` TEXT NULL,
+    PRIMARY KEY (`message_id`),
+    KEY `polls_open` (`closed_at`, `ends_at`)
+);
+
+-- `emoji` is the Unicode emoji itself, or the hash of a custom emoji
+CREATE TABLE IF NOT EXISTS `poll_options` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `position` TINYINT UNSIGNED NOT NULL,
+    `text` VARCHAR(100) NOT NULL,
+    `emoji` VARCHAR(64) NULL,
+    `image` CHAR(64) NULL,
+    PRIMARY KEY (`message_id`, `position`)
+);
+
+CREATE TABLE IF NOT EXISTS `poll_votes` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `position` TINYINT UNSIGNED NOT NULL,
+    PRIMARY KEY (`message_id`, `user_id`, `position`)
+);
@@ -0,0 +1,17 @@
+ALTER TABLE `messages` ADD COLUMN `expires_at` DATETIME NULL;
+
+-- background jobs, `payload` is the JSON of the job
+CREATE TABLE IF NOT EXISTS `jobs` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `kind` VARCHAR(32) NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NULL,
+    `payload` MEDIUMTEXT NOT NULL,
+    `run_at` DATETIME NOT NULL,
+    `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+    `locked_until` DATETIME NULL,
+    `last_error` TEXT NULL,
+    PRIMARY KEY (`id`),
+    KEY `jobs_due` (`run_at`),
+    KEY `jobs_channel` (`channel_id`, `kind`, `user_id`)
+);
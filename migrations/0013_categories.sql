@@ -0,0 +1,13 @@
+-- categories and positions belong to each user, who orders their own channel list
+CREATE TABLE IF NOT EXISTS `channel_categories` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(100) NOT NULL,
+    `position` INT UNSIGNED NOT NULL DEFAULT 0,
+    PRIMARY KEY (`id`),
+    KEY `channel_categories_user` (`user_id`)
+);
+
+ALTER TABLE `channel_members`
+    ADD COLUMN `category_id` BIGINT UNSIGNED NULL,
+    ADD COLUMN `position` INT UNSIGNED NOT NULL DEFAULT 0;
@@ -0,0 +1,24 @@
+ALTER TABLE `channel_members` ADD COLUMN `timeout_until` DATETIME NULL;
+
+CREATE TABLE IF NOT EXISTS `channel_bans` (
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `banned_by` BIGINT UNSIGNED NOT NULL,
+    `reason` VARCHAR(512) NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`channel_id`, `user_id`)
+);
+
+-- append-only, nothing updates or deletes these rows
+CREATE TABLE IF NOT EXISTS `audit_log` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `actor_id` BIGINT UNSIGNED NOT NULL,
+    `action` VARCHAR(32) NOT NULL,
+    `target_id` BIGINT UNSIGNED NULL,
+    `reason` VARCHAR(512) NULL,
+    `details` TEXT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    KEY `audit_log_channel` (`channel_id`, `action`, `actor_id`)
+);
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS `automod_rules` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(100) NOT NULL,
+    -- JSON of the trigger and of the action
+    `trigger` TEXT NOT NULL,
+    `action` VARCHAR(255) NOT NULL,
+    `enabled` BOOLEAN NOT NULL DEFAULT TRUE,
+    `creator_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    KEY `automod_rules_channel` (`channel_id`)
+);
+
+-- messages waiting for review
+CREATE TABLE IF NOT EXISTS `automod_flags` (
+    `message_id` BIGINT UNSIGNED NOT NULL,
+    `rule_id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `author_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`message_id`, `rule_id`),
+    KEY `automod_flags_channel` (`channel_id`, `message_id`)
+);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS `reports` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `origin_channel_id` BIGINT UNSIGNED NOT NULL,
+    `reporter_id` BIGINT UNSIGNED NOT NULL,
+    -- JSON of the target, compared as a string to group reports on the same content
+    `target` VARCHAR(255) NOT NULL,
+    `target_user_id` BIGINT UNSIGNED NULL,
+    `category` VARCHAR(16) NOT NULL,
+    `comment` VARCHAR(1000) NULL,
+    `snapshot` MEDIUMTEXT NOT NULL,
+    `resolution` VARCHAR(16) NULL,
+    `resolved_by` BIGINT UNSIGNED NULL,
+    `resolved_at` DATETIME NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    KEY `reports_queue` (`channel_id`, `resolution`, `id`),
+    KEY `reports_target` (`channel_id`, `target`)
+);
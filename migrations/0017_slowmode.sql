@@ -0,0 +1,4 @@
+ALTER TABLE `channels` ADD COLUMN `slowmode_seconds` INT UNSIGNED NOT NULL DEFAULT 0;
+
+-- last message of a member in a channel
+ALTER TABLE `messages` ADD KEY `messages_channel_author` (`channel_id`, `author_id`, `id`);
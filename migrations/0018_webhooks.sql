@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS `webhooks` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(80) NOT NULL,
+    `avatar` VARCHAR(64) NULL,
+    -- hashed like session tokens
+    `token` VARCHAR(64) NOT NULL,
+    `creator_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    KEY `webhooks_channel` (`channel_id`)
+);
+
+-- webhook messages use the webhook id as author, its name and avatar as they were when posting
+ALTER TABLE `messages`
+    ADD COLUMN `embeds` TEXT NULL,
+    ADD COLUMN `webhook_id` BIGINT UNSIGNED NULL,
+    ADD COLUMN `webhook_name` VARCHAR(80) NULL,
+    ADD COLUMN `webhook_avatar` VARCHAR(64) NULL;
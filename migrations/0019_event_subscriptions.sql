@@ -0,0 +1,38 @@
+CREATE TABLE IF NOT EXISTS `event_subscriptions` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `url` VARCHAR(2048) NOT NULL,
+    -- kept in clear, deliveries are signed with it
+    `secret` VARCHAR(64) NOT NULL,
+    -- JSON array of event types
+    `events` TEXT NOT NULL,
+    `enabled` BOOLEAN NOT NULL DEFAULT TRUE,
+    -- failed deliveries in a row, reset by a success
+    `failure_count` INT UNSIGNED NOT NULL DEFAULT 0,
+    `disabled_reason` VARCHAR(255) NULL,
+    `creator_id` BIGINT UNSIGNED NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    KEY `event_subscriptions_channel` (`channel_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `event_deliveries` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `subscription_id` BIGINT UNSIGNED NOT NULL,
+    `event` VARCHAR(32) NOT NULL,
+    `payload` MEDIUMTEXT NOT NULL,
+    -- pending, succeeded or failed
+    `status` VARCHAR(16) NOT NULL DEFAULT 'pending',
+    `attempts` INT UNSIGNED NOT NULL DEFAULT 0,
+    `next_attempt_at` DATETIME NULL,
+    `locked_until` DATETIME NULL,
+    `response_status` SMALLINT UNSIGNED NULL,
+    `response_body` VARCHAR(1024) NULL,
+    `last_error` VARCHAR(1024) NULL,
+    `duration_ms` INT UNSIGNED NULL,
+    `created_at` DATETIME NOT NULL,
+    `delivered_at` DATETIME NULL,
+    PRIMARY KEY (`id`),
+    KEY `event_deliveries_subscription` (`subscription_id`, `id`),
+    KEY `event_deliveries_due` (`status`, `next_attempt_at`)
+);
@@ -0,0 +1,17 @@
+ALTER TABLE `users` ADD COLUMN `bot` BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- the bot user of an application shares its id
+CREATE TABLE IF NOT EXISTS `applications` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `owner_id` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(64) NOT NULL,
+    `description` VARCHAR(400) NOT NULL DEFAULT '',
+    -- JSON array of scopes
+    `scopes` TEXT NOT NULL,
+    -- hashed like session tokens, NULL once revoked
+    `bot_token` CHAR(64) NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    KEY `applications_owner` (`owner_id`),
+    UNIQUE KEY `applications_bot_token` (`bot_token`)
+);
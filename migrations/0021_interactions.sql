@@ -0,0 +1,31 @@
+ALTER TABLE `applications`
+    ADD COLUMN `interactions_url` VARCHAR(2048) NULL,
+    -- kept in clear, interactions are signed with it
+    ADD COLUMN `interactions_secret` VARCHAR(64) NULL;
+
+CREATE TABLE IF NOT EXISTS `application_commands` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `application_id` BIGINT UNSIGNED NOT NULL,
+    `name` VARCHAR(32) NOT NULL,
+    `description` VARCHAR(100) NOT NULL,
+    -- JSON array of typed options
+    `options` TEXT NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `application_commands_name` (`application_id`, `name`)
+);
+
+CREATE TABLE IF NOT EXISTS `interactions` (
+    `id` BIGINT UNSIGNED NOT NULL,
+    `application_id` BIGINT UNSIGNED NOT NULL,
+    `command_id` BIGINT UNSIGNED NOT NULL,
+    `channel_id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    -- hashed like session tokens
+    `token` CHAR(64) NOT NULL,
+    -- message or deferred, NULL until the application answers
+    `response` VARCHAR(16) NULL,
+    `ephemeral` BOOLEAN NOT NULL DEFAULT FALSE,
+    `created_at` DATETIME NOT NULL,
+    PRIMARY KEY (`id`)
+);
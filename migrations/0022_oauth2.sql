@@ -0,0 +1,42 @@
+ALTER TABLE `applications`
+    -- JSON array of URLs
+    ADD COLUMN `redirect_uris` TEXT NULL,
+    -- hashed like session tokens, NULL for public clients
+    ADD COLUMN `client_secret` CHAR(64) NULL;
+
+-- OAuth2 access and refresh tokens are sessions scoped to an application
+ALTER TABLE `sessions`
+    -- session, access or refresh
+    ADD COLUMN `kind` VARCHAR(16) NOT NULL DEFAULT 'session',
+    ADD COLUMN `application_id` BIGINT UNSIGNED NULL,
+    -- tokens issued from the same authorization, refreshing keeps it
+    ADD COLUMN `grant_id` BIGINT UNSIGNED NULL,
+    -- JSON array of scopes, NULL for a full session
+    ADD COLUMN `scopes` TEXT NULL,
+    ADD COLUMN `expires_at` DATETIME NULL,
+    ADD KEY `sessions_grant` (`grant_id`),
+    ADD KEY `sessions_application` (`application_id`, `user_id`);
+
+CREATE TABLE IF NOT EXISTS `oauth_codes` (
+    -- hashed like session tokens
+    `code` CHAR(64) NOT NULL,
+    `application_id` BIGINT UNSIGNED NOT NULL,
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `redirect_uri` VARCHAR(2048) NOT NULL,
+    `scopes` TEXT NOT NULL,
+    -- PKCE S256 challenge
+    `code_challenge` VARCHAR(128) NOT NULL,
+    `expires_at` DATETIME NOT NULL,
+    PRIMARY KEY (`code`),
+    KEY `oauth_codes_application` (`application_id`, `user_id`)
+);
+
+-- what each user agreed to share with each application
+CREATE TABLE IF NOT EXISTS `oauth_consents` (
+    `user_id` BIGINT UNSIGNED NOT NULL,
+    `application_id` BIGINT UNSIGNED NOT NULL,
+    `scopes` TEXT NOT NULL,
+    `created_at` DATETIME NOT NULL,
+    `updated_at` DATETIME NOT NULL,
+    PRIMARY KEY (`user_id`, `application_id`)
+);
@@ -0,0 +1,9 @@
+-- Keeps the oldest of any open duplicates so the unique key below can be built
+DELETE r FROM `reports` r JOIN `reports` o
+    ON o.`channel_id`=r.`channel_id` AND o.`reporter_id`=r.`reporter_id` AND o.`target`=r.`target` AND o.`id`<r.`id`
+    WHERE r.`resolution` IS NULL AND o.`resolution` IS NULL;
+
+-- NULL once resolved, so a closed report does not stop the same content from being reported again
+ALTER TABLE `reports`
+    ADD COLUMN `open_target` VARCHAR(255) AS (IF(`resolution` IS NULL, `target`, NULL)) STORED,
+    ADD UNIQUE KEY `reports_open` (`channel_id`, `reporter_id`, `open_target`);
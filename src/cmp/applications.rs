@@ -0,0 +1,525 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    interactions::Interactions,
+    outgoing::Outgoing,
+    permissions::Permissions,
+    users::{hash_token, User},
+};
+
+const TOKEN_LENGTH: usize = 64;
+const SECRET_LENGTH: usize = 32;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_DESCRIPTION_LENGTH: usize = 400;
+const MAX_APPLICATIONS: i64 = 25;
+const MAX_REDIRECT_URIS: usize = 10;
+
+/// What a bot may do, on top of its permissions in each channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Scope {
+    #[serde(rename = "messages.read")]
+    MessagesRead,
+    /// Sending and uploading.
+    #[serde(rename = "messages.write")]
+    MessagesWrite,
+    #[serde(rename = "messages.manage")]
+    MessagesManage,
+    #[serde(rename = "channels.manage")]
+    ChannelsManage,
+    /// Kicking, banning and timing out.
+    #[serde(rename = "members.moderate")]
+    MembersModerate,
+    /// Opening DMs with users sharing a channel.
+    #[serde(rename = "dms")]
+    Dms,
+}
+
+impl Scope {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "messages.read" => Some(Self::MessagesRead),
+            "messages.write" => Some(Self::MessagesWrite),
+            "messages.manage" => Some(Self::MessagesManage),
+            "channels.manage" => Some(Self::ChannelsManage),
+            "members.moderate" => Some(Self::MembersModerate),
+            "dms" => Some(Self::Dms),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MessagesRead => "messages.read",
+            Self::MessagesWrite => "messages.write",
+            Self::MessagesManage => "messages.manage",
+            Self::ChannelsManage => "channels.manage",
+            Self::MembersModerate => "members.moderate",
+            Self::Dms => "dms",
+        }
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        match self {
+            Self::MessagesRead => Permissions::READ,
+            Self::MessagesWrite => Permissions::SEND | Permissions::UPLOAD,
+            Self::MessagesManage => Permissions::MANAGE_MESSAGES,
+            Self::ChannelsManage => Permissions::MANAGE_CHANNEL,
+            Self::MembersModerate => Permissions::KICK | Permissions::BAN | Permissions::MODERATE,
+            Self::Dms => Permissions::NONE,
+        }
+    }
+}
+
+/// Permissions a set of scopes lets through.
+pub fn scope_permissions(scopes: &[Scope]) -> Permissions {
+    scopes.iter().fold(Permissions::NONE, |acc, scope| acc | scope.permissions())
+}
+
+/// Owned by a user, acts through its bot user.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Application {
+    /// Also the id of the bot user.
+    pub id: Snowflake,
+    pub owner_id: Snowflake,
+    pub name: String,
+    pub description: String,
+    pub scopes: Vec<Scope>,
+    /// Whether the bot can authenticate, the token itself is stored hashed.
+    pub has_token: bool,
+    /// Interactions are POSTed there when set, else sent to the gateway connections of the bot.
+    pub interactions_url: Option<String>,
+    /// Signs the interactions POSTed to `interactions_url`.
+    #[serde(skip)]
+    pub interactions_secret: Option<String>,
+    /// Where OAuth2 authorizations may send the user back, matched exactly.
+    pub redirect_uris: Vec<String>,
+    /// Hashed, OAuth2 clients without one are public and only rely on PKCE.
+    #[serde(skip)]
+    pub client_secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Only returned when the application is created or its token reset.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApplicationWithToken {
+    #[serde(flatten)]
+    pub application: Application,
+    /// Sent as `Authorization: Bot <token>`, it does not expire.
+    pub token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ApplicationInput {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ApplicationPatch {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub scopes: Option<Vec<Scope>>,
+    /// An empty string goes back to gateway delivery.
+    pub interactions_url: Option<String>,
+    pub redirect_uris: Option<Vec<String>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct InteractionsSecret {
+    pub secret: String,
+}
+
+/// Only returned when it is generated, it is stored hashed.
+#[derive(Debug, serde::Serialize)]
+pub struct ClientSecret {
+    pub client_id: Snowflake,
+    pub client_secret: String,
+}
+
+/// Scopes of an application, an unreadable column grants nothing.
+pub fn parse_scopes(source: Option<&str>) -> Vec<Scope> {
+    source.and_then(|source| serde_json::from_str(source).ok()).unwrap_or_default()
+}
+
+fn from_row(row: &MySqlRow) -> Result<Application, sqlx::error::Error> {
+    Ok(Application {
+        id: row.try_get("id")?,
+        owner_id: row.try_get("owner_id")?,
+        name: row.try_get("name")?,
+        description: row.try_get("description")?,
+        scopes: parse_scopes(row.try_get::<Option<String>, _>("scopes")?.as_deref()),
+        has_token: row.try_get::<Option<String>, _>("bot_token")?.is_some(),
+        interactions_url: row.try_get("interactions_url")?,
+        interactions_secret: row.try_get("interactions_secret")?,
+        redirect_uris: row.try_get::<Option<String>, _>("redirect_uris")?.and_then(|uris| serde_json::from_str(&uris).ok()).unwrap_or_default(),
+        client_secret: row.try_get("client_secret")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl Application {
+    /// Only the owner sees the application.
+    pub async fn from_id(owner_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Application, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM applications WHERE `owner_id`=? AND `id`=?;")
+            .bind(owner_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn by_id(id: Snowflake, pool: &Pool<MySql>) -> Result<Application, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM applications WHERE `id`=?;")
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    /// The application as seen by its own bot, or by its owner through their session.
+    pub async fn managed_by(user: &User, id: Snowflake, pool: &Pool<MySql>) -> Result<Application, Error> {
+        if user.bot && user.id == id { return Ok(Application::by_id(id, pool).await?) }
+        check_session(user)?;
+
+        Ok(Application::from_id(user.id, id, pool).await?)
+    }
+
+    pub async fn list(owner_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Application>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM applications WHERE `owner_id`=? ORDER BY `id` ASC;")
+            .bind(owner_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    /// Creates the bot user along with the application.
+    pub async fn create(owner_id: Snowflake, input: ApplicationInput, pool: &Pool<MySql>) -> Result<ApplicationWithToken, sqlx::error::Error> {
+        let id = ids::generate();
+        let application = Application {
+            id,
+            owner_id,
+            name: input.name,
+            description: input.description,
+            scopes: input.scopes,
+            has_token: true,
+            interactions_url: None,
+            interactions_secret: None,
+            redirect_uris: Vec::new(),
+            client_secret: None,
+            created_at: id.created_at(),
+        };
+        let token = ids::random_string(TOKEN_LENGTH);
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("INSERT INTO users (`id`, `name`, `bot`) VALUES (?, ?, TRUE);")
+            .bind(application.id)
+            .bind(&application.name)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("INSERT INTO applications (`id`, `owner_id`, `name`, `description`, `scopes`, `bot_token`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+            .bind(application.id)
+            .bind(application.owner_id)
+            .bind(&application.name)
+            .bind(&application.description)
+            .bind(serde_json::to_string(&application.scopes).unwrap_or_default())
+            .bind(hash_token(&token))
+            .bind(application.created_at)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(ApplicationWithToken { application, token })
+    }
+
+    /// The bot user is renamed with the application.
+    pub async fn save(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE applications SET `name`=?, `description`=?, `scopes`=?, `interactions_url`=?, `redirect_uris`=? WHERE `id`=?;")
+            .bind(&self.name)
+            .bind(&self.description)
+            .bind(serde_json::to_string(&self.scopes).unwrap_or_default())
+            .bind(&self.interactions_url)
+            .bind(serde_json::to_string(&self.redirect_uris).unwrap_or_default())
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE users SET `name`=? WHERE `id`=?;")
+            .bind(&self.name)
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await
+    }
+
+    /// The previous token stops working right away.
+    pub async fn reset_token(mut self, pool: &Pool<MySql>) -> Result<ApplicationWithToken, sqlx::error::Error> {
+        let token = ids::random_string(TOKEN_LENGTH);
+        sqlx::query("UPDATE applications SET `bot_token`=? WHERE `id`=?;")
+            .bind(hash_token(&token))
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        self.has_token = true;
+        Ok(ApplicationWithToken { application: self, token })
+    }
+
+    /// The bot cannot authenticate until a new token is generated.
+    pub async fn revoke_token(&mut self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE applications SET `bot_token`=NULL WHERE `id`=?;")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        self.has_token = false;
+        Ok(())
+    }
+
+    /// Interactions in flight may fail their signature check.
+    pub async fn rotate_interactions_secret(&mut self, pool: &Pool<MySql>) -> Result<InteractionsSecret, sqlx::error::Error> {
+        let secret = ids::random_string(SECRET_LENGTH);
+        sqlx::query("UPDATE applications SET `interactions_secret`=? WHERE `id`=?;")
+            .bind(&secret)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        self.interactions_secret = Some(secret.clone());
+        Ok(InteractionsSecret { secret })
+    }
+
+    /// Confidential clients authenticate with it on the OAuth2 routes.
+    pub async fn rotate_client_secret(&mut self, pool: &Pool<MySql>) -> Result<ClientSecret, sqlx::error::Error> {
+        let secret = ids::random_string(TOKEN_LENGTH);
+        sqlx::query("UPDATE applications SET `client_secret`=? WHERE `id`=?;")
+            .bind(hash_token(&secret))
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        self.client_secret = Some(hash_token(&secret));
+        Ok(ClientSecret { client_id: self.id, client_secret: secret })
+    }
+
+    /// The bot leaves every channel, its user is kept so its messages still have an author.
+    /// The OAuth2 authorizations given to the application go with it.
+    /// Returns the channels it left.
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<Vec<Snowflake>, sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        let channels = sqlx::query("SELECT `channel_id` FROM channel_members WHERE `user_id`=?;")
+            .bind(self.id)
+            .fetch_all(&mut tx)
+            .await?
+            .iter()
+            .map(|row| row.try_get("channel_id"))
+            .collect::<Result<Vec<Snowflake>, _>>()?;
+
+        for query in [
+            "DELETE FROM member_roles WHERE `user_id`=?;",
+            "DELETE FROM channel_members WHERE `user_id`=?;",
+            "DELETE FROM sessions WHERE `application_id`=?;",
+            "DELETE FROM oauth_codes WHERE `application_id`=?;",
+            "DELETE FROM oauth_consents WHERE `application_id`=?;",
+            "DELETE FROM interactions WHERE `application_id`=?;",
+            "DELETE FROM application_commands WHERE `application_id`=?;",
+            "DELETE FROM applications WHERE `id`=?;",
+        ] {
+            sqlx::query(query).bind(self.id).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(channels)
+    }
+}
+
+/// Fails with a 403 when a bot tries something only people can do.
+pub fn check_not_bot(user: &User) -> Result<(), Error> {
+    if !user.bot { return Ok(()) }
+
+    Err(Error::new(Status::Forbidden, "Bots cannot use this route".to_string(), "Use a user account".to_string()))
+}
+
+/// Fails with a 403 unless `user` acts through their own session, so neither as a bot nor with a scoped token.
+pub fn check_session(user: &User) -> Result<(), Error> {
+    if user.scopes.is_none() { return Ok(()) }
+
+    Err(Error::new(Status::Forbidden, "This route needs a user session".to_string(), "Bots and OAuth2 tokens cannot use it".to_string()))
+}
+
+/// Fails with a 403 for OAuth2 tokens, which only reach the routes of their scopes. Bots act for themselves.
+pub fn check_not_token(user: &User) -> Result<(), Error> {
+    if user.bot || user.scopes.is_none() { return Ok(()) }
+
+    Err(Error::new(Status::Forbidden, "OAuth2 tokens cannot use this route".to_string(), "Use a user session or a bot token".to_string()))
+}
+
+/// Fails with a 403 unless the credentials of `user` carry `scope`.
+pub fn check_scope(user: &User, scope: Scope) -> Result<(), Error> {
+    if user.has_scope(scope) { return Ok(()) }
+
+    Err(Error::new(Status::Forbidden, format!("Missing scope {}", scope.as_str()), "Add the scope to the application".to_string()))
+}
+
+fn check_input(name: &str, description: &str, scopes: &mut Vec<Scope>) -> Result<(), Error> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Error::new(Status::BadRequest, format!("An application name must be between 1 and {MAX_NAME_LENGTH} caracters"), "Check the body of your request".to_string()));
+    }
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(Error::new(Status::BadRequest, format!("An application description cannot be longer than {MAX_DESCRIPTION_LENGTH} caracters"), "Check the body of your request".to_string()));
+    }
+    scopes.sort_by_key(Scope::as_str);
+    scopes.dedup();
+
+    Ok(())
+}
+
+fn check_interactions_url(application: &Application, outgoing: &Outgoing) -> Result<(), Error> {
+    let Some(url) = &application.interactions_url else { return Ok(()) };
+    let valid = url.len() <= MAX_URL_LENGTH
+        && (url.starts_with("https://") || url.starts_with("http://"))
+        && reqwest::Url::parse(url).is_ok_and(|url| url.host().is_some());
+    if !valid {
+        return Err(Error::new(Status::BadRequest, format!("An interactions url must be an http link of at most {MAX_URL_LENGTH} caracters"), "Check the body of your request".to_string()));
+    }
+    // names are checked again on each delivery, once resolved
+    if let Err(e) = outgoing.check_url(url) {
+        return Err(Error::new(Status::BadRequest, e, "Use a url reachable on the internet".to_string()));
+    }
+    if application.interactions_secret.is_none() {
+        return Err(Error::new(Status::BadRequest, "The application has no interactions secret".to_string(), "Generate one before setting an interactions url".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Absolute URLs without fragment, custom schemes are allowed for native apps.
+fn check_redirect_uris(uris: &mut Vec<String>) -> Result<(), Error> {
+    uris.sort();
+    uris.dedup();
+    if uris.len() > MAX_REDIRECT_URIS {
+        return Err(Error::new(Status::BadRequest, format!("An application cannot have more than {MAX_REDIRECT_URIS} redirect uris"), "Check the body of your request".to_string()));
+    }
+    for uri in uris.iter() {
+        let valid = uri.len() <= MAX_URL_LENGTH && reqwest::Url::parse(uri).is_ok_and(|url| url.fragment().is_none());
+        if !valid {
+            return Err(Error::new(Status::BadRequest, format!("Invalid redirect uri {uri}"), "Use absolute URLs without fragment".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[get("/applications")]
+async fn get_applications(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<Application>>, Error> {
+    check_session(&user)?;
+
+    Ok(Json(Application::list(user.id, pool).await?))
+}
+
+#[post("/applications", data = "<input>")]
+async fn create_application(pool: &State<Pool<MySql>>, user: User, input: Json<ApplicationInput>) -> Result<Json<ApplicationWithToken>, Error> {
+    check_session(&user)?;
+    let mut input = input.into_inner();
+    check_input(&input.name, &input.description, &mut input.scopes)?;
+
+    let count: i64 = sqlx::query("SELECT COUNT(*) AS `count` FROM applications WHERE `owner_id`=?;")
+        .bind(user.id)
+        .fetch_one(pool.inner())
+        .await?
+        .try_get("count")?;
+    if count >= MAX_APPLICATIONS {
+        return Err(Error::new(Status::BadRequest, format!("A user can own at most {MAX_APPLICATIONS} applications"), "Delete an application first".to_string()));
+    }
+
+    Ok(Json(Application::create(user.id, input, pool).await?))
+}
+
+#[get("/applications/<id>")]
+async fn get_application(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Application>, Error> {
+    check_session(&user)?;
+
+    Ok(Json(Application::from_id(user.id, id, pool).await?))
+}
+
+/// Scope changes apply to the next request of the bot.
+#[patch("/applications/<id>", data = "<input>")]
+async fn edit_application(pool: &State<Pool<MySql>>, interactions: &State<Interactions>, user: User, id: Snowflake, input: Json<ApplicationPatch>) -> Result<Json<Application>, Error> {
+    check_session(&user)?;
+    let mut application = Application::from_id(user.id, id, pool).await?;
+    let input = input.into_inner();
+
+    if let Some(name) = input.name { application.name = name }
+    if let Some(description) = input.description { application.description = description }
+    if let Some(scopes) = input.scopes { application.scopes = scopes }
+    if let Some(url) = input.interactions_url { application.interactions_url = Some(url).filter(|url| !url.is_empty()) }
+    if let Some(uris) = input.redirect_uris { application.redirect_uris = uris }
+    check_input(&application.name, &application.description, &mut application.scopes)?;
+    check_interactions_url(&application, interactions.outgoing())?;
+    check_redirect_uris(&mut application.redirect_uris)?;
+    application.save(pool).await?;
+
+    Ok(Json(application))
+}
+
+#[delete("/applications/<id>")]
+async fn delete_application(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+    let application = Application::from_id(user.id, id, pool).await?;
+
+    for channel_id in application.delete(pool).await? {
+        bus.publish(channel_id, Event::MemberLeave { channel_id, user_id: application.id });
+    }
+    Ok(Status::NoContent)
+}
+
+#[post("/applications/<id>/bot/token")]
+async fn reset_token(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<ApplicationWithToken>, Error> {
+    check_session(&user)?;
+    let application = Application::from_id(user.id, id, pool).await?;
+
+    Ok(Json(application.reset_token(pool).await?))
+}
+
+#[delete("/applications/<id>/bot/token")]
+async fn revoke_token(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+    let mut application = Application::from_id(user.id, id, pool).await?;
+
+    application.revoke_token(pool).await?;
+    Ok(Status::NoContent)
+}
+
+/// Key of the HMAC-SHA256 signing the interactions POSTed to the application.
+#[post("/applications/<id>/interactions/secret")]
+async fn rotate_interactions_secret(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<InteractionsSecret>, Error> {
+    check_session(&user)?;
+    let mut application = Application::from_id(user.id, id, pool).await?;
+
+    Ok(Json(application.rotate_interactions_secret(pool).await?))
+}
+
+/// Makes the application a confidential OAuth2 client.
+#[post("/applications/<id>/oauth2/secret")]
+async fn rotate_client_secret(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<ClientSecret>, Error> {
+    check_session(&user)?;
+    let mut application = Application::from_id(user.id, id, pool).await?;
+
+    Ok(Json(application.rotate_client_secret(pool).await?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_applications, create_application, get_application, edit_application, delete_application, reset_token, revoke_token, rotate_interactions_secret, rotate_client_secret]
+}
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{http::Status, serde::json::Json, Request, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    channels::Channel,
+    errors::Error,
+    ids::{self, Snowflake},
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const MAX_REASON_LENGTH: usize = 512;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    #[field(value = "member_kick")]
+    MemberKick,
+    #[field(value = "member_ban")]
+    MemberBan,
+    #[field(value = "member_unban")]
+    MemberUnban,
+    #[field(value = "member_timeout")]
+    MemberTimeout,
+    #[field(value = "member_timeout_remove")]
+    MemberTimeoutRemove,
+    #[field(value = "message_delete")]
+    MessageDelete,
+    #[field(value = "automod_rule_create")]
+    AutomodRuleCreate,
+    #[field(value = "automod_rule_update")]
+    AutomodRuleUpdate,
+    #[field(value = "automod_rule_delete")]
+    AutomodRuleDelete,
+    /// Recorded with the timed out author as actor.
+    #[field(value = "automod_timeout")]
+    AutomodTimeout,
+    #[field(value = "report_resolve")]
+    ReportResolve,
+}
+
+impl AuditAction {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "member_kick" => Some(Self::MemberKick),
+            "member_ban" => Some(Self::MemberBan),
+            "member_unban" => Some(Self::MemberUnban),
+            "member_timeout" => Some(Self::MemberTimeout),
+            "member_timeout_remove" => Some(Self::MemberTimeoutRemove),
+            "message_delete" => Some(Self::MessageDelete),
+            "automod_rule_create" => Some(Self::AutomodRuleCreate),
+            "automod_rule_update" => Some(Self::AutomodRuleUpdate),
+            "automod_rule_delete" => Some(Self::AutomodRuleDelete),
+            "automod_timeout" => Some(Self::AutomodTimeout),
+            "report_resolve" => Some(Self::ReportResolve),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MemberKick => "member_kick",
+            Self::MemberBan => "member_ban",
+            Self::MemberUnban => "member_unban",
+            Self::MemberTimeout => "member_timeout",
+            Self::MemberTimeoutRemove => "member_timeout_remove",
+            Self::MessageDelete => "message_delete",
+            Self::AutomodRuleCreate => "automod_rule_create",
+            Self::AutomodRuleUpdate => "automod_rule_update",
+            Self::AutomodRuleDelete => "automod_rule_delete",
+            Self::AutomodTimeout => "automod_timeout",
+            Self::ReportResolve => "report_resolve",
+        }
+    }
+}
+
+/// One moderation action. Entries are only ever appended.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AuditEntry {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub actor_id: Snowflake,
+    pub action: AuditAction,
+    pub target_id: Option<Snowflake>,
+    pub reason: Option<String>,
+    /// Action specific data.
+    pub details: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Optional `X-Audit-Log-Reason` header, for actions without a body.
+pub struct Reason(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Reason {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Reason(req.headers().get_one("X-Audit-Log-Reason").map(|reason| reason.to_string())))
+    }
+}
+
+/// Fails with a 400 if a reason stored outside of the audit log would not fit.
+pub fn check_reason(reason: Option<&str>) -> Result<(), Error> {
+    if reason.map(|reason| reason.chars().count() > MAX_REASON_LENGTH).unwrap_or(false) {
+        return Err(Error::new(Status::BadRequest, format!("A reason cannot be longer than {MAX_REASON_LENGTH} caracters"), "Shorten the reason".to_string()));
+    }
+    Ok(())
+}
+
+fn from_row(row: &MySqlRow) -> Result<AuditEntry, sqlx::error::Error> {
+    Ok(AuditEntry {
+        id: row.try_get("id")?,
+        channel_id: row.try_get("channel_id")?,
+        actor_id: row.try_get("actor_id")?,
+        action: AuditAction::parse(&row.try_get::<String, _>("action")?).ok_or(sqlx::Error::RowNotFound)?,
+        target_id: row.try_get("target_id")?,
+        reason: row.try_get("reason")?,
+        details: row.try_get::<Option<String>, _>("details")?.and_then(|details| serde_json::from_str(&details).ok()),
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl AuditEntry {
+    /// Appends an entry, reasons longer than the limit are cut.
+    pub async fn record(channel_id: Snowflake, actor_id: Snowflake, action: AuditAction, target_id: Option<Snowflake>, reason: Option<String>, details: Option<serde_json::Value>, pool: &Pool<MySql>) -> Result<AuditEntry, sqlx::error::Error> {
+        let id = ids::generate();
+        let entry = AuditEntry {
+            id,
+            channel_id,
+            actor_id,
+            action,
+            target_id,
+            reason: reason.map(|reason| reason.chars().take(MAX_REASON_LENGTH).collect()).filter(|reason: &String| !reason.trim().is_empty()),
+            details,
+            created_at: id.created_at(),
+        };
+
+        sqlx::query("INSERT INTO audit_log (`id`, `channel_id`, `actor_id`, `action`, `target_id`, `reason`, `details`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?, ?);")
+            .bind(entry.id)
+            .bind(entry.channel_id)
+            .bind(entry.actor_id)
+            .bind(entry.action.as_str())
+            .bind(entry.target_id)
+            .bind(&entry.reason)
+            .bind(entry.details.as_ref().map(|details| details.to_string()))
+            .bind(entry.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(entry)
+    }
+}
+
+/// Newest entries first.
+#[get("/channels/<id>/audit-log?<action>&<actor>&<before>&<limit>")]
+async fn get_audit_log(pool: &State<Pool<MySql>>, user: User, id: Snowflake, action: Option<AuditAction>, actor: Option<Snowflake>, before: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<AuditEntry>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let q = sqlx::query("SELECT * FROM audit_log WHERE `channel_id`=? AND (? IS NULL OR `action`=?) AND (? IS NULL OR `actor_id`=?) AND `id`<? ORDER BY `id` DESC LIMIT ?;")
+        .bind(channel.id)
+        .bind(action.map(|a| a.as_str()))
+        .bind(action.map(|a| a.as_str()))
+        .bind(actor)
+        .bind(actor)
+        .bind(before.map(Snowflake::get).unwrap_or(u64::MAX))
+        .bind(limit)
+        .fetch_all(pool.inner())
+        .await?;
+
+    Ok(Json(q.iter().map(from_row).collect::<Result<_, _>>()?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_audit_log]
+}
@@ -0,0 +1,688 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use chrono::{DateTime, Duration, Utc};
+use regex::{Regex, RegexBuilder};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    audit::{AuditAction, AuditEntry},
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    members::check_text_channel,
+    messages::{self, Message, MessageInput},
+    moderation::{self, MAX_TIMEOUT_SECONDS},
+    permissions::{self, check_permission, Permissions},
+    users::User,
+};
+
+const MAX_RULES: i64 = 20;
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_LIST_SIZE: usize = 100;
+const MAX_PATTERNS: usize = 10;
+const MAX_PATTERN_LENGTH: usize = 256;
+/// Compiled size limit of a pattern, keeps a rule from eating memory.
+/// Unicode classes such as `\W` need more than 64 KiB once case insensitive.
+const REGEX_SIZE_LIMIT: usize = 1 << 18;
+const MAX_WINDOW_SECONDS: u64 = 3600;
+/// Messages of the author looked back at by repeated-message rules.
+const HISTORY_SIZE: i64 = 50;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// What a rule looks for in a message.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    /// Case insensitive words or phrases, matched on word boundaries.
+    Keywords { keywords: Vec<String> },
+    /// Case insensitive regular expressions.
+    Regex { patterns: Vec<String> },
+    /// Links to any domain outside the list, subdomains are allowed.
+    LinkWhitelist { domains: Vec<String> },
+    MentionSpam { max_mentions: usize },
+    /// The same content sent more than `max_repeats` times in `window_seconds`.
+    Repeated { max_repeats: u32, window_seconds: u64 },
+    /// More than `max_ratio` of the letters are uppercase, messages under `min_length` letters are ignored.
+    Caps { max_ratio: f32, min_length: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    Block,
+    /// The message goes through and is queued for review.
+    Flag,
+    /// The message is blocked and its author timed out.
+    Timeout { duration_seconds: u64 },
+}
+
+impl Action {
+    fn severity(&self) -> u8 {
+        match self {
+            Self::Flag => 0,
+            Self::Block => 1,
+            Self::Timeout { .. } => 2,
+        }
+    }
+}
+
+/// Rules of a channel also apply to its threads.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Rule {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub name: String,
+    pub trigger: Trigger,
+    pub action: Action,
+    pub enabled: bool,
+    pub creator_id: Snowflake,
+    pub created_at: DateTime<Utc>,
+    /// Compiled keyword or regex trigger, see `patterns`.
+    #[serde(skip)]
+    pub patterns: Arc<Vec<Regex>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RuleInput {
+    pub name: String,
+    pub trigger: Trigger,
+    pub action: Action,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool { true }
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RulePatch {
+    pub name: Option<String>,
+    pub trigger: Option<Trigger>,
+    pub action: Option<Action>,
+    pub enabled: Option<bool>,
+}
+
+/// A message waiting for review.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Flag {
+    pub message: Message,
+    pub rule_id: Snowflake,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything the engine looks at, gathered beforehand so evaluation does no I/O.
+#[derive(Debug, Clone)]
+pub struct Context<'a> {
+    pub content: &'a str,
+    pub mentions: usize,
+    /// Earlier messages of the author in the channel.
+    pub history: &'a [(DateTime<Utc>, String)],
+    pub now: DateTime<Utc>,
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+fn keywords_regex(keywords: &[String]) -> Result<Regex, regex::Error> {
+    let alternatives = keywords.iter().map(|k| regex::escape(k.trim())).collect::<Vec<_>>().join("|");
+    compile(&format!(r"(?:^|\W)(?:{alternatives})(?:\W|$)"))
+}
+
+/// Regexes of a keyword or regex trigger, none for the others. Patterns that fail to compile are left out.
+fn build(trigger: &Trigger) -> Vec<Regex> {
+    match trigger {
+        Trigger::Keywords { keywords } if !keywords.is_empty() => keywords_regex(keywords).into_iter().collect(),
+        Trigger::Regex { patterns } => patterns.iter().filter_map(|p| compile(p).ok()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Compiled triggers shared by every request, so a message does not compile the rules of its channel again.
+/// A rule whose trigger was edited no longer matches its entry and is compiled again.
+fn patterns(rule_id: Snowflake, trigger: &Trigger) -> Arc<Vec<Regex>> {
+    if let Some((cached, patterns)) = compiled().get(&rule_id) {
+        if cached == trigger { return patterns.clone() }
+    }
+    // compiled outside of the lock
+    let patterns = Arc::new(build(trigger));
+    compiled().insert(rule_id, (trigger.clone(), patterns.clone()));
+    patterns
+}
+
+/// Rule id -> trigger it was compiled from and its patterns.
+type Compiled = HashMap<Snowflake, (Trigger, Arc<Vec<Regex>>)>;
+
+fn compiled() -> std::sync::MutexGuard<'static, Compiled> {
+    static COMPILED: OnceLock<Mutex<Compiled>> = OnceLock::new();
+    COMPILED.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn link_regex() -> &'static Regex {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    LINK.get_or_init(|| Regex::new(r"(?i)\b(?:https?://|www\.)([a-z0-9.-]+)").expect("valid link regex"))
+}
+
+fn normalize(content: &str) -> String {
+    content.trim().to_lowercase()
+}
+
+/// Whether the trigger of a rule fires on a message. Patterns that fail to compile never match.
+pub fn matches(rule: &Rule, ctx: &Context) -> bool {
+    match &rule.trigger {
+        Trigger::Keywords { .. } | Trigger::Regex { .. } => rule.patterns.iter().any(|re| re.is_match(ctx.content)),
+        Trigger::LinkWhitelist { domains } => link_regex().captures_iter(ctx.content).any(|caps| {
+            let host = caps[1].trim_end_matches('.').to_lowercase();
+            let host = host.strip_prefix("www.").unwrap_or(&host);
+            !domains.iter().any(|domain| {
+                let domain = domain.trim().to_lowercase();
+                host == domain || host.ends_with(&format!(".{domain}"))
+            })
+        }),
+        Trigger::MentionSpam { max_mentions } => ctx.mentions > *max_mentions,
+        Trigger::Repeated { max_repeats, window_seconds } => {
+            let content = normalize(ctx.content);
+            if content.is_empty() { return false }
+            let since = ctx.now - Duration::seconds(*window_seconds as i64);
+            let repeats = ctx.history.iter().filter(|(at, previous)| *at >= since && normalize(previous) == content).count();
+            repeats + 1 > *max_repeats as usize
+        },
+        Trigger::Caps { max_ratio, min_length } => {
+            let (letters, upper) = ctx.content.chars()
+                .filter(|c| c.is_alphabetic())
+                .fold((0usize, 0usize), |(letters, upper), c| (letters + 1, upper + c.is_uppercase() as usize));
+            letters >= *min_length && letters > 0 && upper as f32 / letters as f32 > *max_ratio
+        },
+    }
+}
+
+/// The enabled rules a message breaks, most severe action first.
+pub fn evaluate<'r>(rules: &'r [Rule], ctx: &Context) -> Vec<&'r Rule> {
+    let mut broken = rules.iter().filter(|rule| rule.enabled && matches(rule, ctx)).collect::<Vec<_>>();
+    broken.sort_by_key(|rule| std::cmp::Reverse(rule.action.severity()));
+    broken
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::new(Status::BadRequest, message.to_string(), "Check the body of your request".to_string())
+}
+
+fn check_list(list: &[String], what: &str) -> Result<(), Error> {
+    if list.is_empty() || list.len() > MAX_LIST_SIZE {
+        return Err(bad_request(&format!("A rule needs between 1 and {MAX_LIST_SIZE} {what}")));
+    }
+    if list.iter().any(|item| item.trim().is_empty() || item.chars().count() > MAX_PATTERN_LENGTH) {
+        return Err(bad_request(&format!("The {what} must be between 1 and {MAX_PATTERN_LENGTH} caracters")));
+    }
+
+    Ok(())
+}
+
+fn check_rule(name: &str, trigger: &Trigger, action: &Action) -> Result<(), Error> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(bad_request(&format!("A rule name must be between 1 and {MAX_NAME_LENGTH} caracters")));
+    }
+
+    match trigger {
+        Trigger::Keywords { keywords } => {
+            check_list(keywords, "keywords")?;
+            keywords_regex(keywords).map_err(|_| bad_request("The keyword list is too large"))?;
+        },
+        Trigger::Regex { patterns } => {
+            if patterns.len() > MAX_PATTERNS {
+                return Err(bad_request(&format!("A rule can have at most {MAX_PATTERNS} patterns")));
+            }
+            check_list(patterns, "patterns")?;
+            for pattern in patterns {
+                compile(pattern).map_err(|err| bad_request(&format!("Invalid pattern {pattern:?}: {err}")))?;
+            }
+        },
+        Trigger::LinkWhitelist { domains } => check_list(domains, "domains")?,
+        Trigger::MentionSpam { max_mentions } => {
+            // messages keep at most 50 mentions
+            if !(1..50).contains(max_mentions) {
+                return Err(bad_request("\"max_mentions\" must be between 1 and 49"));
+            }
+        },
+        Trigger::Repeated { max_repeats, window_seconds } => {
+            if !(1..=20).contains(max_repeats) || !(1..=MAX_WINDOW_SECONDS).contains(window_seconds) {
+                return Err(bad_request(&format!("\"max_repeats\" must be between 1 and 20 and \"window_seconds\" between 1 and {MAX_WINDOW_SECONDS}")));
+            }
+        },
+        Trigger::Caps { max_ratio, min_length } => {
+            if !(*max_ratio > 0.0 && *max_ratio < 1.0) || *min_length == 0 {
+                return Err(bad_request("\"max_ratio\" must be between 0 and 1 and \"min_length\" at least 1"));
+            }
+        },
+    }
+
+    if let Action::Timeout { duration_seconds } = action {
+        if !(1..=MAX_TIMEOUT_SECONDS).contains(duration_seconds) {
+            return Err(bad_request(&format!("A timeout lasts between 1 and {MAX_TIMEOUT_SECONDS} seconds")));
+        }
+    }
+
+    Ok(())
+}
+
+/// Timing members out through a rule takes the right to time them out directly.
+fn required(action: &Action) -> Permissions {
+    match action {
+        Action::Timeout { .. } => Permissions::MANAGE_CHANNEL | Permissions::MODERATE,
+        _ => Permissions::MANAGE_CHANNEL,
+    }
+}
+
+fn from_row(row: &MySqlRow) -> Result<Rule, sqlx::error::Error> {
+    let decode = |column: &str| sqlx::Error::ColumnDecode { index: column.to_string(), source: "invalid automod rule".into() };
+    let id = row.try_get("id")?;
+    let trigger = serde_json::from_str(&row.try_get::<String, _>("trigger")?).map_err(|_| decode("trigger"))?;
+
+    Ok(Rule {
+        id,
+        channel_id: row.try_get("channel_id")?,
+        name: row.try_get("name")?,
+        patterns: patterns(id, &trigger),
+        trigger,
+        action: serde_json::from_str(&row.try_get::<String, _>("action")?).map_err(|_| decode("action"))?,
+        enabled: row.try_get("enabled")?,
+        creator_id: row.try_get("creator_id")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl Rule {
+    pub async fn from_id(channel_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Rule, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM automod_rules WHERE `channel_id`=? AND `id`=?;")
+            .bind(channel_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Rule>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM automod_rules WHERE `channel_id`=? ORDER BY `id` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    pub async fn create(channel_id: Snowflake, creator_id: Snowflake, input: RuleInput, pool: &Pool<MySql>) -> Result<Rule, sqlx::error::Error> {
+        let id = ids::generate();
+        let rule = Rule {
+            id,
+            channel_id,
+            name: input.name,
+            patterns: patterns(id, &input.trigger),
+            trigger: input.trigger,
+            action: input.action,
+            enabled: input.enabled,
+            creator_id,
+            created_at: id.created_at(),
+        };
+
+        sqlx::query("INSERT INTO automod_rules (`id`, `channel_id`, `name`, `trigger`, `action`, `enabled`, `creator_id`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?, ?);")
+            .bind(rule.id)
+            .bind(rule.channel_id)
+            .bind(&rule.name)
+            .bind(serde_json::to_string(&rule.trigger).unwrap_or_default())
+            .bind(serde_json::to_string(&rule.action).unwrap_or_default())
+            .bind(rule.enabled)
+            .bind(rule.creator_id)
+            .bind(rule.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(rule)
+    }
+
+    /// Compiles the trigger again if it changed.
+    pub async fn save(&mut self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        self.patterns = patterns(self.id, &self.trigger);
+        sqlx::query("UPDATE automod_rules SET `name`=?, `trigger`=?, `action`=?, `enabled`=? WHERE `id`=?;")
+            .bind(&self.name)
+            .bind(serde_json::to_string(&self.trigger).unwrap_or_default())
+            .bind(serde_json::to_string(&self.action).unwrap_or_default())
+            .bind(self.enabled)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The messages it flagged leave the review queue.
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        for query in [
+            "DELETE FROM automod_flags WHERE `rule_id`=?;",
+            "DELETE FROM automod_rules WHERE `id`=?;",
+        ] {
+            sqlx::query(query).bind(self.id).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        compiled().remove(&self.id);
+
+        Ok(())
+    }
+}
+
+impl Flag {
+    /// Newest first, deleted messages are left out.
+    pub async fn list(channel_id: Snowflake, before: Option<Snowflake>, limit: u32, pool: &Pool<MySql>) -> Result<Vec<Flag>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT f.`message_id`, m.`channel_id` AS `message_channel_id`, f.`rule_id`, f.`created_at` FROM automod_flags f \
+            JOIN messages m ON m.`id`=f.`message_id` WHERE f.`channel_id`=? AND m.`deleted_at` IS NULL AND f.`message_id`<? \
+            ORDER BY f.`message_id` DESC LIMIT ?;")
+            .bind(channel_id)
+            .bind(before.map(Snowflake::get).unwrap_or(u64::MAX))
+            .bind(limit)
+            .fetch_all(pool)
+            .await?;
+
+        let mut flags = Vec::with_capacity(q.len());
+        for row in &q {
+            flags.push(Flag {
+                message: Message::from_id(row.try_get("message_channel_id")?, row.try_get("message_id")?, pool).await?,
+                rule_id: row.try_get("rule_id")?,
+                created_at: row.try_get("created_at")?,
+            });
+        }
+        Ok(flags)
+    }
+}
+
+/// Enabled rules of the channel broken by a message of `author_id` about to be sent, most severe first.
+async fn broken_rules(channel: &Channel, author_id: Snowflake, input: &MessageInput, pool: &Pool<MySql>) -> Result<Vec<Rule>, Error> {
+    let rules = Rule::list(channel.parent_id.unwrap_or(channel.id), pool).await?;
+    if rules.iter().all(|rule| !rule.enabled) { return Ok(Vec::new()) }
+
+    // a poll is checked on its question and options too
+    let mut content = input.content.clone();
+    if let Some(poll) = &input.poll {
+        for text in std::iter::once(&poll.question).chain(poll.options.iter().map(|option| &option.text)) {
+            content.push('\n');
+            content.push_str(text);
+        }
+    }
+
+    let now = Utc::now();
+    let window = rules.iter()
+        .filter_map(|rule| match rule.trigger {
+            Trigger::Repeated { window_seconds, .. } if rule.enabled => Some(window_seconds),
+            _ => None,
+        })
+        .max();
+    let mut history = Vec::new();
+    if let Some(window) = window {
+        let q = sqlx::query("SELECT `content`, `created_at` FROM messages WHERE `channel_id`=? AND `author_id`=? AND `created_at`>=? AND `deleted_at` IS NULL ORDER BY `id` DESC LIMIT ?;")
+            .bind(channel.id)
+            .bind(author_id)
+            .bind(now - Duration::seconds(window as i64))
+            .bind(HISTORY_SIZE)
+            .fetch_all(pool)
+            .await?;
+        for row in &q {
+            history.push((row.try_get("created_at")?, row.try_get("content")?));
+        }
+    }
+
+    let ctx = Context { content: &content, mentions: messages::parse_mentions(&input.content).len(), history: &history, now };
+    Ok(evaluate(&rules, &ctx).into_iter().cloned().collect())
+}
+
+fn blocked(rule: &Rule) -> Error {
+    Error::new(Status::Forbidden, format!("Your message was blocked by the automod rule \"{}\"", rule.name), "Edit the content of your message".to_string())
+}
+
+/// Runs the rules of the channel on a message about to be sent.
+/// Returns the rules that flag it, or fails if one blocks it. Members with `MANAGE_MESSAGES` are exempt.
+pub async fn moderate(channel: &Channel, user: &User, input: &MessageInput, pool: &Pool<MySql>, bus: &Bus) -> Result<Vec<Snowflake>, Error> {
+    if (permissions::compute(user.id, channel, pool).await? & user.allowed_permissions()).contains(Permissions::MANAGE_MESSAGES) { return Ok(Vec::new()) }
+    let broken = broken_rules(channel, user.id, input, pool).await?;
+    let Some(rule) = broken.first() else { return Ok(Vec::new()) };
+
+    if let Action::Timeout { duration_seconds } = rule.action {
+        let rules_channel_id = channel.parent_id.unwrap_or(channel.id);
+        let until = Utc::now() + Duration::seconds(duration_seconds as i64);
+        if moderation::set_timeout(rules_channel_id, user.id, Some(until), pool).await? {
+            bus.publish(rules_channel_id, Event::MemberTimeout { channel_id: rules_channel_id, user_id: user.id, timeout_until: Some(until) });
+            AuditEntry::record(rules_channel_id, user.id, AuditAction::AutomodTimeout, Some(user.id), Some(format!("Automod rule \"{}\"", rule.name)), Some(serde_json::json!({ "rule_id": rule.id, "timeout_until": until })), pool).await?;
+        }
+    }
+    if rule.action != Action::Flag { return Err(blocked(rule)) }
+
+    Ok(broken.iter().map(|rule| rule.id).collect())
+}
+
+/// Same as `moderate` for a webhook message. A webhook is never exempt and cannot be timed out,
+/// so a timeout rule only blocks the message.
+pub async fn moderate_webhook(channel: &Channel, webhook_id: Snowflake, input: &MessageInput, pool: &Pool<MySql>) -> Result<Vec<Snowflake>, Error> {
+    let broken = broken_rules(channel, webhook_id, input, pool).await?;
+    match broken.first() {
+        Some(rule) if rule.action != Action::Flag => Err(blocked(rule)),
+        _ => Ok(broken.iter().map(|rule| rule.id).collect()),
+    }
+}
+
+/// Queues a sent message for review by each rule that flagged it.
+pub async fn flag(message: &Message, channel: &Channel, rule_ids: &[Snowflake], pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    for rule_id in rule_ids {
+        sqlx::query("INSERT IGNORE INTO automod_flags (`message_id`, `rule_id`, `channel_id`, `author_id`, `created_at`) VALUES (?, ?, ?, ?, ?);")
+            .bind(message.id)
+            .bind(rule_id)
+            .bind(channel.parent_id.unwrap_or(channel.id))
+            .bind(message.author_id)
+            .bind(message.created_at)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[get("/channels/<id>/automod/rules")]
+async fn get_rules(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Rule>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Ok(Json(Rule::list(channel.id, pool).await?))
+}
+
+#[post("/channels/<id>/automod/rules", data = "<input>")]
+async fn create_rule(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<RuleInput>) -> Result<Json<Rule>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    let input = input.into_inner();
+    check_permission(&user, &channel, required(&input.action), pool).await?;
+    check_rule(&input.name, &input.trigger, &input.action)?;
+
+    let count: i64 = sqlx::query("SELECT COUNT(*) AS `count` FROM automod_rules WHERE `channel_id`=?;")
+        .bind(channel.id)
+        .fetch_one(pool.inner())
+        .await?
+        .try_get("count")?;
+    if count >= MAX_RULES {
+        return Err(Error::new(Status::BadRequest, format!("A channel can have at most {MAX_RULES} automod rules"), "Delete a rule first".to_string()));
+    }
+
+    let rule = Rule::create(channel.id, user.id, input, pool).await?;
+    AuditEntry::record(channel.id, user.id, AuditAction::AutomodRuleCreate, None, None, Some(serde_json::json!({ "rule_id": rule.id, "name": rule.name })), pool).await?;
+    Ok(Json(rule))
+}
+
+#[patch("/channels/<id>/automod/rules/<rule_id>", data = "<input>")]
+async fn edit_rule(pool: &State<Pool<MySql>>, user: User, id: Snowflake, rule_id: Snowflake, input: Json<RulePatch>) -> Result<Json<Rule>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let mut rule = Rule::from_id(channel.id, rule_id, pool).await?;
+    let input = input.into_inner();
+
+    if let Some(name) = input.name { rule.name = name }
+    if let Some(trigger) = input.trigger { rule.trigger = trigger }
+    if let Some(action) = input.action { rule.action = action }
+    if let Some(enabled) = input.enabled { rule.enabled = enabled }
+    if let Action::Timeout { .. } = rule.action {
+        check_permission(&user, &channel, required(&rule.action), pool).await?;
+    }
+    check_rule(&rule.name, &rule.trigger, &rule.action)?;
+    rule.save(pool).await?;
+
+    AuditEntry::record(channel.id, user.id, AuditAction::AutomodRuleUpdate, None, None, Some(serde_json::json!({ "rule_id": rule.id, "name": rule.name })), pool).await?;
+    Ok(Json(rule))
+}
+
+#[delete("/channels/<id>/automod/rules/<rule_id>")]
+async fn delete_rule(pool: &State<Pool<MySql>>, user: User, id: Snowflake, rule_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let rule = Rule::from_id(channel.id, rule_id, pool).await?;
+
+    rule.delete(pool).await?;
+    AuditEntry::record(channel.id, user.id, AuditAction::AutomodRuleDelete, None, None, Some(serde_json::json!({ "rule_id": rule.id, "name": rule.name })), pool).await?;
+    Ok(Status::NoContent)
+}
+
+/// The review queue of the channel and its threads.
+#[get("/channels/<id>/automod/flags?<before>&<limit>")]
+async fn get_flags(pool: &State<Pool<MySql>>, user: User, id: Snowflake, before: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<Flag>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    Ok(Json(Flag::list(channel.id, before, limit, pool).await?))
+}
+
+/// Marks a flagged message as reviewed, deleting it goes through the message routes.
+#[delete("/channels/<id>/automod/flags/<message_id>")]
+async fn dismiss_flag(pool: &State<Pool<MySql>>, user: User, id: Snowflake, message_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+
+    sqlx::query("DELETE FROM automod_flags WHERE `channel_id`=? AND `message_id`=?;")
+        .bind(channel.id)
+        .bind(message_id)
+        .execute(pool.inner())
+        .await?;
+
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_rules, create_rule, edit_rule, delete_rule, get_flags, dismiss_flag]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(content: &str) -> Context<'_> {
+        Context { content, mentions: 0, history: &[], now: Utc::now() }
+    }
+
+    fn rule(trigger: Trigger, action: Action) -> Rule {
+        let id = ids::generate();
+        Rule { id, channel_id: ids::generate(), name: "rule".to_string(), patterns: patterns(id, &trigger), trigger, action, enabled: true, creator_id: ids::generate(), created_at: Utc::now() }
+    }
+
+    fn blocking(trigger: Trigger) -> Rule {
+        rule(trigger, Action::Block)
+    }
+
+    #[test]
+    fn keywords_match_whole_words_ignoring_case() {
+        let rule = blocking(Trigger::Keywords { keywords: vec!["bad word".to_string(), "spam".to_string()] });
+        assert!(matches(&rule, &ctx("this is SPAM!")));
+        assert!(matches(&rule, &ctx("a Bad Word here")));
+        assert!(!matches(&rule, &ctx("spammer")));
+        assert!(!matches(&rule, &ctx("nothing to see")));
+        assert!(!matches(&blocking(Trigger::Keywords { keywords: vec![] }), &ctx("spam")));
+    }
+
+    #[test]
+    fn regex_ignores_case_and_invalid_patterns() {
+        let rule = blocking(Trigger::Regex { patterns: vec!["(".to_string(), r"free\s+nitro".to_string()] });
+        assert!(matches(&rule, &ctx("FREE   Nitro here")));
+        assert!(!matches(&rule, &ctx("free stuff")));
+    }
+
+    #[test]
+    fn links_outside_the_whitelist() {
+        let rule = blocking(Trigger::LinkWhitelist { domains: vec!["example.com".to_string()] });
+        assert!(!matches(&rule, &ctx("see https://docs.example.com/page")));
+        assert!(!matches(&rule, &ctx("see www.example.com")));
+        assert!(matches(&rule, &ctx("see https://example.com.evil.net")));
+        assert!(matches(&rule, &ctx("see http://other.org")));
+    }
+
+    #[test]
+    fn caps_ratio_above_the_limit() {
+        let rule = blocking(Trigger::Caps { max_ratio: 0.7, min_length: 5 });
+        assert!(matches(&rule, &ctx("STOP SHOUTING")));
+        assert!(!matches(&rule, &ctx("Stop Shouting")));
+        // too short to count
+        assert!(!matches(&rule, &ctx("OK!")));
+        assert!(!matches(&rule, &ctx("12345 !!!")));
+    }
+
+    #[test]
+    fn mentions_above_the_limit() {
+        let rule = blocking(Trigger::MentionSpam { max_mentions: 3 });
+        assert!(!matches(&rule, &Context { mentions: 3, ..ctx("hi") }));
+        assert!(matches(&rule, &Context { mentions: 4, ..ctx("hi") }));
+    }
+
+    #[test]
+    fn repeated_messages_in_the_window() {
+        let now = Utc::now();
+        let history = vec![
+            (now - Duration::seconds(10), "Buy now".to_string()),
+            (now - Duration::seconds(20), " buy NOW ".to_string()),
+            (now - Duration::seconds(600), "buy now".to_string()),
+        ];
+        let ctx = Context { content: "buy now", mentions: 0, history: &history, now };
+
+        assert!(matches(&blocking(Trigger::Repeated { max_repeats: 2, window_seconds: 60 }), &ctx));
+        assert!(!matches(&blocking(Trigger::Repeated { max_repeats: 3, window_seconds: 60 }), &ctx));
+        assert!(matches(&blocking(Trigger::Repeated { max_repeats: 3, window_seconds: 3600 }), &ctx));
+        assert!(!matches(&blocking(Trigger::Repeated { max_repeats: 1, window_seconds: 60 }), &Context { content: "hello", ..ctx }));
+    }
+
+    #[test]
+    fn evaluate_orders_by_severity_and_skips_disabled_rules() {
+        let spam = || Trigger::Keywords { keywords: vec!["spam".to_string()] };
+        let mut disabled = rule(spam(), Action::Timeout { duration_seconds: 60 });
+        disabled.enabled = false;
+        let rules = vec![
+            rule(spam(), Action::Flag),
+            disabled,
+            rule(spam(), Action::Timeout { duration_seconds: 60 }),
+            rule(Trigger::Keywords { keywords: vec!["other".to_string()] }, Action::Timeout { duration_seconds: 60 }),
+            rule(spam(), Action::Block),
+        ];
+
+        let actions = evaluate(&rules, &ctx("spam")).iter().map(|rule| rule.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![Action::Timeout { duration_seconds: 60 }, Action::Block, Action::Flag]);
+        assert!(evaluate(&rules, &ctx("clean")).is_empty());
+    }
+
+    #[test]
+    fn patterns_are_compiled_again_once_edited() {
+        let mut rule = blocking(Trigger::Keywords { keywords: vec!["spam".to_string()] });
+        assert!(Arc::ptr_eq(&rule.patterns, &patterns(rule.id, &rule.trigger)));
+
+        rule.trigger = Trigger::Keywords { keywords: vec!["scam".to_string()] };
+        rule.patterns = patterns(rule.id, &rule.trigger);
+        assert!(matches(&rule, &ctx("a scam")));
+        assert!(!matches(&rule, &ctx("some spam")));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{MySql, Pool, QueryBuilder, Row};
+
+use super::{
+    applications::check_session,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    messages::{self, Message, SELECT_MESSAGE},
+    permissions::{self, check_permission, Permissions},
+    users::User,
+};
+
+const MAX_NOTE_LENGTH: usize = 500;
+const MAX_BOOKMARKS: i64 = 1000;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// Private to its user.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Bookmark {
+    pub message: Message,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct BookmarkInput {
+    pub note: Option<String>,
+}
+
+impl Bookmark {
+    /// Bookmarks of messages the user can still read, newest messages first.
+    /// Permissions are computed once for every channel, bookmarks of channels that are gone are left out.
+    pub async fn list(user: &User, before: Option<Snowflake>, limit: u32, pool: &Pool<MySql>) -> Result<Vec<Bookmark>, sqlx::error::Error> {
+        let readable = permissions::compute_all(user.id, pool).await?
+            .into_iter()
+            .filter(|(_, perms)| (*perms & user.allowed_permissions()).contains(Permissions::READ))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        if readable.is_empty() { return Ok(Vec::new()) }
+
+        // threads follow their parent channel
+        let mut query = QueryBuilder::<MySql>::new(SELECT_MESSAGE);
+        query.push(" JOIN bookmarks b ON b.`message_id`=m.`id` JOIN channels c ON c.`id`=m.`channel_id` WHERE b.`user_id`=").push_bind(user.id);
+        query.push(" AND m.`deleted_at` IS NULL AND COALESCE(c.`parent_id`, c.`id`) IN (");
+        let mut separated = query.separated(", ");
+        for id in &readable {
+            separated.push_bind(*id);
+        }
+        query.push(")");
+        if let Some(before) = before {
+            query.push(" AND m.`id`<").push_bind(before);
+        }
+        query.push(" GROUP BY m.`id` ORDER BY m.`id` DESC LIMIT ").push_bind(limit);
+
+        let rows = query.build().fetch_all(pool).await?;
+        let mut messages = rows.iter().map(messages::from_row).collect::<Result<Vec<_>, _>>()?;
+        if messages.is_empty() { return Ok(Vec::new()) }
+        messages::fill(&mut messages, pool).await?;
+
+        let mut query = QueryBuilder::<MySql>::new("SELECT `message_id`, `note`, `created_at` FROM bookmarks WHERE `user_id`=");
+        query.push_bind(user.id).push(" AND `message_id` IN (");
+        let mut separated = query.separated(", ");
+        for message in &messages {
+            separated.push_bind(message.id);
+        }
+        query.push(")");
+        let mut notes = HashMap::new();
+        for row in query.build().fetch_all(pool).await? {
+            notes.insert(row.try_get::<Snowflake, _>("message_id")?, (row.try_get("note")?, row.try_get("created_at")?));
+        }
+
+        Ok(messages.into_iter()
+            .filter_map(|message| {
+                let (note, created_at) = notes.remove(&message.id)?;
+                Some(Bookmark { message, note, created_at })
+            })
+            .collect())
+    }
+
+    /// Adds the bookmark or replaces its note, keeping when it was first saved.
+    pub async fn save(user_id: Snowflake, message: Message, note: Option<String>, pool: &Pool<MySql>) -> Result<Bookmark, sqlx::error::Error> {
+        sqlx::query("INSERT INTO bookmarks (`user_id`, `message_id`, `channel_id`, `note`, `created_at`) VALUES (?, ?, ?, ?, ?) \
+            ON DUPLICATE KEY UPDATE `note`=VALUES(`note`);")
+            .bind(user_id)
+            .bind(message.id)
+            .bind(message.channel_id)
+            .bind(&note)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+
+        let created_at = sqlx::query("SELECT `created_at` FROM bookmarks WHERE `user_id`=? AND `message_id`=?;")
+            .bind(user_id)
+            .bind(message.id)
+            .fetch_one(pool)
+            .await?
+            .try_get("created_at")?;
+
+        Ok(Bookmark { message, note, created_at })
+    }
+
+    pub async fn exists(user_id: Snowflake, message_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("SELECT 1 FROM bookmarks WHERE `user_id`=? AND `message_id`=?;")
+            .bind(user_id)
+            .bind(message_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(q.is_some())
+    }
+
+    /// Returns `false` if there was no such bookmark.
+    pub async fn remove(user_id: Snowflake, message_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("DELETE FROM bookmarks WHERE `user_id`=? AND `message_id`=?;")
+            .bind(user_id)
+            .bind(message_id)
+            .execute(pool)
+            .await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+
+    pub async fn count(user_id: Snowflake, pool: &Pool<MySql>) -> Result<i64, sqlx::error::Error> {
+        sqlx::query("SELECT COUNT(*) AS `count` FROM bookmarks WHERE `user_id`=?;")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await?
+            .try_get("count")
+    }
+}
+
+#[get("/users/@me/bookmarks?<before>&<limit>")]
+async fn get_bookmarks(pool: &State<Pool<MySql>>, user: User, before: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<Bookmark>>, Error> {
+    check_session(&user)?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    Ok(Json(Bookmark::list(&user, before, limit, pool).await?))
+}
+
+/// Saving an existing bookmark again replaces its note. Sent to the other sessions of the user.
+#[put("/channels/<id>/messages/<mid>/bookmark", data = "<input>")]
+async fn save_bookmark(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, input: Option<Json<BookmarkInput>>) -> Result<Json<Bookmark>, Error> {
+    check_session(&user)?;
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let message = Message::from_id(channel.id, mid, pool).await?;
+
+    let note = input.and_then(|input| input.into_inner().note).filter(|note| !note.trim().is_empty());
+    if note.as_ref().map(|note| note.chars().count() > MAX_NOTE_LENGTH).unwrap_or(false) {
+        return Err(Error::new(Status::BadRequest, format!("A note cannot be longer than {MAX_NOTE_LENGTH} caracters"), "Check the body of your request".to_string()));
+    }
+    // editing the note of an existing bookmark is always allowed
+    if !Bookmark::exists(user.id, message.id, pool).await? && Bookmark::count(user.id, pool).await? >= MAX_BOOKMARKS {
+        return Err(Error::new(Status::BadRequest, format!("You cannot have more than {MAX_BOOKMARKS} bookmarks"), "Remove older bookmarks first".to_string()));
+    }
+
+    let bookmark = Bookmark::save(user.id, message, note, pool).await?;
+    bus.publish_to_user(user.id, Event::BookmarkSave(bookmark.clone()));
+    Ok(Json(bookmark))
+}
+
+#[delete("/users/@me/bookmarks/<mid>")]
+async fn remove_bookmark(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, mid: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+    if Bookmark::remove(user.id, mid, pool).await? {
+        bus.publish_to_user(user.id, Event::BookmarkRemove { message_id: mid });
+    }
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_bookmarks, save_bookmark, remove_bookmark]
+}
@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::check_session,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    users::User,
+};
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_CATEGORIES: usize = 50;
+
+/// Groups channels in the list of one user.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Category {
+    pub id: Snowflake,
+    pub name: String,
+    pub position: u32,
+}
+
+/// A channel as placed in the list of the user.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ListedChannel {
+    #[serde(flatten)]
+    pub channel: Channel,
+    pub category_id: Option<Snowflake>,
+    pub position: u32,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CategoryInput {
+    pub name: String,
+    #[serde(default)]
+    pub position: u32,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CategoryPatch {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CategoryPosition {
+    pub id: Snowflake,
+    pub position: u32,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChannelPosition {
+    pub id: Snowflake,
+    pub position: u32,
+    /// `None` moves the channel out of any category.
+    #[serde(default)]
+    pub category_id: Option<Snowflake>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Positions {
+    #[serde(default)]
+    pub categories: Vec<CategoryPosition>,
+    #[serde(default)]
+    pub channels: Vec<ChannelPosition>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Category, sqlx::error::Error> {
+    Ok(Category {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        position: row.try_get("position")?,
+    })
+}
+
+impl Category {
+    pub async fn from_id(user_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Category, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM channel_categories WHERE `user_id`=? AND `id`=?;")
+            .bind(user_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(user_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Category>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM channel_categories WHERE `user_id`=? ORDER BY `position` ASC, `id` ASC;")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    pub async fn create(user_id: Snowflake, name: String, position: u32, pool: &Pool<MySql>) -> Result<Category, sqlx::error::Error> {
+        let category = Category { id: ids::generate(), name, position };
+        sqlx::query("INSERT INTO channel_categories (`id`, `user_id`, `name`, `position`) VALUES (?, ?, ?, ?);")
+            .bind(category.id)
+            .bind(user_id)
+            .bind(&category.name)
+            .bind(category.position)
+            .execute(pool)
+            .await?;
+
+        Ok(category)
+    }
+
+    pub async fn save(&self, user_id: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE channel_categories SET `name`=?, `position`=? WHERE `user_id`=? AND `id`=?;")
+            .bind(&self.name)
+            .bind(self.position)
+            .bind(user_id)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Its channels are moved out of any category.
+    pub async fn delete(&self, user_id: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE channel_members SET `category_id`=NULL WHERE `user_id`=? AND `category_id`=?;")
+            .bind(user_id)
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM channel_categories WHERE `user_id`=? AND `id`=?;")
+            .bind(user_id)
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Channels of the user, threads left out, in list order.
+pub async fn list_channels(user_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<ListedChannel>, sqlx::error::Error> {
+    let q = sqlx::query("SELECT m.`channel_id`, m.`category_id`, m.`position` FROM channel_members m JOIN channels c ON c.`id`=m.`channel_id` \
+        WHERE m.`user_id`=? AND c.`kind`<>'thread' ORDER BY m.`position` ASC, m.`channel_id` ASC;")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut channels = Vec::with_capacity(q.len());
+    for row in &q {
+        channels.push(ListedChannel {
+            channel: Channel::from_id(row.try_get("channel_id")?, pool).await?,
+            category_id: row.try_get("category_id")?,
+            position: row.try_get("position")?,
+        });
+    }
+    Ok(channels)
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Error::new(Status::BadRequest, format!("A category name must be between 1 and {MAX_NAME_LENGTH} caracters"), "Check the body of your request".to_string()));
+    }
+
+    Ok(())
+}
+
+#[get("/users/@me/channels")]
+async fn get_channels(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<ListedChannel>>, Error> {
+    check_session(&user)?;
+    Ok(Json(list_channels(user.id, pool).await?))
+}
+
+#[get("/users/@me/categories")]
+async fn get_categories(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<Category>>, Error> {
+    check_session(&user)?;
+    Ok(Json(Category::list(user.id, pool).await?))
+}
+
+#[post("/users/@me/categories", data = "<input>")]
+async fn create_category(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, input: Json<CategoryInput>) -> Result<Json<Category>, Error> {
+    check_session(&user)?;
+    let input = input.into_inner();
+    check_name(&input.name)?;
+    if Category::list(user.id, pool).await?.len() >= MAX_CATEGORIES {
+        return Err(Error::new(Status::BadRequest, format!("You cannot have more than {MAX_CATEGORIES} categories"), "Delete unused categories first".to_string()));
+    }
+
+    let category = Category::create(user.id, input.name, input.position, pool).await?;
+    bus.publish_to_user(user.id, Event::CategoryUpdate(category.clone()));
+    Ok(Json(category))
+}
+
+#[patch("/users/@me/categories/<id>", data = "<input>")]
+async fn edit_category(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, input: Json<CategoryPatch>) -> Result<Json<Category>, Error> {
+    check_session(&user)?;
+    let mut category = Category::from_id(user.id, id, pool).await?;
+    if let Some(name) = input.into_inner().name {
+        check_name(&name)?;
+        category.name = name;
+    }
+
+    category.save(user.id, pool).await?;
+    bus.publish_to_user(user.id, Event::CategoryUpdate(category.clone()));
+    Ok(Json(category))
+}
+
+#[delete("/users/@me/categories/<id>")]
+async fn delete_category(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+    let category = Category::from_id(user.id, id, pool).await?;
+
+    category.delete(user.id, pool).await?;
+    bus.publish_to_user(user.id, Event::CategoryDelete { id: category.id });
+    Ok(Status::NoContent)
+}
+
+/// Reorders categories and channels all at once, nothing changes if one of them is invalid.
+#[patch("/channels/positions", data = "<input>")]
+async fn edit_positions(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, input: Json<Positions>) -> Result<Json<Positions>, Error> {
+    check_session(&user)?;
+    let input = input.into_inner();
+    let categories: HashSet<Snowflake> = Category::list(user.id, pool).await?.into_iter().map(|c| c.id).collect();
+    let channels: HashSet<Snowflake> = list_channels(user.id, pool).await?.into_iter().map(|c| c.channel.id).collect();
+
+    let unknown = |id: Snowflake| Error::new(Status::BadRequest, format!("{id} is not one of your channels or categories"), "Check the body of your request".to_string());
+    for position in &input.categories {
+        if !categories.contains(&position.id) { return Err(unknown(position.id)) }
+    }
+    for position in &input.channels {
+        if !channels.contains(&position.id) { return Err(unknown(position.id)) }
+        if let Some(category_id) = position.category_id {
+            if !categories.contains(&category_id) { return Err(unknown(category_id)) }
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    for position in &input.categories {
+        sqlx::query("UPDATE channel_categories SET `position`=? WHERE `user_id`=? AND `id`=?;")
+            .bind(position.position)
+            .bind(user.id)
+            .bind(position.id)
+            .execute(&mut tx)
+            .await?;
+    }
+    for position in &input.channels {
+        sqlx::query("UPDATE channel_members SET `position`=?, `category_id`=? WHERE `user_id`=? AND `channel_id`=?;")
+            .bind(position.position)
+            .bind(position.category_id)
+            .bind(user.id)
+            .bind(position.id)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    bus.publish_to_user(user.id, Event::PositionsUpdate(input.clone()));
+    Ok(Json(input))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_channels, get_categories, create_category, edit_category, delete_category, edit_positions]
+}
@@ -12,7 +12,8 @@ fn gen_hash(buf: &[u8]) -> String {
     format!("{:x}", hash)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
 pub struct CdnId(String);
 
 impl CdnId {
@@ -20,6 +21,29 @@ impl CdnId {
         assert!(hash.len() != 32, "hash ID must be a 32 caracters long string");
         Self(hash)
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub async fn exists(&self, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let row = sqlx::query("SELECT `hash` FROM cdn WHERE `hash`=?;")
+            .bind(&self.0)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Path of the file on the CDN route, `/cdn/<hash>.<extension>`.
+    pub async fn url(&self, pool: &Pool<MySql>) -> Result<String, sqlx::error::Error> {
+        let q = sqlx::query("SELECT `extension` FROM cdn WHERE `hash`=?;")
+            .bind(&self.0)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(format!("/cdn/{}.{}", self.0, q.try_get::<String, _>("extension")?))
+    }
 }
 
 #[derive(Debug)]
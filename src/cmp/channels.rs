@@ -1,8 +1,223 @@
-use super::cdn::CdnId;
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{Pool, MySql, Row};
 
+use super::{applications::{check_scope, Scope}, cdn::CdnId, errors::Error, gateway::{Bus, Event}, ids::{self, Snowflake}, members::check_grant, permissions::{check_permission, Permissions}, users::User};
+
+const MAX_NAME_LENGTH: usize = 100;
+/// 6 hours.
+const MAX_SLOWMODE_SECONDS: u32 = 6 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelKind {
+    Text,
+    /// Private conversation between exactly two users.
+    Dm,
+    /// Private conversation managed by its owner.
+    GroupDm,
+    /// Conversation spawned from a message of its parent channel.
+    Thread,
+}
+
+impl ChannelKind {
+    pub fn parse(source: &str) -> Self {
+        match source {
+            "dm" => Self::Dm,
+            "group_dm" => Self::GroupDm,
+            "thread" => Self::Thread,
+            _ => Self::Text,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Dm => "dm",
+            Self::GroupDm => "group_dm",
+            Self::Thread => "thread",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct Channel {
-    id: String,
-    name: String,
-    owner: String,
-    icon: CdnId
-}
\ No newline at end of file
+    pub id: Snowflake,
+    pub kind: ChannelKind,
+    pub name: String,
+    pub owner: Snowflake,
+    pub icon: Option<CdnId>,
+    pub default_permissions: Permissions,
+    pub parent_id: Option<Snowflake>,
+    /// Minimum delay between two messages of a member, 0 when off. Threads follow their parent.
+    pub slowmode_seconds: u32,
+}
+
+impl Channel {
+    pub async fn from_id(id: Snowflake, pool: &Pool<MySql>) -> Result<Channel, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM channels WHERE `id`=?;")
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(Self {
+            id: q.try_get("id")?,
+            kind: ChannelKind::parse(&q.try_get::<String, _>("kind")?),
+            name: q.try_get("name")?,
+            owner: q.try_get("owner")?,
+            icon: q.try_get::<Option<String>, _>("icon")?.map(CdnId::new),
+            default_permissions: Permissions::from_bits(q.try_get("default_permissions")?),
+            parent_id: q.try_get("parent_id")?,
+            slowmode_seconds: q.try_get("slowmode_seconds")?,
+        })
+    }
+
+    /// Creates the channel with its owner and `members` as first members.
+    pub async fn create(kind: ChannelKind, name: String, owner: Snowflake, icon: Option<CdnId>, members: &[Snowflake], pool: &Pool<MySql>) -> Result<Channel, sqlx::error::Error> {
+        let channel = Self { id: ids::generate(), kind, name, owner, icon, default_permissions: Permissions::DEFAULT, parent_id: None, slowmode_seconds: 0 };
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("INSERT INTO channels (`id`, `kind`, `name`, `owner`, `icon`, `default_permissions`) VALUES (?, ?, ?, ?, ?, ?);")
+            .bind(channel.id)
+            .bind(channel.kind.as_str())
+            .bind(&channel.name)
+            .bind(channel.owner)
+            .bind(channel.icon.as_ref().map(CdnId::as_str))
+            .bind(channel.default_permissions.bits())
+            .execute(&mut tx)
+            .await?;
+
+        for user_id in std::iter::once(&channel.owner).chain(members) {
+            sqlx::query("INSERT IGNORE INTO channel_members (`channel_id`, `user_id`) VALUES (?, ?);")
+                .bind(channel.id)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(channel)
+    }
+
+    pub async fn save(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE channels SET `name`=?, `owner`=?, `icon`=?, `default_permissions`=?, `slowmode_seconds`=? WHERE `id`=?;")
+            .bind(&self.name)
+            .bind(self.owner)
+            .bind(self.icon.as_ref().map(CdnId::as_str))
+            .bind(self.default_permissions.bits())
+            .bind(self.slowmode_seconds)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChannelInput {
+    pub name: String,
+    pub icon: Option<CdnId>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChannelPatch {
+    pub name: Option<String>,
+    pub icon: Option<CdnId>,
+    pub default_permissions: Option<Permissions>,
+    pub slowmode_seconds: Option<u32>,
+}
+
+impl ChannelPatch {
+    /// `own` are the permissions of the editor, who cannot give every member more than they have.
+    fn apply(self, channel: &mut Channel, own: Permissions) -> Result<(), Error> {
+        if let Some(seconds) = self.slowmode_seconds {
+            if seconds > MAX_SLOWMODE_SECONDS {
+                return Err(Error::new(Status::BadRequest, format!("Slowmode is at most {MAX_SLOWMODE_SECONDS} seconds"), "Check the body of your request".to_string()));
+            }
+        }
+        if let Some(perms) = self.default_permissions { check_grant(perms, own)? }
+
+        if let Some(name) = self.name { channel.name = name }
+        if let Some(icon) = self.icon { channel.icon = Some(icon) }
+        if let Some(perms) = self.default_permissions { channel.default_permissions = Permissions::from_bits(perms.bits()) }
+        if let Some(seconds) = self.slowmode_seconds { channel.slowmode_seconds = seconds }
+        Ok(())
+    }
+}
+
+pub async fn check_input(name: Option<&str>, icon: Option<&CdnId>, pool: &Pool<MySql>) -> Result<(), Error> {
+    if let Some(name) = name {
+        if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(Error::new(Status::BadRequest, format!("A channel name must be between 1 and {MAX_NAME_LENGTH} caracters"), "Check the body of your request".to_string()));
+        }
+    }
+    if let Some(icon) = icon {
+        if !icon.exists(pool).await? {
+            return Err(Error::new(Status::BadRequest, format!("No CDN file found with hash {}", icon.as_str()), "Upload the icon first".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[post("/channels", data = "<input>")]
+async fn create_channel(pool: &State<Pool<MySql>>, user: User, input: Json<ChannelInput>) -> Result<Json<Channel>, Error> {
+    check_scope(&user, Scope::ChannelsManage)?;
+    let input = input.into_inner();
+    check_input(Some(&input.name), input.icon.as_ref(), pool).await?;
+
+    Ok(Json(Channel::create(ChannelKind::Text, input.name, user.id, input.icon, &[], pool).await?))
+}
+
+#[get("/channels/<id>")]
+async fn get_channel(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Channel>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(channel))
+}
+
+#[patch("/channels/<id>", data = "<input>")]
+async fn edit_channel(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, input: Json<ChannelPatch>) -> Result<Json<Channel>, Error> {
+    let mut channel = Channel::from_id(id, pool).await?;
+    let own = check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let input = input.into_inner();
+    check_input(input.name.as_deref(), input.icon.as_ref(), pool).await?;
+    input.apply(&mut channel, own)?;
+    channel.save(pool).await?;
+
+    bus.publish(channel.id, Event::ChannelUpdate(channel.clone()));
+    Ok(Json(channel))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create_channel, get_channel, edit_channel]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel() -> Channel {
+        Channel { id: ids::generate(), kind: ChannelKind::Text, name: "general".to_string(), owner: ids::generate(), icon: None, default_permissions: Permissions::DEFAULT, parent_id: None, slowmode_seconds: 0 }
+    }
+
+    fn patch(default_permissions: Permissions) -> ChannelPatch {
+        ChannelPatch { name: Some("renamed".to_string()), icon: None, default_permissions: Some(default_permissions), slowmode_seconds: None }
+    }
+
+    #[test]
+    fn default_permissions_cannot_exceed_the_editor() {
+        let own = Permissions::DEFAULT | Permissions::MANAGE_CHANNEL;
+        let mut edited = channel();
+
+        let err = patch(Permissions::DEFAULT | Permissions::BAN).apply(&mut edited, own).unwrap_err();
+        assert_eq!(err.status(), Status::Forbidden);
+        assert_eq!(edited.name, "general");
+        assert_eq!(edited.default_permissions, Permissions::DEFAULT);
+
+        patch(Permissions::READ | Permissions::SEND).apply(&mut edited, own).unwrap();
+        assert_eq!(edited.default_permissions, Permissions::READ | Permissions::SEND);
+        patch(Permissions::ALL).apply(&mut edited, Permissions::ALL).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use serde_json::{Map, Value};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::Application,
+    channels::Channel,
+    errors::Error,
+    ids::{self, Snowflake},
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const MAX_COMMANDS: usize = 100;
+const MAX_OPTIONS: usize = 25;
+const MAX_CHOICES: usize = 25;
+const MAX_NAME_LENGTH: usize = 32;
+const MAX_DESCRIPTION_LENGTH: usize = 100;
+const MAX_STRING_LENGTH: usize = 6000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// A user id.
+    User,
+    /// A channel id.
+    Channel,
+}
+
+impl OptionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Integer => "integer",
+            Self::Number => "number",
+            Self::Boolean => "boolean",
+            Self::User => "user",
+            Self::Channel => "channel",
+        }
+    }
+
+    fn accepts(&self, value: &Value) -> bool {
+        match self {
+            Self::String => value.as_str().is_some_and(|s| s.chars().count() <= MAX_STRING_LENGTH),
+            Self::Integer => value.is_i64(),
+            Self::Number => value.is_number(),
+            Self::Boolean => value.is_boolean(),
+            Self::User | Self::Channel => serde_json::from_value::<Snowflake>(value.clone()).is_ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CommandOption {
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "type")]
+    pub kind: OptionType,
+    #[serde(default)]
+    pub required: bool,
+    /// When set, the value must be one of them. Strings and numbers only.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<OptionChoice>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OptionChoice {
+    pub name: String,
+    pub value: Value,
+}
+
+/// Slash command registered by an application, invoked in the channels its bot is in.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Command {
+    pub id: Snowflake,
+    pub application_id: Snowflake,
+    pub name: String,
+    pub description: String,
+    pub options: Vec<CommandOption>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CommandInput {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub options: Vec<CommandOption>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CommandPatch {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub options: Option<Vec<CommandOption>>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Command, sqlx::error::Error> {
+    let options = serde_json::from_str(&row.try_get::<String, _>("options")?)
+        .map_err(|_| sqlx::Error::ColumnDecode { index: "options".to_string(), source: "invalid command options".into() })?;
+
+    Ok(Command {
+        id: row.try_get("id")?,
+        application_id: row.try_get("application_id")?,
+        name: row.try_get("name")?,
+        description: row.try_get("description")?,
+        options,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl Command {
+    pub async fn from_id(application_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Command, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM application_commands WHERE `application_id`=? AND `id`=?;")
+            .bind(application_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn from_name(application_id: Snowflake, name: &str, pool: &Pool<MySql>) -> Result<Command, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM application_commands WHERE `application_id`=? AND `name`=?;")
+            .bind(application_id)
+            .bind(name)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(application_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Command>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM application_commands WHERE `application_id`=? ORDER BY `name` ASC;")
+            .bind(application_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    /// Commands of the bots that are members of the channel.
+    pub async fn list_for_channel(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Command>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT c.* FROM application_commands c JOIN channel_members m ON m.`user_id`=c.`application_id` WHERE m.`channel_id`=? ORDER BY c.`name` ASC, c.`application_id` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    fn new(application_id: Snowflake, input: CommandInput) -> Command {
+        let id = ids::generate();
+        Command { id, application_id, name: input.name, description: input.description, options: input.options, created_at: id.created_at() }
+    }
+
+    pub async fn create(application_id: Snowflake, input: CommandInput, pool: &Pool<MySql>) -> Result<Command, sqlx::error::Error> {
+        let command = Command::new(application_id, input);
+        sqlx::query("INSERT INTO application_commands (`id`, `application_id`, `name`, `description`, `options`, `created_at`) VALUES (?, ?, ?, ?, ?, ?);")
+            .bind(command.id)
+            .bind(command.application_id)
+            .bind(&command.name)
+            .bind(&command.description)
+            .bind(serde_json::to_string(&command.options).unwrap_or_default())
+            .bind(command.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(command)
+    }
+
+    /// Replaces every command of the application at once.
+    pub async fn overwrite(application_id: Snowflake, inputs: Vec<CommandInput>, pool: &Pool<MySql>) -> Result<Vec<Command>, sqlx::error::Error> {
+        let commands: Vec<Command> = inputs.into_iter().map(|input| Command::new(application_id, input)).collect();
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM application_commands WHERE `application_id`=?;")
+            .bind(application_id)
+            .execute(&mut tx)
+            .await?;
+        for command in &commands {
+            sqlx::query("INSERT INTO application_commands (`id`, `application_id`, `name`, `description`, `options`, `created_at`) VALUES (?, ?, ?, ?, ?, ?);")
+                .bind(command.id)
+                .bind(command.application_id)
+                .bind(&command.name)
+                .bind(&command.description)
+                .bind(serde_json::to_string(&command.options).unwrap_or_default())
+                .bind(command.created_at)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(commands)
+    }
+
+    pub async fn save(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE application_commands SET `name`=?, `description`=?, `options`=? WHERE `id`=?;")
+            .bind(&self.name)
+            .bind(&self.description)
+            .bind(serde_json::to_string(&self.options).unwrap_or_default())
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("DELETE FROM application_commands WHERE `id`=?;")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn bad_request(message: String) -> Error {
+    Error::new(Status::BadRequest, message, "Check the body of your request".to_string())
+}
+
+/// Lowercase letters, digits, `-` and `_`.
+fn check_name(name: &str, what: &str) -> Result<(), Error> {
+    let valid = (1..=MAX_NAME_LENGTH).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(bad_request(format!("A {what} name must be 1 to {MAX_NAME_LENGTH} lowercase letters, digits, - or _")));
+    }
+
+    Ok(())
+}
+
+fn check_description(description: &str, what: &str) -> Result<(), Error> {
+    if description.trim().is_empty() || description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(bad_request(format!("A {what} description must be between 1 and {MAX_DESCRIPTION_LENGTH} caracters")));
+    }
+
+    Ok(())
+}
+
+fn check_command(name: &str, description: &str, options: &[CommandOption]) -> Result<(), Error> {
+    check_name(name, "command")?;
+    check_description(description, "command")?;
+    if options.len() > MAX_OPTIONS {
+        return Err(bad_request(format!("A command cannot have more than {MAX_OPTIONS} options")));
+    }
+
+    for (i, option) in options.iter().enumerate() {
+        check_name(&option.name, "option")?;
+        check_description(&option.description, "option")?;
+        if options[..i].iter().any(|other| other.name == option.name) {
+            return Err(bad_request(format!("The option {} is defined twice", option.name)));
+        }
+        if option.required && options[..i].iter().any(|other| !other.required) {
+            return Err(bad_request("Required options must come before optional ones".to_string()));
+        }
+
+        if option.choices.is_empty() { continue }
+        if !matches!(option.kind, OptionType::String | OptionType::Integer | OptionType::Number) {
+            return Err(bad_request(format!("Options of type {} cannot have choices", option.kind.as_str())));
+        }
+        if option.choices.len() > MAX_CHOICES {
+            return Err(bad_request(format!("An option cannot have more than {MAX_CHOICES} choices")));
+        }
+        for choice in &option.choices {
+            if choice.name.trim().is_empty() || choice.name.chars().count() > MAX_DESCRIPTION_LENGTH || !option.kind.accepts(&choice.value) {
+                return Err(bad_request(format!("Every choice of {} needs a name and a {} value", option.name, option.kind.as_str())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_unique(commands: &[&str]) -> Result<(), Error> {
+    if commands.len() > MAX_COMMANDS {
+        return Err(bad_request(format!("An application cannot have more than {MAX_COMMANDS} commands")));
+    }
+    for (i, name) in commands.iter().enumerate() {
+        if commands[..i].contains(name) {
+            return Err(Error::new(Status::Conflict, format!("The application already has a command named {name}"), "Pick another name".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the options given when invoking `command`, dropping the null ones.
+pub fn check_options(command: &Command, options: &mut Map<String, Value>) -> Result<(), Error> {
+    options.retain(|_, value| !value.is_null());
+
+    if let Some(name) = options.keys().find(|name| !command.options.iter().any(|option| &option.name == *name)) {
+        return Err(bad_request(format!("The command {} has no option {name}", command.name)));
+    }
+    for option in &command.options {
+        let Some(value) = options.get(&option.name) else {
+            if option.required { return Err(bad_request(format!("The option {} is required", option.name))) }
+            continue;
+        };
+        if !option.kind.accepts(value) {
+            return Err(bad_request(format!("The option {} must be a {}", option.name, option.kind.as_str())));
+        }
+        if !option.choices.is_empty() && !option.choices.iter().any(|choice| &choice.value == value) {
+            return Err(bad_request(format!("The option {} must be one of its choices", option.name)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Commands are managed by the owner of the application or by its bot.
+#[get("/applications/<id>/commands")]
+async fn get_commands(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Command>>, Error> {
+    let application = Application::managed_by(&user, id, pool).await?;
+
+    Ok(Json(Command::list(application.id, pool).await?))
+}
+
+#[post("/applications/<id>/commands", data = "<input>")]
+async fn create_command(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<CommandInput>) -> Result<Json<Command>, Error> {
+    let application = Application::managed_by(&user, id, pool).await?;
+    let input = input.into_inner();
+    check_command(&input.name, &input.description, &input.options)?;
+
+    let commands = Command::list(application.id, pool).await?;
+    let mut names: Vec<&str> = commands.iter().map(|command| command.name.as_str()).collect();
+    names.push(&input.name);
+    check_unique(&names)?;
+
+    Ok(Json(Command::create(application.id, input, pool).await?))
+}
+
+/// Replaces every command of the application, commands left out are deleted.
+#[put("/applications/<id>/commands", data = "<input>")]
+async fn overwrite_commands(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<Vec<CommandInput>>) -> Result<Json<Vec<Command>>, Error> {
+    let application = Application::managed_by(&user, id, pool).await?;
+    let input = input.into_inner();
+    for command in &input {
+        check_command(&command.name, &command.description, &command.options)?;
+    }
+    check_unique(&input.iter().map(|command| command.name.as_str()).collect::<Vec<_>>())?;
+
+    Ok(Json(Command::overwrite(application.id, input, pool).await?))
+}
+
+#[patch("/applications/<id>/commands/<command_id>", data = "<input>")]
+async fn edit_command(pool: &State<Pool<MySql>>, user: User, id: Snowflake, command_id: Snowflake, input: Json<CommandPatch>) -> Result<Json<Command>, Error> {
+    let application = Application::managed_by(&user, id, pool).await?;
+    let mut command = Command::from_id(application.id, command_id, pool).await?;
+    let input = input.into_inner();
+
+    if let Some(name) = input.name { command.name = name }
+    if let Some(description) = input.description { command.description = description }
+    if let Some(options) = input.options { command.options = options }
+    check_command(&command.name, &command.description, &command.options)?;
+    if Command::from_name(application.id, &command.name, pool).await.is_ok_and(|other| other.id != command.id) {
+        return Err(Error::new(Status::Conflict, format!("The application already has a command named {}", command.name), "Pick another name".to_string()));
+    }
+    command.save(pool).await?;
+
+    Ok(Json(command))
+}
+
+#[delete("/applications/<id>/commands/<command_id>")]
+async fn delete_command(pool: &State<Pool<MySql>>, user: User, id: Snowflake, command_id: Snowflake) -> Result<Status, Error> {
+    let application = Application::managed_by(&user, id, pool).await?;
+
+    Command::from_id(application.id, command_id, pool).await?.delete(pool).await?;
+    Ok(Status::NoContent)
+}
+
+/// Commands that can be invoked in the channel, threads offer the commands of their parent.
+#[get("/channels/<id>/commands")]
+async fn get_channel_commands(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Command>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Command::list_for_channel(channel.parent_id.unwrap_or(channel.id), pool).await?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_commands, create_command, overwrite_commands, edit_command, delete_command, get_channel_commands]
+}
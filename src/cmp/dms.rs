@@ -0,0 +1,214 @@
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{MySql, Pool, Row};
+
+use super::{
+    applications::{check_scope, check_session, Scope},
+    channels::{Channel, ChannelKind},
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    members::Member,
+    users::User,
+};
+
+/// Read from the app config (`Rocket.toml`).
+#[derive(Debug, serde::Deserialize)]
+pub struct DmConfig {
+    #[serde(default = "default_group_dm_max_size")]
+    pub group_dm_max_size: usize,
+}
+
+fn default_group_dm_max_size() -> usize {
+    10
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DmInput {
+    pub recipient_id: Snowflake,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct GroupDmInput {
+    #[serde(default)]
+    pub name: String,
+    pub recipients: Vec<Snowflake>,
+}
+
+fn pair(a: Snowflake, b: Snowflake) -> (Snowflake, Snowflake) {
+    if a < b { (a, b) } else { (b, a) }
+}
+
+fn blocked() -> Error {
+    Error::new(Status::Forbidden, "You cannot open a conversation with this user".to_string(), "One of you blocked the other".to_string())
+}
+
+fn no_group_dm_bots() -> Error {
+    Error::new(Status::Forbidden, "Bots cannot be in group DMs".to_string(), "Add the bot to a channel instead".to_string())
+}
+
+fn not_group_dm() -> Error {
+    Error::new(Status::BadRequest, "This channel is not a group DM".to_string(), "Use the members routes for text channels".to_string())
+}
+
+async fn find_dm(a: Snowflake, b: Snowflake, pool: &Pool<MySql>) -> Result<Option<Channel>, sqlx::error::Error> {
+    let (user_a, user_b) = pair(a, b);
+    let row = sqlx::query("SELECT `channel_id` FROM dm_pairs WHERE `user_a`=? AND `user_b`=?;")
+        .bind(user_a)
+        .bind(user_b)
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(row) => Ok(Some(Channel::from_id(row.try_get("channel_id")?, pool).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the DM between the two users, creating it the first time.
+pub async fn open_dm(from: Snowflake, to: Snowflake, pool: &Pool<MySql>) -> Result<Channel, sqlx::error::Error> {
+    if let Some(channel) = find_dm(from, to, pool).await? { return Ok(channel) }
+
+    let channel = Channel::create(ChannelKind::Dm, String::new(), from, None, &[to], pool).await?;
+    let (user_a, user_b) = pair(from, to);
+    let q = sqlx::query("INSERT IGNORE INTO dm_pairs (`user_a`, `user_b`, `channel_id`) VALUES (?, ?, ?);")
+        .bind(user_a)
+        .bind(user_b)
+        .bind(channel.id)
+        .execute(pool)
+        .await?;
+
+    if q.rows_affected() == 0 {
+        // the same DM was opened concurrently, keep the one that won
+        for query in ["DELETE FROM channels WHERE `id`=?;", "DELETE FROM channel_members WHERE `channel_id`=?;"] {
+            sqlx::query(query).bind(channel.id).execute(pool).await?;
+        }
+        return find_dm(from, to, pool).await?.ok_or(sqlx::Error::RowNotFound);
+    }
+
+    Ok(channel)
+}
+
+/// Whether both users are members of a channel that is not a DM.
+async fn shares_channel(a: Snowflake, b: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let row = sqlx::query("SELECT ma.`channel_id` FROM channel_members ma JOIN channel_members mb ON mb.`channel_id`=ma.`channel_id` JOIN channels c ON c.`id`=ma.`channel_id` \
+        WHERE ma.`user_id`=? AND mb.`user_id`=? AND c.`kind` NOT IN ('dm', 'group_dm') LIMIT 1;")
+        .bind(a)
+        .bind(b)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+/// Whether `user_id` is blocked by (or blocked) the other side of a DM.
+pub async fn is_blocked_dm(channel: &Channel, user_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    if channel.kind != ChannelKind::Dm { return Ok(false) }
+
+    let row = sqlx::query("SELECT `user_id` FROM channel_members WHERE `channel_id`=? AND `user_id`<>?;")
+        .bind(channel.id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    match row {
+        Some(row) => User::is_blocked_between(user_id, row.try_get("user_id")?, pool).await,
+        None => Ok(false),
+    }
+}
+
+#[post("/users/@me/dms", data = "<input>")]
+async fn create_dm(pool: &State<Pool<MySql>>, user: User, input: Json<DmInput>) -> Result<Json<Channel>, Error> {
+    check_scope(&user, Scope::Dms)?;
+    let recipient = User::from_id(input.recipient_id, pool).await?;
+    if recipient.id == user.id {
+        return Err(Error::new(Status::BadRequest, "You cannot open a DM with yourself".to_string(), "Check the recipient id".to_string()));
+    }
+    if User::is_blocked_between(user.id, recipient.id, pool).await? { return Err(blocked()) }
+    // bots only talk privately to people they already met
+    if (user.bot || recipient.bot) && !shares_channel(user.id, recipient.id, pool).await? {
+        return Err(Error::new(Status::Forbidden, "A bot can only have DMs with users sharing a channel with it".to_string(), "Add the bot to a channel first".to_string()));
+    }
+
+    Ok(Json(open_dm(user.id, recipient.id, pool).await?))
+}
+
+#[post("/users/@me/group-dms", data = "<input>")]
+async fn create_group_dm(pool: &State<Pool<MySql>>, config: &State<DmConfig>, user: User, input: Json<GroupDmInput>) -> Result<Json<Channel>, Error> {
+    check_session(&user)?;
+    let mut input = input.into_inner();
+    input.recipients.sort();
+    input.recipients.dedup();
+    input.recipients.retain(|id| *id != user.id);
+
+    if input.recipients.len() + 1 > config.group_dm_max_size {
+        return Err(Error::new(Status::BadRequest, format!("A group DM cannot have more than {} members", config.group_dm_max_size), "Create a channel instead".to_string()));
+    }
+    if input.name.chars().count() > 100 {
+        return Err(Error::new(Status::BadRequest, "A group DM name cannot be longer than 100 caracters".to_string(), "Check the body of your request".to_string()));
+    }
+    for id in &input.recipients {
+        let recipient = User::from_id(*id, pool).await?;
+        if recipient.bot { return Err(no_group_dm_bots()) }
+        if User::is_blocked_between(user.id, recipient.id, pool).await? { return Err(blocked()) }
+    }
+
+    Ok(Json(Channel::create(ChannelKind::GroupDm, input.name, user.id, None, &input.recipients, pool).await?))
+}
+
+#[get("/users/@me/dms")]
+async fn get_dms(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<Channel>>, Error> {
+    check_scope(&user, Scope::Dms)?;
+    let q = sqlx::query("SELECT c.`id` FROM channels c JOIN channel_members m ON m.`channel_id`=c.`id` WHERE m.`user_id`=? AND c.`kind` IN ('dm', 'group_dm') ORDER BY c.`id` DESC;")
+        .bind(user.id)
+        .fetch_all(pool.inner())
+        .await?;
+
+    let mut channels = Vec::with_capacity(q.len());
+    for row in &q {
+        channels.push(Channel::from_id(row.try_get("id")?, pool).await?);
+    }
+    Ok(Json(channels))
+}
+
+#[put("/channels/<id>/recipients/<user_id>")]
+async fn add_recipient(pool: &State<Pool<MySql>>, config: &State<DmConfig>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+    let channel = Channel::from_id(id, pool).await?;
+    if channel.kind != ChannelKind::GroupDm { return Err(not_group_dm()) }
+    if channel.owner != user.id {
+        return Err(Error::new(Status::Forbidden, "Only the owner can add people to a group DM".to_string(), "Ask the group owner".to_string()));
+    }
+    let target = User::from_id(user_id, pool).await?;
+    if target.bot { return Err(no_group_dm_bots()) }
+    if User::is_blocked_between(user.id, target.id, pool).await? { return Err(blocked()) }
+    if Member::list(channel.id, pool).await?.len() >= config.group_dm_max_size {
+        return Err(Error::new(Status::BadRequest, format!("A group DM cannot have more than {} members", config.group_dm_max_size), "Create a channel instead".to_string()));
+    }
+
+    if Member::add(channel.id, target.id, pool).await? {
+        bus.publish(channel.id, Event::MemberJoin { channel_id: channel.id, user_id: target.id });
+    }
+    Ok(Status::NoContent)
+}
+
+/// The owner removes someone, or anyone leaves by targeting themselves.
+#[delete("/channels/<id>/recipients/<user_id>")]
+async fn remove_recipient(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+    let channel = Channel::from_id(id, pool).await?;
+    if channel.kind != ChannelKind::GroupDm { return Err(not_group_dm()) }
+    if channel.owner != user.id && user_id != user.id {
+        return Err(Error::new(Status::Forbidden, "Only the owner can remove people from a group DM".to_string(), "Ask the group owner".to_string()));
+    }
+    if user_id == channel.owner {
+        return Err(Error::new(Status::Forbidden, "The owner cannot leave the group DM".to_string(), "Transfer the ownership first".to_string()));
+    }
+
+    if Member::remove(channel.id, user_id, pool).await? {
+        bus.publish(channel.id, Event::MemberLeave { channel_id: channel.id, user_id });
+    }
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create_dm, create_group_dm, get_dms, add_recipient, remove_recipient]
+}
@@ -0,0 +1,95 @@
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use sqlx::{MySql, Pool};
+
+use super::{cdn::CdnId, errors::Error};
+
+const MAX_EMBEDS: usize = 10;
+const MAX_FIELDS: usize = 25;
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_FIELD_VALUE_LENGTH: usize = 1024;
+const MAX_FOOTER_LENGTH: usize = 2048;
+const MAX_URL_LENGTH: usize = 2048;
+/// Text of all the embeds of a message together.
+const MAX_TOTAL_LENGTH: usize = 6000;
+
+/// Rich content block attached to a message.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Embed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Link of the title, http or https only.
+    pub url: Option<String>,
+    /// RGB color of the side bar, `0xRRGGBB`.
+    pub color: Option<u32>,
+    #[serde(default)]
+    pub fields: Vec<EmbedField>,
+    pub image: Option<CdnId>,
+    pub footer: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+fn bad_request(message: String) -> Error {
+    Error::new(Status::BadRequest, message, "Check the embeds of your message".to_string())
+}
+
+fn check_length(text: Option<&str>, max: usize, what: &str) -> Result<usize, Error> {
+    let length = text.map(|text| text.chars().count()).unwrap_or(0);
+    if length > max {
+        return Err(bad_request(format!("An embed {what} cannot be longer than {max} caracters")));
+    }
+
+    Ok(length)
+}
+
+pub async fn check(embeds: &[Embed], pool: &Pool<MySql>) -> Result<(), Error> {
+    if embeds.len() > MAX_EMBEDS {
+        return Err(bad_request(format!("A message cannot have more than {MAX_EMBEDS} embeds")));
+    }
+
+    let mut total = 0;
+    for embed in embeds {
+        total += check_length(embed.title.as_deref(), MAX_TITLE_LENGTH, "title")?;
+        total += check_length(embed.description.as_deref(), MAX_DESCRIPTION_LENGTH, "description")?;
+        total += check_length(embed.footer.as_deref(), MAX_FOOTER_LENGTH, "footer")?;
+
+        if let Some(url) = &embed.url {
+            if url.len() > MAX_URL_LENGTH || !(url.starts_with("https://") || url.starts_with("http://")) {
+                return Err(bad_request(format!("An embed url must be an http link of at most {MAX_URL_LENGTH} caracters")));
+            }
+        }
+        if embed.color.is_some_and(|color| color > 0xFF_FF_FF) {
+            return Err(bad_request("An embed color must be between 0x000000 and 0xFFFFFF".to_string()));
+        }
+        if embed.fields.len() > MAX_FIELDS {
+            return Err(bad_request(format!("An embed cannot have more than {MAX_FIELDS} fields")));
+        }
+        for field in &embed.fields {
+            if field.name.trim().is_empty() || field.value.trim().is_empty() {
+                return Err(bad_request("An embed field needs a name and a value".to_string()));
+            }
+            total += check_length(Some(&field.name), MAX_TITLE_LENGTH, "field name")?;
+            total += check_length(Some(&field.value), MAX_FIELD_VALUE_LENGTH, "field value")?;
+        }
+        if let Some(image) = &embed.image {
+            if !image.exists(pool).await? {
+                return Err(bad_request(format!("No CDN file found with hash {}", image.as_str())));
+            }
+        }
+    }
+
+    if total > MAX_TOTAL_LENGTH {
+        return Err(bad_request(format!("The embeds of a message cannot hold more than {MAX_TOTAL_LENGTH} caracters")));
+    }
+
+    Ok(())
+}
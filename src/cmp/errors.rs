@@ -1,20 +1,37 @@
 use std::io::{Read, Cursor};
 
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Header};
 use rocket::{http::Status, response, Response, Request};
 use rocket::response::Responder;
 use serde_json::json;
 
-#[derive(serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct Error {
     status: Status,
     message: String,
-    solution: String
+    solution: String,
+    /// Seconds to wait before retrying, also sent as a `Retry-After` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
 }
 
 impl Error {
     pub fn new(status: Status, message: String, solution: String) -> Self {
-        Self { status, message, solution }
+        Self { status, message, solution, retry_after: None }
+    }
+
+    /// A 429 asking the client to wait `retry_after` seconds.
+    pub fn too_many_requests(message: String, retry_after: u64) -> Self {
+        Self {
+            status: Status::TooManyRequests,
+            message,
+            solution: format!("Retry in {retry_after} seconds"),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
     }
 
     pub fn build<'r>(&self) -> Response<'r> {
@@ -36,10 +53,23 @@ impl Read for Error {
 impl<'r> Responder<'r, 'r> for Error {
     fn respond_to(self, _: &Request) -> response::Result<'r> {
         let body = serde_json::to_string(&self).unwrap_or("{}".to_string());
-        Response::build()
+        let mut response = Response::build();
+        response
             .streamed_body(Cursor::new(body))
             .header(ContentType::JSON)
-            .status(self.status)
-            .ok()
+            .status(self.status);
+        if let Some(retry_after) = self.retry_after {
+            response.header(Header::new("Retry-After", retry_after.to_string()));
+        }
+        response.ok()
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Self::new(Status::NotFound, "No ressource found at this address".to_string(), "Check that your identifier is the good one".to_string()),
+            _ => Self::new(Status::InternalServerError, "Unable to acquire intern connection".to_string(), "Retry later".to_string()),
+        }
     }
 }
\ No newline at end of file
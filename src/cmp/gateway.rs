@@ -0,0 +1,454 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::response::stream::{Event as SseEvent, EventStream};
+use rocket::tokio::{self, select, sync::broadcast::{self, error::RecvError}};
+use rocket::{Route, Shutdown, State};
+use rocket_ws as ws;
+use sqlx::{MySql, Pool};
+
+use super::{bookmarks::Bookmark, categories::{Category, Positions}, channels::Channel, ids::Snowflake, interactions::{EphemeralMessage, Interaction}, messages::Message, permissions::{self, Permissions}, polls::PollResults, presence::{self, Presence, PresenceInput, Presences}, reactions::Emoji, read_states::ReadState, threads::Thread, users::User};
+
+/// Number of dispatched events kept in memory for clients resuming after a reconnect.
+const HISTORY_SIZE: usize = 1024;
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clients send a heartbeat op at this interval.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// Connections without a heartbeat for this long are closed.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "t", content = "d", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Event {
+    MessageCreate(Message),
+    MessageUpdate(Message),
+    MessageDelete { channel_id: Snowflake, id: Snowflake },
+    ChannelUpdate(Channel),
+    /// Roles, member roles or overwrites of the channel changed, clients fetch their permissions again.
+    PermissionsUpdate { channel_id: Snowflake },
+    MemberJoin { channel_id: Snowflake, user_id: Snowflake },
+    MemberLeave { channel_id: Snowflake, user_id: Snowflake },
+    /// `timeout_until` is null once the timeout is lifted.
+    MemberTimeout { channel_id: Snowflake, user_id: Snowflake, timeout_until: Option<DateTime<Utc>> },
+    ReactionAdd { channel_id: Snowflake, message_id: Snowflake, user_id: Snowflake, emoji: Emoji },
+    ReactionRemove { channel_id: Snowflake, message_id: Snowflake, user_id: Snowflake, emoji: Emoji },
+    PollUpdate { channel_id: Snowflake, message_id: Snowflake, results: PollResults },
+    PollClose { channel_id: Snowflake, message_id: Snowflake, results: PollResults },
+    MessagePin { channel_id: Snowflake, message_id: Snowflake, pinned_by: Snowflake },
+    MessageUnpin { channel_id: Snowflake, message_id: Snowflake },
+    BookmarkSave(Bookmark),
+    BookmarkRemove { message_id: Snowflake },
+    CategoryUpdate(Category),
+    CategoryDelete { id: Snowflake },
+    PositionsUpdate(Positions),
+    PresenceUpdate(Presence),
+    TypingStart { channel_id: Snowflake, user_id: Snowflake, expires_at: DateTime<Utc> },
+    ReadStateUpdate(ReadState),
+    ThreadCreate(Thread),
+    ThreadUpdate(Thread),
+    /// Sent to the bot of the application, which answers on the callback route.
+    InteractionCreate(Interaction),
+    /// Sent to the user who invoked the command.
+    EphemeralMessage(EphemeralMessage),
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MessageCreate(_) => "MESSAGE_CREATE",
+            Self::MessageUpdate(_) => "MESSAGE_UPDATE",
+            Self::MessageDelete { .. } => "MESSAGE_DELETE",
+            Self::ChannelUpdate(_) => "CHANNEL_UPDATE",
+            Self::PermissionsUpdate { .. } => "PERMISSIONS_UPDATE",
+            Self::MemberJoin { .. } => "MEMBER_JOIN",
+            Self::MemberLeave { .. } => "MEMBER_LEAVE",
+            Self::MemberTimeout { .. } => "MEMBER_TIMEOUT",
+            Self::ReactionAdd { .. } => "REACTION_ADD",
+            Self::ReactionRemove { .. } => "REACTION_REMOVE",
+            Self::PollUpdate { .. } => "POLL_UPDATE",
+            Self::PollClose { .. } => "POLL_CLOSE",
+            Self::MessagePin { .. } => "MESSAGE_PIN",
+            Self::MessageUnpin { .. } => "MESSAGE_UNPIN",
+            Self::BookmarkSave(_) => "BOOKMARK_SAVE",
+            Self::BookmarkRemove { .. } => "BOOKMARK_REMOVE",
+            Self::CategoryUpdate(_) => "CATEGORY_UPDATE",
+            Self::CategoryDelete { .. } => "CATEGORY_DELETE",
+            Self::PositionsUpdate(_) => "POSITIONS_UPDATE",
+            Self::PresenceUpdate(_) => "PRESENCE_UPDATE",
+            Self::TypingStart { .. } => "TYPING_START",
+            Self::ReadStateUpdate(_) => "READ_STATE_UPDATE",
+            Self::ThreadCreate(_) => "THREAD_CREATE",
+            Self::ThreadUpdate(_) => "THREAD_UPDATE",
+            Self::InteractionCreate(_) => "INTERACTION_CREATE",
+            Self::EphemeralMessage(_) => "EPHEMERAL_MESSAGE",
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Dispatch {
+    pub seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<Snowflake>,
+    /// Set for events only sent to the sessions of one user.
+    #[serde(skip)]
+    pub user_id: Option<Snowflake>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Dispatch {
+    /// Whether a connection of `user_id` receives it, given what it is subscribed to.
+    fn is_for(&self, user_id: Snowflake, subscribed: impl Fn(&Snowflake) -> bool) -> bool {
+        match (self.user_id, &self.channel_id) {
+            (Some(target), _) => target == user_id,
+            (None, Some(channel_id)) => subscribed(channel_id),
+            (None, None) => false,
+        }
+    }
+
+    /// The channel `user_id` was removed from, kicked, banned or gone from a group DM.
+    fn removed(&self, user_id: Snowflake) -> Option<Snowflake> {
+        match self.event {
+            Event::MemberLeave { channel_id, user_id: member } if member == user_id => Some(channel_id),
+            _ => None,
+        }
+    }
+
+    /// The channel whose permissions may have changed, threads follow it.
+    fn permissions_changed(&self) -> Option<Snowflake> {
+        match &self.event {
+            Event::ChannelUpdate(channel) => Some(channel.parent_id.unwrap_or(channel.id)),
+            Event::PermissionsUpdate { channel_id } => Some(*channel_id),
+            _ => None,
+        }
+    }
+}
+
+/// Subscribed channels, mapped to the channel giving access to them, the parent for threads.
+#[derive(Default)]
+struct Subscriptions(HashMap<Snowflake, Snowflake>);
+
+impl Subscriptions {
+    fn contains(&self, channel_id: &Snowflake) -> bool {
+        self.0.contains_key(channel_id)
+    }
+
+    /// Forwards the dispatch, then stops listening to what the user can no longer read.
+    fn receives(&mut self, user_id: Snowflake, dispatch: &Dispatch) -> bool {
+        let receives = dispatch.is_for(user_id, |id| self.contains(id));
+        if let Some(channel_id) = dispatch.removed(user_id) {
+            self.0.retain(|_, root| *root != channel_id);
+        }
+        receives
+    }
+
+    /// Checks READ again after a permission change, and stops listening to the channel and its threads once it is gone.
+    async fn recheck(&mut self, pool: &Pool<MySql>, user: &User, dispatch: &Dispatch) {
+        let Some(root) = dispatch.permissions_changed() else { return };
+        if !self.0.values().any(|id| *id == root) { return }
+
+        if !can_read(pool, user, root).await {
+            self.0.retain(|_, id| *id != root);
+        }
+    }
+}
+
+struct History {
+    seq: u64,
+    events: VecDeque<Arc<Dispatch>>,
+}
+
+/// In-process event bus, handlers publish to it and every gateway connection listens.
+#[derive(Clone)]
+pub struct Bus {
+    sender: broadcast::Sender<Arc<Dispatch>>,
+    history: Arc<Mutex<History>>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+        Self {
+            sender,
+            history: Arc::new(Mutex::new(History { seq: 0, events: VecDeque::with_capacity(HISTORY_SIZE) })),
+        }
+    }
+
+    /// Sends an event to everyone subscribed to the channel.
+    pub fn publish(&self, channel_id: Snowflake, event: Event) {
+        self.dispatch(Some(channel_id), None, event);
+    }
+
+    /// Sends an event to every gateway session of a user.
+    pub fn publish_to_user(&self, user_id: Snowflake, event: Event) {
+        self.dispatch(None, Some(user_id), event);
+    }
+
+    fn dispatch(&self, channel_id: Option<Snowflake>, user_id: Option<Snowflake>, event: Event) {
+        // the lock keeps sequence numbers in the same order as the broadcast
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.seq += 1;
+        let dispatch = Arc::new(Dispatch { seq: history.seq, channel_id, user_id, event });
+
+        if history.events.len() == HISTORY_SIZE { history.events.pop_front(); }
+        history.events.push_back(dispatch.clone());
+        let _ = self.sender.send(dispatch);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Dispatch>> {
+        self.sender.subscribe()
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.history.lock().unwrap_or_else(|e| e.into_inner()).seq
+    }
+
+    /// Events published after `seq`, or `None` if some of them were already dropped.
+    pub fn since(&self, seq: u64) -> Option<Vec<Arc<Dispatch>>> {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        if seq > history.seq { return None }
+        match history.events.front() {
+            Some(first) if first.seq > seq + 1 => None,
+            _ => Some(history.events.iter().filter(|d| d.seq > seq).cloned().collect()),
+        }
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum ClientOp {
+    Identify {
+        token: String,
+        #[serde(default)]
+        channels: Vec<Snowflake>,
+        resume: Option<u64>,
+    },
+    Subscribe { channel_id: Snowflake },
+    Unsubscribe { channel_id: Snowflake },
+    Heartbeat,
+    PresenceUpdate(PresenceInput),
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum ServerOp<'a> {
+    Ready { user: &'a User, seq: u64, heartbeat_interval: u64 },
+    HeartbeatAck,
+    Dispatch(&'a Dispatch),
+    Subscribed { channel_id: Snowflake },
+    Unsubscribed { channel_id: Snowflake },
+    /// The client missed events that cannot be replayed and must fetch the state again.
+    InvalidSession,
+    Error { message: &'a str },
+}
+
+fn text(op: &ServerOp) -> ws::Message {
+    ws::Message::text(serde_json::to_string(op).unwrap_or("{}".to_string()))
+}
+
+async fn read_op(stream: &mut ws::stream::DuplexStream) -> Option<ClientOp> {
+    loop {
+        match stream.next().await? {
+            Ok(msg) if msg.is_text() => return serde_json::from_str(msg.to_text().ok()?).ok(),
+            Ok(msg) if msg.is_close() => return None,
+            Ok(_) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+async fn readable(pool: &Pool<MySql>, user: &User, channel_id: Snowflake) -> Option<Channel> {
+    let channel = Channel::from_id(channel_id, pool).await.ok()?;
+    let perms = permissions::compute(user.id, &channel, pool).await.ok()?;
+    (perms & user.allowed_permissions()).contains(Permissions::READ).then_some(channel)
+}
+
+async fn can_read(pool: &Pool<MySql>, user: &User, channel_id: Snowflake) -> bool {
+    readable(pool, user, channel_id).await.is_some()
+}
+
+/// Only channels the user can read can be subscribed to.
+async fn subscribe(pool: &Pool<MySql>, user: &User, subscriptions: &mut Subscriptions, channel_id: Snowflake) -> bool {
+    let Some(channel) = readable(pool, user, channel_id).await else { return false };
+    subscriptions.0.insert(channel_id, channel.parent_id.unwrap_or(channel_id));
+    true
+}
+
+#[get("/gateway")]
+fn gateway(ws: ws::WebSocket, pool: &State<Pool<MySql>>, bus: &State<Bus>, presences: &State<Presences>) -> ws::Channel<'static> {
+    let pool = pool.inner().clone();
+    let bus = bus.inner().clone();
+    let presences = presences.inner().clone();
+
+    ws.channel(move |mut stream| Box::pin(async move {
+        let (token, channels, resume) = match tokio::time::timeout(IDENTIFY_TIMEOUT, read_op(&mut stream)).await {
+            Ok(Some(ClientOp::Identify { token, channels, resume })) => (token, channels, resume),
+            _ => return stream.send(text(&ServerOp::Error { message: "The first message must be an identify op" })).await,
+        };
+        // bots identify with "Bot <token>"
+        let user = match token.strip_prefix("Bot ") {
+            Some(token) => User::from_bot_token(token, &pool).await,
+            None => User::from_token(&token, &pool).await,
+        };
+        let user = match user {
+            Ok(user) => user,
+            Err(_) => return stream.send(text(&ServerOp::Error { message: "Invalid authorization token" })).await,
+        };
+
+        let mut subscriptions = Subscriptions::default();
+        for channel_id in channels {
+            subscribe(&pool, &user, &mut subscriptions, channel_id).await;
+        }
+
+        // subscribe before replaying so nothing is lost between the two
+        let mut rx = bus.subscribe();
+        let (backlog, mut last_seq) = match resume {
+            Some(seq) => match bus.since(seq) {
+                Some(backlog) => {
+                    let last_seq = backlog.last().map(|d| d.seq).unwrap_or(seq);
+                    (backlog, last_seq)
+                },
+                None => return stream.send(text(&ServerOp::InvalidSession)).await,
+            },
+            None => (Vec::new(), bus.last_seq()),
+        };
+
+        // the user is online until this is dropped
+        let _connection = presences.connect(user.id, &pool, &bus);
+        let mut deadline = tokio::time::Instant::now() + HEARTBEAT_TIMEOUT;
+
+        stream.send(text(&ServerOp::Ready { user: &user, seq: last_seq, heartbeat_interval: HEARTBEAT_INTERVAL.as_millis() as u64 })).await?;
+        for dispatch in &backlog {
+            if subscriptions.receives(user.id, dispatch) {
+                stream.send(text(&ServerOp::Dispatch(dispatch))).await?;
+            }
+            subscriptions.recheck(&pool, &user, dispatch).await;
+        }
+
+        loop {
+            select! {
+                op = read_op(&mut stream) => match op {
+                    Some(ClientOp::Subscribe { channel_id }) => {
+                        if subscribe(&pool, &user, &mut subscriptions, channel_id).await {
+                            stream.send(text(&ServerOp::Subscribed { channel_id })).await?;
+                        } else {
+                            stream.send(text(&ServerOp::Error { message: "No readable channel found with this id" })).await?;
+                        }
+                    },
+                    Some(ClientOp::Unsubscribe { channel_id }) => {
+                        subscriptions.0.remove(&channel_id);
+                        stream.send(text(&ServerOp::Unsubscribed { channel_id })).await?;
+                    },
+                    Some(ClientOp::Heartbeat) => {
+                        deadline = tokio::time::Instant::now() + HEARTBEAT_TIMEOUT;
+                        stream.send(text(&ServerOp::HeartbeatAck)).await?;
+                    },
+                    Some(ClientOp::PresenceUpdate(input)) => {
+                        if presence::check_custom_status(&input).is_err() {
+                            stream.send(text(&ServerOp::Error { message: "The custom status is too long" })).await?;
+                        } else {
+                            presences.set(user.id, input, &pool, &bus);
+                        }
+                    },
+                    Some(ClientOp::Identify { .. }) => {
+                        stream.send(text(&ServerOp::Error { message: "Already identified" })).await?;
+                    },
+                    None => break,
+                },
+                dispatch = rx.recv() => match dispatch {
+                    Ok(dispatch) => {
+                        if dispatch.seq <= last_seq { continue }
+                        last_seq = dispatch.seq;
+                        if subscriptions.receives(user.id, &dispatch) {
+                            stream.send(text(&ServerOp::Dispatch(&dispatch))).await?;
+                        }
+                        subscriptions.recheck(&pool, &user, &dispatch).await;
+                    },
+                    Err(RecvError::Lagged(_)) => {
+                        stream.send(text(&ServerOp::InvalidSession)).await?;
+                        break;
+                    },
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tokio::time::sleep_until(deadline) => {
+                    stream.send(text(&ServerOp::Error { message: "No heartbeat received in time" })).await?;
+                    break;
+                },
+            }
+        }
+
+        Ok(())
+    }))
+}
+
+/// Server-sent events fallback for clients that cannot open a WebSocket.
+#[get("/gateway/events?<channels>&<resume>")]
+#[allow(clippy::too_many_arguments)]
+async fn gateway_events(pool: &State<Pool<MySql>>, user: User, bus: &State<Bus>, presences: &State<Presences>, channels: Vec<Snowflake>, resume: Option<u64>, mut shutdown: Shutdown) -> EventStream![] {
+    let mut subscriptions = Subscriptions::default();
+    for channel_id in channels {
+        subscribe(pool, &user, &mut subscriptions, channel_id).await;
+    }
+
+    let mut rx = bus.subscribe();
+    let backlog = match resume {
+        Some(seq) => bus.since(seq),
+        None => Some(Vec::new()),
+    };
+
+    // the stream is dropped when the client disconnects
+    let connection = presences.connect(user.id, pool, bus);
+    let pool = pool.inner().clone();
+
+    EventStream! {
+        let _connection = connection;
+        let backlog = match backlog {
+            Some(backlog) => backlog,
+            None => {
+                yield SseEvent::data("").event("INVALID_SESSION");
+                return;
+            }
+        };
+        let last_seq = backlog.last().map(|d| d.seq).unwrap_or(0);
+        for dispatch in &backlog {
+            if subscriptions.receives(user.id, dispatch) {
+                yield SseEvent::json(&dispatch.event).event(dispatch.event.name()).id(dispatch.seq.to_string());
+            }
+            subscriptions.recheck(&pool, &user, dispatch).await;
+        }
+
+        loop {
+            let dispatch = select! {
+                dispatch = rx.recv() => match dispatch {
+                    Ok(dispatch) => dispatch,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => {
+                        yield SseEvent::data("").event("INVALID_SESSION");
+                        break;
+                    },
+                },
+                _ = &mut shutdown => break,
+            };
+
+            if dispatch.seq <= last_seq { continue }
+            if subscriptions.receives(user.id, &dispatch) {
+                yield SseEvent::json(&dispatch.event).event(dispatch.event.name()).id(dispatch.seq.to_string());
+            }
+            subscriptions.recheck(&pool, &user, &dispatch).await;
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![gateway, gateway_events]
+}
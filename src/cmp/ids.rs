@@ -0,0 +1,146 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, TimeZone, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::request::FromParam;
+use sqlx::decode::Decode;
+use sqlx::encode::{Encode, IsNull};
+use sqlx::error::BoxDynError;
+use sqlx::mysql::{MySql, MySqlTypeInfo, MySqlValueRef};
+
+/// 2023-01-01T00:00:00Z, in milliseconds.
+const EPOCH: u64 = 1_672_531_200_000;
+const WORKER_BITS: u64 = 10;
+const SEQUENCE_BITS: u64 = 12;
+const WORKER_MASK: u64 = (1 << WORKER_BITS) - 1;
+const SEQUENCE_MASK: u64 = (1 << SEQUENCE_BITS) - 1;
+
+// (last timestamp, last sequence)
+static STATE: Mutex<(u64, u64)> = Mutex::new((0, 0));
+static WORKER: AtomicU64 = AtomicU64::new(0);
+
+/// 64 bits unique ID: 42 bits of milliseconds since `EPOCH`, 10 bits of worker, 12 bits of sequence.
+/// IDs sort by creation time. They are serialized as strings since JSON numbers lose precision above 2^53.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Snowflake(u64);
+
+impl Snowflake {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    pub fn created_at(self) -> DateTime<Utc> {
+        let millis = (self.0 >> (WORKER_BITS + SEQUENCE_BITS)) + EPOCH;
+        Utc.timestamp_millis_opt(millis as i64).single().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Snowflake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Snowflake {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+impl serde::Serialize for Snowflake {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Snowflake {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // accept both "123" and 123
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw { Str(String), Num(u64) }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Str(s) => s.parse().map_err(serde::de::Error::custom),
+            Raw::Num(n) => Ok(Self(n)),
+        }
+    }
+}
+
+// stored as BIGINT UNSIGNED
+impl sqlx::Type<MySql> for Snowflake {
+    fn type_info() -> MySqlTypeInfo {
+        <u64 as sqlx::Type<MySql>>::type_info()
+    }
+
+    fn compatible(ty: &MySqlTypeInfo) -> bool {
+        <u64 as sqlx::Type<MySql>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, MySql> for Snowflake {
+    fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+        <u64 as Encode<MySql>>::encode_by_ref(&self.0, buf)
+    }
+}
+
+impl<'r> Decode<'r, MySql> for Snowflake {
+    fn decode(value: MySqlValueRef<'r>) -> Result<Self, BoxDynError> {
+        <u64 as Decode<MySql>>::decode(value).map(Self)
+    }
+}
+
+impl<'a> FromParam<'a> for Snowflake {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        param.parse().map_err(|_| param)
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for Snowflake {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(field.value.parse().map_err(form::Error::custom)?)
+    }
+}
+
+/// Sets the worker part of generated IDs, each running instance must use a different one.
+pub fn set_worker(worker: u64) {
+    WORKER.store(worker & WORKER_MASK, Ordering::Relaxed);
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_millis() as u64).saturating_sub(EPOCH)
+}
+
+/// Generates a unique ID that sorts by creation time.
+pub fn generate() -> Snowflake {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut now = now_millis();
+
+    if now <= state.0 {
+        // same millisecond (or clock went backward): keep counting on the last timestamp
+        now = state.0;
+        state.1 = (state.1 + 1) & SEQUENCE_MASK;
+        if state.1 == 0 { now += 1 }
+    } else {
+        state.1 = 0;
+    }
+    state.0 = now;
+
+    Snowflake((now << (WORKER_BITS + SEQUENCE_BITS)) | (WORKER.load(Ordering::Relaxed) << SEQUENCE_BITS) | state.1)
+}
+
+/// Random alphanumeric string, for invite codes and secrets.
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
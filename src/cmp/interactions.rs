@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::tokio::{self, sync::oneshot};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use serde_json::{Map, Value};
+use sqlx::{MySql, Pool, Row};
+
+use super::{
+    applications::{check_not_bot, Application},
+    channels::Channel,
+    commands::{self, Command},
+    embeds::{self, Embed},
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    messages::{self, Message, MessageInput},
+    outgoing::Outgoing,
+    permissions::{self, check_permission, Permissions},
+    subscriptions::sign,
+    users::{hash_token, User},
+};
+
+/// The application must answer an interaction within this delay.
+const DEADLINE: StdDuration = StdDuration::from_secs(3);
+/// How long after the interaction follow-ups can be sent, 15 minutes.
+const FOLLOWUP_WINDOW: i64 = 15 * 60;
+const TOKEN_LENGTH: usize = 64;
+
+/// Interactions waiting for the answer of a bot connected to the gateway.
+pub struct Interactions {
+    outgoing: Outgoing,
+    pending: Mutex<HashMap<Snowflake, oneshot::Sender<InteractionResponse>>>,
+}
+
+impl Interactions {
+    /// `allowed_hosts` are the hosts interactions urls may point to even on a private address.
+    pub fn new(allowed_hosts: Vec<String>) -> Self {
+        let outgoing = Outgoing::new(DEADLINE, allowed_hosts).expect("Cannot build the interactions client");
+        Self { outgoing, pending: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn outgoing(&self) -> &Outgoing {
+        &self.outgoing
+    }
+
+    fn wait(&self, id: Snowflake) -> oneshot::Receiver<InteractionResponse> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).insert(id, sender);
+        receiver
+    }
+
+    /// False if nobody waits for the interaction anymore.
+    fn resolve(&self, id: Snowflake, response: InteractionResponse) -> bool {
+        let sender = self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        sender.is_some_and(|sender| sender.send(response).is_ok())
+    }
+
+    fn forget(&self, id: Snowflake) {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+    }
+}
+
+impl Default for Interactions {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+/// Sent to the application when a user invokes one of its commands.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Interaction {
+    pub id: Snowflake,
+    pub application_id: Snowflake,
+    pub channel_id: Snowflake,
+    /// Who invoked the command.
+    pub user: User,
+    pub command: InvokedCommand,
+    /// Authenticates the callback and the follow-ups.
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InvokedCommand {
+    pub id: Snowflake,
+    pub name: String,
+    pub options: Map<String, Value>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct InteractionInput {
+    pub application_id: Snowflake,
+    /// Name of the command.
+    pub command: String,
+    #[serde(default)]
+    pub options: Map<String, Value>,
+}
+
+/// Answer of the application, in the body of its HTTP response or sent to the callback route.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InteractionResponse {
+    Message(ResponseMessage),
+    /// The application answers later with follow-ups.
+    Deferred {
+        #[serde(default)]
+        ephemeral: bool,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ResponseMessage {
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    /// Only shown to the user who invoked the command, and not stored.
+    #[serde(default)]
+    pub ephemeral: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EphemeralMessage {
+    pub interaction_id: Snowflake,
+    pub application_id: Snowflake,
+    pub channel_id: Snowflake,
+    pub content: String,
+    pub embeds: Vec<Embed>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What the user who invoked the command gets back.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InteractionReply {
+    Message(Box<Message>),
+    Ephemeral(EphemeralMessage),
+    Deferred { interaction_id: Snowflake, ephemeral: bool },
+}
+
+/// Stored state of an interaction, found back from its token.
+struct Stored {
+    id: Snowflake,
+    application_id: Snowflake,
+    channel_id: Snowflake,
+    user_id: Snowflake,
+    responded: bool,
+    /// Answered with `Deferred`, the first follow-up takes the place of the answer.
+    deferred: bool,
+    ephemeral: bool,
+    created_at: DateTime<Utc>,
+}
+
+impl Stored {
+    async fn from_token(id: Snowflake, token: &str, pool: &Pool<MySql>) -> Result<Stored, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM interactions WHERE `id`=? AND `token`=?;")
+            .bind(id)
+            .bind(hash_token(token))
+            .fetch_one(pool)
+            .await?;
+        let response: Option<String> = q.try_get("response")?;
+
+        Ok(Stored {
+            id: q.try_get("id")?,
+            application_id: q.try_get("application_id")?,
+            channel_id: q.try_get("channel_id")?,
+            user_id: q.try_get("user_id")?,
+            responded: response.is_some(),
+            deferred: response.as_deref() == Some("deferred"),
+            ephemeral: q.try_get("ephemeral")?,
+            created_at: q.try_get("created_at")?,
+        })
+    }
+}
+
+async fn record(interaction: &Interaction, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    sqlx::query("INSERT INTO interactions (`id`, `application_id`, `command_id`, `channel_id`, `user_id`, `token`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+        .bind(interaction.id)
+        .bind(interaction.application_id)
+        .bind(interaction.command.id)
+        .bind(interaction.channel_id)
+        .bind(interaction.user.id)
+        .bind(hash_token(&interaction.token))
+        .bind(interaction.created_at)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// An interaction is answered once, false if it already was.
+async fn claim_response(id: Snowflake, response: &InteractionResponse, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let (kind, ephemeral) = match response {
+        InteractionResponse::Message(message) => ("message", message.ephemeral),
+        InteractionResponse::Deferred { ephemeral } => ("deferred", *ephemeral),
+    };
+    let q = sqlx::query("UPDATE interactions SET `response`=?, `ephemeral`=? WHERE `id`=? AND `response` IS NULL;")
+        .bind(kind)
+        .bind(ephemeral)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(q.rows_affected() > 0)
+}
+
+/// The first follow-up of a deferred interaction answers it, false for the next ones.
+async fn claim_deferred(id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let q = sqlx::query("UPDATE interactions SET `response`='message' WHERE `id`=? AND `response`='deferred';")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(q.rows_affected() > 0)
+}
+
+fn no_response() -> Error {
+    Error::new(Status::GatewayTimeout, "The application did not respond".to_string(), "Try again later or contact its owner".to_string())
+}
+
+fn unknown_interaction() -> Error {
+    Error::new(Status::NotFound, "Unknown interaction".to_string(), "Check the interaction id and token, its deadline may have passed".to_string())
+}
+
+/// POSTs the interaction to the application when it has an interactions URL, else sends it to its bot on the gateway.
+async fn deliver(application: &Application, interaction: &Interaction, state: &Interactions, bus: &Bus) -> Result<InteractionResponse, Error> {
+    let (Some(url), Some(secret)) = (&application.interactions_url, &application.interactions_secret) else {
+        let receiver = state.wait(interaction.id);
+        bus.publish_to_user(application.id, Event::InteractionCreate(interaction.clone()));
+        let response = tokio::time::timeout(DEADLINE, receiver).await;
+        state.forget(interaction.id);
+        return match response {
+            Ok(Ok(response)) => Ok(response),
+            _ => Err(no_response()),
+        };
+    };
+
+    // signed like event deliveries
+    let body = serde_json::to_string(interaction).unwrap_or_default();
+    let timestamp = Utc::now().timestamp();
+    let response = state.outgoing.post(url)
+        .map_err(|_| no_response())?
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Helix-Timestamp", timestamp.to_string())
+        .header("X-Helix-Signature", format!("sha256={}", sign(secret, timestamp, &body)))
+        .body(body)
+        .send()
+        .await
+        .map_err(|_| no_response())?;
+    if !response.status().is_success() { return Err(no_response()) }
+
+    let body = response.bytes().await.map_err(|_| no_response())?;
+    serde_json::from_slice(&body).map_err(|_| Error::new(Status::BadGateway, "The application sent an invalid response".to_string(), "Contact the owner of the application".to_string()))
+}
+
+/// Posts a message as the bot, or builds the ephemeral message only the invoking user sees.
+async fn reply(stored: &Stored, message: ResponseMessage, pool: &Pool<MySql>, bus: &Bus) -> Result<InteractionReply, Error> {
+    if message.ephemeral {
+        messages::check_content(&message.content, 0, !message.embeds.is_empty())?;
+        embeds::check(&message.embeds, pool).await?;
+        return Ok(InteractionReply::Ephemeral(EphemeralMessage {
+            interaction_id: stored.id,
+            application_id: stored.application_id,
+            channel_id: stored.channel_id,
+            content: message.content,
+            embeds: message.embeds,
+            created_at: Utc::now(),
+        }));
+    }
+
+    let channel = Channel::from_id(stored.channel_id, pool).await?;
+    let bot = User::from_id(stored.application_id, pool).await?;
+    let input = MessageInput {
+        content: message.content,
+        attachments: Vec::new(),
+        reply_to: None,
+        poll: None,
+        ttl: None,
+        embeds: message.embeds,
+        webhook: None,
+    };
+
+    Ok(InteractionReply::Message(Box::new(messages::send(&channel, &bot, input, pool, bus).await?)))
+}
+
+/// Invokes a command and waits for the answer of the application.
+#[post("/channels/<id>/interactions", data = "<input>")]
+async fn invoke(pool: &State<Pool<MySql>>, bus: &State<Bus>, state: &State<Interactions>, user: User, id: Snowflake, input: Json<InteractionInput>) -> Result<Json<InteractionReply>, Error> {
+    check_not_bot(&user)?;
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::SEND, pool).await?;
+    let mut input = input.into_inner();
+
+    let not_here = || Error::new(Status::NotFound, "This application is not in the channel".to_string(), "Ask a channel manager to add its bot".to_string());
+    let application = match Application::by_id(input.application_id, pool).await {
+        Ok(application) => application,
+        Err(sqlx::Error::RowNotFound) => return Err(not_here()),
+        Err(e) => return Err(e.into()),
+    };
+    let bot = User::from_id(application.id, pool).await?;
+    if !(permissions::compute(bot.id, &channel, pool).await? & bot.allowed_permissions()).contains(Permissions::READ) {
+        return Err(not_here());
+    }
+    let command = match Command::from_name(application.id, &input.command, pool).await {
+        Ok(command) => command,
+        Err(sqlx::Error::RowNotFound) => return Err(Error::new(Status::NotFound, format!("Unknown command {}", input.command), "List the commands of the channel".to_string())),
+        Err(e) => return Err(e.into()),
+    };
+    commands::check_options(&command, &mut input.options)?;
+
+    let interaction_id = ids::generate();
+    let interaction = Interaction {
+        id: interaction_id,
+        application_id: application.id,
+        channel_id: channel.id,
+        user: user.clone(),
+        command: InvokedCommand { id: command.id, name: command.name, options: input.options },
+        token: ids::random_string(TOKEN_LENGTH),
+        created_at: interaction_id.created_at(),
+    };
+    record(&interaction, pool).await?;
+
+    let response = deliver(&application, &interaction, state, bus).await?;
+    if !claim_response(interaction.id, &response, pool).await? { return Err(unknown_interaction()) }
+
+    let stored = Stored {
+        id: interaction.id,
+        application_id: application.id,
+        channel_id: channel.id,
+        user_id: user.id,
+        responded: true,
+        deferred: matches!(response, InteractionResponse::Deferred { .. }),
+        ephemeral: matches!(response, InteractionResponse::Deferred { ephemeral: true }),
+        created_at: interaction.created_at,
+    };
+    match response {
+        InteractionResponse::Message(message) => Ok(Json(reply(&stored, message, pool, bus).await?)),
+        InteractionResponse::Deferred { ephemeral } => Ok(Json(InteractionReply::Deferred { interaction_id: interaction.id, ephemeral })),
+    }
+}
+
+/// Answer of a bot receiving interactions on the gateway, before the deadline.
+#[post("/interactions/<id>/<token>/callback", data = "<input>")]
+async fn callback(pool: &State<Pool<MySql>>, state: &State<Interactions>, id: Snowflake, token: &str, input: Json<InteractionResponse>) -> Result<Status, Error> {
+    let stored = match Stored::from_token(id, token, pool).await {
+        Ok(stored) => stored,
+        Err(sqlx::Error::RowNotFound) => return Err(unknown_interaction()),
+        Err(e) => return Err(e.into()),
+    };
+    if stored.responded || !state.resolve(stored.id, input.into_inner()) { return Err(unknown_interaction()) }
+
+    Ok(Status::NoContent)
+}
+
+/// More messages after the first answer, ephemeral ones go to the gateway of the invoking user.
+#[post("/interactions/<id>/<token>/followups", data = "<input>")]
+async fn followup(pool: &State<Pool<MySql>>, bus: &State<Bus>, id: Snowflake, token: &str, input: Json<ResponseMessage>) -> Result<Json<InteractionReply>, Error> {
+    let stored = match Stored::from_token(id, token, pool).await {
+        Ok(stored) => stored,
+        Err(sqlx::Error::RowNotFound) => return Err(unknown_interaction()),
+        Err(e) => return Err(e.into()),
+    };
+    if !stored.responded {
+        return Err(Error::new(Status::BadRequest, "The interaction was not answered yet".to_string(), "Answer it before sending follow-ups".to_string()));
+    }
+    if Utc::now() - stored.created_at > Duration::seconds(FOLLOWUP_WINDOW) {
+        return Err(Error::new(Status::Gone, "The interaction expired".to_string(), "Follow-ups can be sent for 15 minutes".to_string()));
+    }
+
+    let mut message = input.into_inner();
+    if stored.deferred && claim_deferred(stored.id, pool).await? {
+        message.ephemeral = stored.ephemeral;
+    }
+    let reply = reply(&stored, message, pool, bus).await?;
+    if let InteractionReply::Ephemeral(message) = &reply {
+        bus.publish_to_user(stored.user_id, Event::EphemeralMessage(message.clone()));
+    }
+    Ok(Json(reply))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![invoke, callback, followup]
+}
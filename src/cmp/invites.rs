@@ -0,0 +1,230 @@
+use chrono::{DateTime, Duration, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::check_session,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    members::{check_grant, check_text_channel, Member, Role},
+    moderation::check_not_banned,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const CODE_LENGTH: usize = 8;
+/// 30 days.
+const MAX_AGE: u64 = 30 * 24 * 3600;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub channel_id: Snowflake,
+    pub creator_id: Snowflake,
+    pub role_id: Option<Snowflake>,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct InviteInput {
+    pub max_uses: Option<u32>,
+    /// Lifetime in seconds, the invite never expires if omitted.
+    pub max_age: Option<u64>,
+    pub role_id: Option<Snowflake>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct InvitePreview {
+    pub code: String,
+    pub channel_id: Snowflake,
+    pub channel_name: String,
+    pub icon_url: Option<String>,
+    pub members: u64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Invite, sqlx::error::Error> {
+    Ok(Invite {
+        code: row.try_get("code")?,
+        channel_id: row.try_get("channel_id")?,
+        creator_id: row.try_get("creator_id")?,
+        role_id: row.try_get("role_id")?,
+        max_uses: row.try_get("max_uses")?,
+        uses: row.try_get("uses")?,
+        expires_at: row.try_get("expires_at")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl Invite {
+    pub async fn from_code(code: &str, pool: &Pool<MySql>) -> Result<Invite, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM invites WHERE `code`=?;")
+            .bind(code)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Invite>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM invites WHERE `channel_id`=? ORDER BY `created_at` DESC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    pub async fn create(channel_id: Snowflake, creator_id: Snowflake, input: InviteInput, pool: &Pool<MySql>) -> Result<Invite, sqlx::error::Error> {
+        let now = Utc::now();
+        let invite = Invite {
+            code: ids::random_string(CODE_LENGTH),
+            channel_id,
+            creator_id,
+            role_id: input.role_id,
+            max_uses: input.max_uses,
+            uses: 0,
+            expires_at: input.max_age.map(|age| now + Duration::seconds(age.min(MAX_AGE) as i64)),
+            created_at: now,
+        };
+
+        sqlx::query("INSERT INTO invites (`code`, `channel_id`, `creator_id`, `role_id`, `max_uses`, `expires_at`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+            .bind(&invite.code)
+            .bind(invite.channel_id)
+            .bind(invite.creator_id)
+            .bind(invite.role_id)
+            .bind(invite.max_uses)
+            .bind(invite.expires_at)
+            .bind(invite.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(invite)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.expires_at.map(|at| at > Utc::now()).unwrap_or(true)
+            && self.max_uses.map(|max| self.uses < max).unwrap_or(true)
+    }
+
+    /// Counts one use, fails if the invite ran out of uses or expired in the meantime.
+    pub async fn consume(&self, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("UPDATE invites SET `uses`=`uses`+1 WHERE `code`=? AND (`max_uses` IS NULL OR `uses`<`max_uses`) AND (`expires_at` IS NULL OR `expires_at`>?);")
+            .bind(&self.code)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("DELETE FROM invites WHERE `code`=?;")
+            .bind(&self.code)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn invalid_invite() -> Error {
+    Error::new(Status::Gone, "This invite expired or reached its maximum uses".to_string(), "Ask for a new invite".to_string())
+}
+
+#[post("/channels/<id>/invites", data = "<input>")]
+async fn create_invite(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<InviteInput>) -> Result<Json<Invite>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    let own = check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let input = input.into_inner();
+
+    if let Some(role_id) = input.role_id {
+        let role = Role::from_id(channel.id, role_id, pool).await?;
+        check_grant(role.permissions, own)?;
+    }
+    if input.max_uses == Some(0) {
+        return Err(Error::new(Status::BadRequest, "An invite needs at least one use".to_string(), "Omit max_uses for unlimited uses".to_string()));
+    }
+
+    Ok(Json(Invite::create(channel.id, user.id, input, pool).await?))
+}
+
+#[get("/channels/<id>/invites")]
+async fn get_channel_invites(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Invite>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Ok(Json(Invite::list(channel.id, pool).await?))
+}
+
+#[get("/invites/<code>")]
+async fn get_invite(pool: &State<Pool<MySql>>, code: String) -> Result<Json<InvitePreview>, Error> {
+    let invite = Invite::from_code(&code, pool).await?;
+    if !invite.is_valid() { return Err(invalid_invite()) }
+    let channel = Channel::from_id(invite.channel_id, pool).await?;
+
+    let icon_url = match &channel.icon {
+        Some(icon) => Some(icon.url(pool).await?),
+        None => None,
+    };
+    let members = sqlx::query("SELECT COUNT(*) AS `count` FROM channel_members WHERE `channel_id`=?;")
+        .bind(channel.id)
+        .fetch_one(pool.inner())
+        .await?
+        .try_get::<i64, _>("count")?;
+
+    Ok(Json(InvitePreview {
+        code: invite.code,
+        channel_id: channel.id,
+        channel_name: channel.name,
+        icon_url,
+        members: members as u64,
+        expires_at: invite.expires_at,
+    }))
+}
+
+#[post("/invites/<code>/accept")]
+async fn accept_invite(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, code: String) -> Result<Json<Channel>, Error> {
+    // bots are added by a member managing the channel
+    check_session(&user)?;
+    let invite = Invite::from_code(&code, pool).await?;
+    let channel = Channel::from_id(invite.channel_id, pool).await?;
+
+    // joining twice does not use the invite up
+    if Member::is_member(channel.id, user.id, pool).await? { return Ok(Json(channel)) }
+    check_not_banned(channel.id, user.id, pool).await?;
+    if !invite.consume(pool).await? { return Err(invalid_invite()) }
+
+    Member::add(channel.id, user.id, pool).await?;
+    if let Some(role_id) = invite.role_id {
+        // the role may have been deleted since
+        if let Ok(role) = Role::from_id(channel.id, role_id, pool).await {
+            Member::add_role(channel.id, user.id, role.id, pool).await?;
+        }
+    }
+
+    bus.publish(channel.id, Event::MemberJoin { channel_id: channel.id, user_id: user.id });
+    Ok(Json(channel))
+}
+
+#[delete("/invites/<code>")]
+async fn revoke_invite(pool: &State<Pool<MySql>>, user: User, code: String) -> Result<Status, Error> {
+    let invite = Invite::from_code(&code, pool).await?;
+    if invite.creator_id != user.id {
+        let channel = Channel::from_id(invite.channel_id, pool).await?;
+        check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    }
+
+    invite.delete(pool).await?;
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create_invite, get_channel_invites, get_invite, accept_invite, revoke_invite]
+}
@@ -0,0 +1,442 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{MySql, Pool, Row};
+
+use super::{
+    applications::check_not_token,
+    audit::{AuditAction, AuditEntry, Reason},
+    channels::{Channel, ChannelKind},
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    moderation::check_not_banned,
+    permissions::{self, check_permission, Overwrite, OverwriteTarget, Permissions},
+    users::User,
+};
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Member {
+    pub user_id: Snowflake,
+    pub roles: Vec<Snowflake>,
+    pub joined_at: DateTime<Utc>,
+    /// Set while the member is timed out.
+    pub timeout_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Role {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub name: String,
+    pub permissions: Permissions,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RoleInput {
+    pub name: String,
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RolePatch {
+    pub name: Option<String>,
+    pub permissions: Option<Permissions>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OverwriteInput {
+    #[serde(default)]
+    pub allow: Permissions,
+    #[serde(default)]
+    pub deny: Permissions,
+}
+
+impl Member {
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Member>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT m.`user_id`, m.`joined_at`, m.`timeout_until`, GROUP_CONCAT(mr.`role_id`) AS `roles` FROM channel_members m \
+            LEFT JOIN member_roles mr ON mr.`channel_id`=m.`channel_id` AND mr.`user_id`=m.`user_id` \
+            WHERE m.`channel_id`=? GROUP BY m.`user_id`, m.`joined_at`, m.`timeout_until` ORDER BY m.`joined_at` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter()
+            .map(|row| Ok(Member {
+                user_id: row.try_get("user_id")?,
+                roles: row.try_get::<Option<String>, _>("roles")?
+                    .map(|list| list.split(',').filter_map(|id| id.parse().ok()).collect())
+                    .unwrap_or_default(),
+                joined_at: row.try_get("joined_at")?,
+                timeout_until: row.try_get::<Option<DateTime<Utc>>, _>("timeout_until")?.filter(|until| *until > Utc::now()),
+            }))
+            .collect()
+    }
+
+    pub async fn is_member(channel_id: Snowflake, user_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let row = sqlx::query("SELECT `user_id` FROM channel_members WHERE `channel_id`=? AND `user_id`=?;")
+            .bind(channel_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Returns `false` if the user was already a member.
+    pub async fn add(channel_id: Snowflake, user_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("INSERT IGNORE INTO channel_members (`channel_id`, `user_id`, `joined_at`) VALUES (?, ?, ?);")
+            .bind(channel_id)
+            .bind(user_id)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+
+    pub async fn add_role(channel_id: Snowflake, user_id: Snowflake, role_id: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("INSERT IGNORE INTO member_roles (`channel_id`, `user_id`, `role_id`) VALUES (?, ?, ?);")
+            .bind(channel_id)
+            .bind(user_id)
+            .bind(role_id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes the membership along with the member roles and overwrite.
+    pub async fn remove(channel_id: Snowflake, user_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        let q = sqlx::query("DELETE FROM channel_members WHERE `channel_id`=? AND `user_id`=?;")
+            .bind(channel_id)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM member_roles WHERE `channel_id`=? AND `user_id`=?;")
+            .bind(channel_id)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM channel_overwrites WHERE `channel_id`=? AND `target_type`='member' AND `target_id`=?;")
+            .bind(channel_id)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+}
+
+impl Role {
+    pub async fn from_id(channel_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Role, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM channel_roles WHERE `channel_id`=? AND `id`=?;")
+            .bind(channel_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(Self {
+            id: q.try_get("id")?,
+            channel_id: q.try_get("channel_id")?,
+            name: q.try_get("name")?,
+            permissions: Permissions::from_bits(q.try_get("permissions")?),
+        })
+    }
+
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Role>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM channel_roles WHERE `channel_id`=? ORDER BY `id` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter()
+            .map(|row| Ok(Self {
+                id: row.try_get("id")?,
+                channel_id: row.try_get("channel_id")?,
+                name: row.try_get("name")?,
+                permissions: Permissions::from_bits(row.try_get("permissions")?),
+            }))
+            .collect()
+    }
+
+    pub async fn create(channel_id: Snowflake, name: String, permissions: Permissions, pool: &Pool<MySql>) -> Result<Role, sqlx::error::Error> {
+        let role = Self { id: ids::generate(), channel_id, name, permissions };
+
+        sqlx::query("INSERT INTO channel_roles (`id`, `channel_id`, `name`, `permissions`) VALUES (?, ?, ?, ?);")
+            .bind(role.id)
+            .bind(role.channel_id)
+            .bind(&role.name)
+            .bind(role.permissions.bits())
+            .execute(pool)
+            .await?;
+
+        Ok(role)
+    }
+
+    pub async fn save(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE channel_roles SET `name`=?, `permissions`=? WHERE `id`=?;")
+            .bind(&self.name)
+            .bind(self.permissions.bits())
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        for query in [
+            "DELETE FROM channel_roles WHERE `id`=?;",
+            "DELETE FROM member_roles WHERE `role_id`=?;",
+        ] {
+            sqlx::query(query).bind(self.id).execute(&mut tx).await?;
+        }
+        sqlx::query("DELETE FROM channel_overwrites WHERE `channel_id`=? AND `target_type`='role' AND `target_id`=?;")
+            .bind(self.channel_id)
+            .bind(self.id.to_string())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() || name.chars().count() > 64 {
+        return Err(Error::new(Status::BadRequest, "A role name must be between 1 and 64 caracters".to_string(), "Check the body of your request".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Nobody can hand out a permission they do not have themselves.
+pub fn check_grant(granted: Permissions, own: Permissions) -> Result<(), Error> {
+    if own.contains(granted) { return Ok(()) }
+
+    Err(Error::new(
+        Status::Forbidden,
+        format!("You cannot grant {}", (granted & !own).names().join(", ")),
+        "Only grant permissions you have".to_string(),
+    ))
+}
+
+/// Membership of private channels goes through the DM routes.
+pub fn check_text_channel(channel: &Channel) -> Result<(), Error> {
+    if channel.kind == ChannelKind::Text { return Ok(()) }
+
+    Err(Error::new(Status::BadRequest, "The members of this channel are not managed here".to_string(), "Use the recipients routes for group DMs and the thread members routes for threads".to_string()))
+}
+
+fn not_member() -> Error {
+    Error::new(Status::NotFound, "This user is not a member of the channel".to_string(), "Add them to the channel first".to_string())
+}
+
+#[get("/channels/<id>/members")]
+async fn get_members(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Member>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Member::list(channel.id, pool).await?))
+}
+
+#[put("/channels/<id>/members/<user_id>")]
+async fn add_member(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let target = User::from_id(user_id, pool).await?;
+    check_not_banned(channel.id, target.id, pool).await?;
+
+    if Member::add(channel.id, target.id, pool).await? {
+        bus.publish(channel.id, Event::MemberJoin { channel_id: channel.id, user_id: target.id });
+    }
+    Ok(Status::NoContent)
+}
+
+/// Kicks a member, or leaves the channel when targeting yourself.
+#[delete("/channels/<id>/members/<user_id>", rank = 2)]
+async fn remove_member(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake, reason: Reason) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    if user_id == channel.owner {
+        return Err(Error::new(Status::Forbidden, "The owner cannot leave the channel".to_string(), "Transfer the ownership first".to_string()));
+    }
+    if user_id != user.id {
+        check_permission(&user, &channel, Permissions::KICK, pool).await?;
+    }
+
+    if !Member::remove(channel.id, user_id, pool).await? { return Err(not_member()) }
+    bus.publish(channel.id, Event::MemberLeave { channel_id: channel.id, user_id });
+    if user_id != user.id {
+        AuditEntry::record(channel.id, user.id, AuditAction::MemberKick, Some(user_id), reason.0, None, pool).await?;
+    }
+    Ok(Status::NoContent)
+}
+
+#[delete("/channels/<id>/members/@me")]
+async fn leave_channel(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake) -> Result<Status, Error> {
+    check_not_token(&user)?;
+    let user_id = user.id;
+    remove_member(pool, bus, user, id, user_id, Reason(None)).await
+}
+
+#[get("/channels/<id>/permissions/@me")]
+async fn get_own_permissions(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Permissions>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+
+    Ok(Json(permissions::compute(user.id, &channel, pool).await? & user.allowed_permissions()))
+}
+
+#[get("/channels/<id>/roles")]
+async fn get_roles(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Role>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Role::list(channel.id, pool).await?))
+}
+
+#[post("/channels/<id>/roles", data = "<input>")]
+async fn create_role(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<RoleInput>) -> Result<Json<Role>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let own = check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let input = input.into_inner();
+    check_name(&input.name)?;
+    check_grant(input.permissions, own)?;
+
+    Ok(Json(Role::create(channel.id, input.name, Permissions::from_bits(input.permissions.bits()), pool).await?))
+}
+
+#[patch("/channels/<id>/roles/<role_id>", data = "<input>")]
+async fn edit_role(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, role_id: Snowflake, input: Json<RolePatch>) -> Result<Json<Role>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let own = check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let mut role = Role::from_id(channel.id, role_id, pool).await?;
+    let input = input.into_inner();
+
+    if let Some(name) = input.name {
+        check_name(&name)?;
+        role.name = name;
+    }
+    if let Some(perms) = input.permissions {
+        check_grant(perms, own)?;
+        role.permissions = Permissions::from_bits(perms.bits());
+    }
+    role.save(pool).await?;
+
+    bus.publish(channel.id, Event::PermissionsUpdate { channel_id: channel.id });
+    Ok(Json(role))
+}
+
+#[delete("/channels/<id>/roles/<role_id>")]
+async fn delete_role(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, role_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Role::from_id(channel.id, role_id, pool).await?.delete(pool).await?;
+    bus.publish(channel.id, Event::PermissionsUpdate { channel_id: channel.id });
+    Ok(Status::NoContent)
+}
+
+#[put("/channels/<id>/members/<user_id>/roles/<role_id>")]
+async fn add_member_role(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake, role_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let own = check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let role = Role::from_id(channel.id, role_id, pool).await?;
+    check_grant(role.permissions, own)?;
+    if !Member::is_member(channel.id, user_id, pool).await? { return Err(not_member()) }
+
+    Member::add_role(channel.id, user_id, role.id, pool).await?;
+    bus.publish(channel.id, Event::PermissionsUpdate { channel_id: channel.id });
+    Ok(Status::NoContent)
+}
+
+#[delete("/channels/<id>/members/<user_id>/roles/<role_id>")]
+async fn remove_member_role(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake, role_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    sqlx::query("DELETE FROM member_roles WHERE `channel_id`=? AND `user_id`=? AND `role_id`=?;")
+        .bind(channel.id)
+        .bind(user_id)
+        .bind(role_id)
+        .execute(pool.inner())
+        .await?;
+
+    bus.publish(channel.id, Event::PermissionsUpdate { channel_id: channel.id });
+    Ok(Status::NoContent)
+}
+
+#[get("/channels/<id>/overwrites")]
+async fn get_overwrites(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Overwrite>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Ok(Json(permissions::overwrites(channel.id, pool).await?))
+}
+
+fn parse_target(target_type: &str) -> Result<OverwriteTarget, Error> {
+    OverwriteTarget::parse(target_type).ok_or_else(|| Error::new(
+        Status::NotFound,
+        "Unknown overwrite target".to_string(),
+        "Use \"role\" or \"member\"".to_string(),
+    ))
+}
+
+#[put("/channels/<id>/overwrites/<target_type>/<target_id>", data = "<input>")]
+async fn set_overwrite(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, target_type: String, target_id: Snowflake, input: Json<OverwriteInput>) -> Result<Json<Overwrite>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let own = check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let target_type = parse_target(&target_type)?;
+    check_grant(input.allow, own)?;
+
+    let overwrite = Overwrite {
+        target_type,
+        target_id,
+        allow: Permissions::from_bits(input.allow.bits()),
+        deny: Permissions::from_bits(input.deny.bits()),
+    };
+    sqlx::query("REPLACE INTO channel_overwrites (`channel_id`, `target_type`, `target_id`, `allow`, `deny`) VALUES (?, ?, ?, ?, ?);")
+        .bind(channel.id)
+        .bind(overwrite.target_type.as_str())
+        .bind(overwrite.target_id)
+        .bind(overwrite.allow.bits())
+        .bind(overwrite.deny.bits())
+        .execute(pool.inner())
+        .await?;
+    bus.publish(channel.id, Event::PermissionsUpdate { channel_id: channel.id });
+
+    Ok(Json(overwrite))
+}
+
+#[delete("/channels/<id>/overwrites/<target_type>/<target_id>")]
+async fn delete_overwrite(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, target_type: String, target_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let target_type = parse_target(&target_type)?;
+
+    sqlx::query("DELETE FROM channel_overwrites WHERE `channel_id`=? AND `target_type`=? AND `target_id`=?;")
+        .bind(channel.id)
+        .bind(target_type.as_str())
+        .bind(target_id)
+        .execute(pool.inner())
+        .await?;
+    bus.publish(channel.id, Event::PermissionsUpdate { channel_id: channel.id });
+
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_members, add_member, remove_member, leave_channel, get_own_permissions,
+        get_roles, create_role, edit_role, delete_role,
+        add_member_role, remove_member_role,
+        get_overwrites, set_overwrite, delete_overwrite,
+    ]
+}
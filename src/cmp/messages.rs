@@ -0,0 +1,514 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{audit::{AuditAction, AuditEntry, Reason}, automod, cdn::CdnId, channels::{Channel, ChannelKind}, dms, embeds::{self, Embed}, errors::Error, gateway::{Bus, Event}, ids::{self, Snowflake}, permissions::{check_permission, Permissions}, polls::{self, Poll, PollInput}, reactions::{self, Reaction}, scheduler::{self, Job}, threads, users::User, webhooks::WebhookAuthor};
+
+const MAX_CONTENT_LENGTH: usize = 4000;
+const MAX_ATTACHMENTS: usize = 10;
+const MAX_MENTIONS: usize = 50;
+/// Longest lifetime of a self-destructing message, 7 days.
+const MAX_TTL: u64 = 7 * 24 * 3600;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+// attachments are folded into a single column so a page is fetched in one query,
+// the replied message and the spawned thread are joined the same way
+pub const SELECT_MESSAGE: &str = "SELECT m.`id`, m.`channel_id`, m.`author_id`, m.`kind`, m.`content`, m.`created_at`, m.`edited_at`, m.`expires_at`, m.`reply_to`, \
+    m.`embeds`, m.`webhook_id`, m.`webhook_name`, m.`webhook_avatar`, \
+    r.`author_id` AS `reply_author_id`, r.`content` AS `reply_content`, r.`deleted_at` AS `reply_deleted_at`, t.`channel_id` AS `thread_id`, \
+    GROUP_CONCAT(a.`hash` ORDER BY a.`position`) AS `attachments` \
+    FROM messages m LEFT JOIN message_attachments a ON a.`message_id`=m.`id` \
+    LEFT JOIN messages r ON r.`id`=m.`reply_to` LEFT JOIN threads t ON t.`message_id`=m.`id`";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Default,
+    Poll,
+}
+
+impl MessageKind {
+    pub fn parse(source: &str) -> Self {
+        match source {
+            "poll" => Self::Poll,
+            _ => Self::Default,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Poll => "poll",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Message {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub author_id: Snowflake,
+    pub kind: MessageKind,
+    pub content: String,
+    pub attachments: Vec<CdnId>,
+    /// Users mentioned with `<@id>` in the content.
+    pub mentions: Vec<Snowflake>,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Self-destructing messages are deleted at this time.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub reply_to: Option<MessageReference>,
+    /// Thread spawned from this message.
+    pub thread_id: Option<Snowflake>,
+    pub reactions: Vec<Reaction>,
+    pub poll: Option<Poll>,
+    pub embeds: Vec<Embed>,
+    /// Set when a webhook posted the message, `author_id` is then the id of the webhook.
+    pub webhook: Option<WebhookAuthor>,
+}
+
+/// The message replied to. Once it is deleted only its id is left.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MessageReference {
+    pub id: Snowflake,
+    pub author_id: Option<Snowflake>,
+    pub content: Option<String>,
+    pub deleted: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MessageEdit {
+    pub content: String,
+    pub edited_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MessageInput {
+    pub content: String,
+    #[serde(default)]
+    pub attachments: Vec<CdnId>,
+    pub reply_to: Option<Snowflake>,
+    /// Makes the message a poll.
+    pub poll: Option<PollInput>,
+    /// Lifetime in seconds, the message deletes itself afterward.
+    pub ttl: Option<u64>,
+    /// Only webhooks and bots can send embeds.
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    #[serde(skip)]
+    pub webhook: Option<WebhookAuthor>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MessagePatch {
+    pub content: String,
+}
+
+/// User IDs mentioned as `<@id>`, without duplicates.
+pub fn parse_mentions(content: &str) -> Vec<Snowflake> {
+    let mut mentions = Vec::new();
+    for part in content.split("<@").skip(1) {
+        let Some((id, _)) = part.split_once('>') else { continue };
+        if let Ok(id) = id.parse::<Snowflake>() {
+            if !mentions.contains(&id) { mentions.push(id) }
+        }
+        if mentions.len() == MAX_MENTIONS { break }
+    }
+
+    mentions
+}
+
+pub fn from_row(row: &MySqlRow) -> Result<Message, sqlx::error::Error> {
+    let attachments = row.try_get::<Option<String>, _>("attachments")?
+        .map(|list| list.split(',').map(|h| CdnId::new(h.to_string())).collect())
+        .unwrap_or_default();
+
+    let reply_to = match row.try_get::<Option<Snowflake>, _>("reply_to")? {
+        Some(id) => {
+            let deleted = row.try_get::<Option<DateTime<Utc>>, _>("reply_deleted_at")?.is_some();
+            let (author_id, content) = if deleted { (None, None) } else { (row.try_get("reply_author_id")?, row.try_get("reply_content")?) };
+            Some(MessageReference { id, author_id, content, deleted })
+        },
+        None => None,
+    };
+
+    let content: String = row.try_get("content")?;
+    let webhook = match row.try_get::<Option<Snowflake>, _>("webhook_id")? {
+        Some(id) => Some(WebhookAuthor {
+            id,
+            name: row.try_get("webhook_name")?,
+            avatar: row.try_get::<Option<String>, _>("webhook_avatar")?.map(CdnId::new),
+        }),
+        None => None,
+    };
+
+    Ok(Message {
+        id: row.try_get("id")?,
+        channel_id: row.try_get("channel_id")?,
+        author_id: row.try_get("author_id")?,
+        kind: MessageKind::parse(&row.try_get::<String, _>("kind")?),
+        mentions: parse_mentions(&content),
+        content,
+        attachments,
+        created_at: row.try_get("created_at")?,
+        edited_at: row.try_get("edited_at")?,
+        expires_at: row.try_get("expires_at")?,
+        reply_to,
+        thread_id: row.try_get("thread_id")?,
+        reactions: Vec::new(),
+        poll: None,
+        embeds: row.try_get::<Option<String>, _>("embeds")?.and_then(|embeds| serde_json::from_str(&embeds).ok()).unwrap_or_default(),
+        webhook,
+    })
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::new(Status::BadRequest, message.to_string(), "Check the body of your request".to_string())
+}
+
+/// Reactions and polls are loaded separately from the message rows.
+pub async fn fill(messages: &mut [Message], pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    reactions::fill(messages, pool).await?;
+    polls::fill(messages, pool).await
+}
+
+/// A poll or embeds are enough for a message without content.
+pub fn check_content(content: &str, attachments: usize, has_body: bool) -> Result<(), Error> {
+    if content.trim().is_empty() && attachments == 0 && !has_body {
+        return Err(bad_request("A message cannot be empty"));
+    }
+    if content.chars().count() > MAX_CONTENT_LENGTH {
+        return Err(bad_request(&format!("A message cannot be longer than {MAX_CONTENT_LENGTH} caracters")));
+    }
+    if attachments > MAX_ATTACHMENTS {
+        return Err(bad_request(&format!("A message cannot have more than {MAX_ATTACHMENTS} attachments")));
+    }
+
+    Ok(())
+}
+
+impl Message {
+    pub async fn from_id(channel_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Message, sqlx::error::Error> {
+        let q = sqlx::query(&format!("{SELECT_MESSAGE} WHERE m.`channel_id`=? AND m.`id`=? AND m.`deleted_at` IS NULL GROUP BY m.`id`;"))
+            .bind(channel_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        let mut message = from_row(&q)?;
+        fill(std::slice::from_mut(&mut message), pool).await?;
+        Ok(message)
+    }
+
+    /// Pages through a channel history. `after` returns the oldest messages first,
+    /// otherwise the newest messages (older than `before` if given) come first.
+    pub async fn list(channel_id: Snowflake, before: Option<Snowflake>, after: Option<Snowflake>, limit: u32, pool: &Pool<MySql>) -> Result<Vec<Message>, sqlx::error::Error> {
+        let q = match after {
+            Some(after) => sqlx::query(&format!("{SELECT_MESSAGE} WHERE m.`channel_id`=? AND m.`deleted_at` IS NULL AND m.`id`>? GROUP BY m.`id` ORDER BY m.`id` ASC LIMIT ?;"))
+                .bind(channel_id)
+                .bind(after)
+                .bind(limit)
+                .fetch_all(pool)
+                .await?,
+            None => sqlx::query(&format!("{SELECT_MESSAGE} WHERE m.`channel_id`=? AND m.`deleted_at` IS NULL AND m.`id`<? GROUP BY m.`id` ORDER BY m.`id` DESC LIMIT ?;"))
+                .bind(channel_id)
+                .bind(before.map(Snowflake::get).unwrap_or(u64::MAX))
+                .bind(limit)
+                .fetch_all(pool)
+                .await?,
+        };
+
+        let mut messages = q.iter().map(from_row).collect::<Result<Vec<_>, _>>()?;
+        fill(&mut messages, pool).await?;
+        Ok(messages)
+    }
+
+    pub async fn create(channel_id: Snowflake, author_id: Snowflake, input: MessageInput, pool: &Pool<MySql>) -> Result<Message, sqlx::error::Error> {
+        let id = ids::generate();
+
+        let mut tx = pool.begin().await?;
+        let kind = if input.poll.is_some() { MessageKind::Poll } else { MessageKind::Default };
+        let expires_at = input.ttl.map(|ttl| id.created_at() + chrono::Duration::seconds(ttl as i64));
+        let embeds = if input.embeds.is_empty() { None } else { serde_json::to_string(&input.embeds).ok() };
+        sqlx::query("INSERT INTO messages (`id`, `channel_id`, `author_id`, `kind`, `content`, `reply_to`, `created_at`, `expires_at`, `embeds`, `webhook_id`, `webhook_name`, `webhook_avatar`) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);")
+            .bind(id)
+            .bind(channel_id)
+            .bind(author_id)
+            .bind(kind.as_str())
+            .bind(&input.content)
+            .bind(input.reply_to)
+            .bind(id.created_at())
+            .bind(expires_at)
+            .bind(embeds)
+            .bind(input.webhook.as_ref().map(|webhook| webhook.id))
+            .bind(input.webhook.as_ref().map(|webhook| webhook.name.as_str()))
+            .bind(input.webhook.as_ref().and_then(|webhook| webhook.avatar.as_ref()).map(CdnId::as_str))
+            .execute(&mut tx)
+            .await?;
+
+        for (position, hash) in input.attachments.iter().enumerate() {
+            sqlx::query("INSERT INTO message_attachments (`message_id`, `position`, `hash`) VALUES (?, ?, ?);")
+                .bind(id)
+                .bind(position as u32)
+                .bind(hash.as_str())
+                .execute(&mut tx)
+                .await?;
+        }
+        if let Some(poll) = &input.poll {
+            polls::insert(id, poll, &mut tx).await?;
+        }
+        for user_id in parse_mentions(&input.content) {
+            sqlx::query("INSERT INTO message_mentions (`message_id`, `channel_id`, `user_id`) VALUES (?, ?, ?);")
+                .bind(id)
+                .bind(channel_id)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        // read back to resolve the replied message
+        Self::from_id(channel_id, id, pool).await
+    }
+
+    /// Replaces the content, keeping the previous one in the edit history.
+    pub async fn edit(&mut self, content: String, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let now = Utc::now();
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("INSERT INTO message_edits (`message_id`, `content`, `edited_at`) VALUES (?, ?, ?);")
+            .bind(self.id)
+            .bind(&self.content)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("UPDATE messages SET `content`=?, `edited_at`=? WHERE `id`=?;")
+            .bind(&content)
+            .bind(now)
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+
+        let mentions = parse_mentions(&content);
+        sqlx::query("DELETE FROM message_mentions WHERE `message_id`=?;")
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+        for user_id in &mentions {
+            sqlx::query("INSERT INTO message_mentions (`message_id`, `channel_id`, `user_id`) VALUES (?, ?, ?);")
+                .bind(self.id)
+                .bind(self.channel_id)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        self.content = content;
+        self.mentions = mentions;
+        self.edited_at = Some(now);
+        Ok(())
+    }
+
+    /// Soft delete: the row and its edit history are kept, the message is hidden.
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE messages SET `deleted_at`=? WHERE `id`=?;")
+            .bind(Utc::now())
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes the message for good, even if already soft deleted: content, edit history and
+    /// attachment references are removed, so CDN files only referenced by it become orphans.
+    pub async fn purge(id: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE messages SET `content`='', `deleted_at`=IFNULL(`deleted_at`, ?) WHERE `id`=?;")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        for query in [
+            "DELETE FROM message_edits WHERE `message_id`=?;",
+            "DELETE FROM message_attachments WHERE `message_id`=?;",
+            "DELETE FROM message_mentions WHERE `message_id`=?;",
+            "DELETE FROM message_reactions WHERE `message_id`=?;",
+        ] {
+            sqlx::query(query).bind(id).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn edits(&self, pool: &Pool<MySql>) -> Result<Vec<MessageEdit>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT `content`, `edited_at` FROM message_edits WHERE `message_id`=? ORDER BY `edited_at` ASC;")
+            .bind(self.id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter()
+            .map(|row| Ok(MessageEdit { content: row.try_get("content")?, edited_at: row.try_get("edited_at")? }))
+            .collect()
+    }
+}
+
+/// Everything checked before a message is sent, also when it is scheduled.
+pub async fn check_message(channel: &Channel, user: &User, input: &MessageInput, pool: &Pool<MySql>) -> Result<(), Error> {
+    let required = if input.attachments.is_empty() { Permissions::SEND } else { Permissions::SEND | Permissions::UPLOAD };
+    let perms = check_permission(user, channel, required, pool).await?;
+    if !perms.contains(Permissions::MANAGE_MESSAGES) {
+        check_slowmode(channel, user, pool).await?;
+    }
+    if dms::is_blocked_dm(channel, user.id, pool).await? {
+        return Err(Error::new(Status::Forbidden, "You cannot send messages to this user".to_string(), "One of you blocked the other".to_string()));
+    }
+    check_content(&input.content, input.attachments.len(), input.poll.is_some() || !input.embeds.is_empty())?;
+    if !input.embeds.is_empty() {
+        if !user.bot { return Err(bad_request("Only webhooks and bots can send embeds")) }
+        embeds::check(&input.embeds, pool).await?;
+    }
+    if let Some(poll) = &input.poll {
+        polls::check_input(channel.id, poll, pool).await?;
+    }
+    if let Some(ttl) = input.ttl {
+        if !(1..=MAX_TTL).contains(&ttl) {
+            return Err(bad_request(&format!("A message ttl must be between 1 and {MAX_TTL} seconds")));
+        }
+    }
+
+    for hash in &input.attachments {
+        if !hash.exists(pool).await? {
+            return Err(bad_request(&format!("No CDN file found with hash {}", hash.as_str())));
+        }
+    }
+    if let Some(reply_to) = input.reply_to {
+        if Message::from_id(channel.id, reply_to, pool).await.is_err() {
+            return Err(bad_request("The message replied to does not exist in this channel"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails with a 429 if the user sent a message in the channel too recently.
+async fn check_slowmode(channel: &Channel, user: &User, pool: &Pool<MySql>) -> Result<(), Error> {
+    let slowmode_seconds = match channel.parent_id {
+        Some(parent_id) => Channel::from_id(parent_id, pool).await?.slowmode_seconds,
+        None => channel.slowmode_seconds,
+    };
+    if slowmode_seconds == 0 { return Ok(()) }
+
+    // deleted messages count too, deleting does not skip the wait
+    let last = sqlx::query("SELECT `created_at` FROM messages WHERE `channel_id`=? AND `author_id`=? ORDER BY `id` DESC LIMIT 1;")
+        .bind(channel.id)
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(last) = last else { return Ok(()) };
+
+    let next = last.try_get::<DateTime<Utc>, _>("created_at")? + chrono::Duration::seconds(slowmode_seconds as i64);
+    let wait = (next - Utc::now()).num_milliseconds();
+    if wait <= 0 { return Ok(()) }
+
+    Err(Error::too_many_requests("Slowmode is enabled in this channel".to_string(), (wait as u64).div_ceil(1000)))
+}
+
+/// Checks, stores and dispatches a new message.
+pub async fn send(channel: &Channel, user: &User, input: MessageInput, pool: &Pool<MySql>, bus: &Bus) -> Result<Message, Error> {
+    check_message(channel, user, &input, pool).await?;
+    let flagged = automod::moderate(channel, user, &input, pool, bus).await?;
+
+    let message = Message::create(channel.id, user.id, input, pool).await?;
+    automod::flag(&message, channel, &flagged, pool).await?;
+    if let Some(expires_at) = message.expires_at {
+        scheduler::schedule(&Job::DeleteMessage { channel_id: channel.id, message_id: message.id }, expires_at, pool).await?;
+    }
+    if channel.kind == ChannelKind::Thread {
+        if let Some(thread) = threads::touch(channel.id, pool).await? {
+            // sending in an archived thread brings it back
+            bus.publish(thread.parent_id, Event::ThreadUpdate(thread));
+        }
+    }
+    bus.publish(channel.id, Event::MessageCreate(message.clone()));
+
+    Ok(message)
+}
+
+#[post("/channels/<id>/messages", data = "<input>")]
+async fn create_message(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, input: Json<MessageInput>) -> Result<Json<Message>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+
+    Ok(Json(send(&channel, &user, input.into_inner(), pool, bus).await?))
+}
+
+#[get("/channels/<id>/messages?<before>&<after>&<limit>")]
+async fn get_messages(pool: &State<Pool<MySql>>, user: User, id: Snowflake, before: Option<Snowflake>, after: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<Message>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    Ok(Json(Message::list(channel.id, before, after, limit, pool).await?))
+}
+
+#[get("/channels/<id>/messages/<mid>")]
+async fn get_message(pool: &State<Pool<MySql>>, user: User, id: Snowflake, mid: Snowflake) -> Result<Json<Message>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Message::from_id(channel.id, mid, pool).await?))
+}
+
+#[patch("/channels/<id>/messages/<mid>", data = "<input>")]
+async fn edit_message(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, input: Json<MessagePatch>) -> Result<Json<Message>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::SEND, pool).await?;
+    let mut message = Message::from_id(channel.id, mid, pool).await?;
+    if message.author_id != user.id {
+        return Err(Error::new(Status::Forbidden, "Only the author can edit a message".to_string(), "Send a new message instead".to_string()));
+    }
+    check_content(&input.content, message.attachments.len(), message.poll.is_some() || !message.embeds.is_empty())?;
+    // the new content goes through the same rules as a new message
+    let edited = MessageInput { content: input.into_inner().content, attachments: Vec::new(), reply_to: None, poll: None, ttl: None, embeds: Vec::new(), webhook: None };
+    let flagged = automod::moderate(&channel, &user, &edited, pool, bus).await?;
+
+    message.edit(edited.content, pool).await?;
+    automod::flag(&message, &channel, &flagged, pool).await?;
+    bus.publish(message.channel_id, Event::MessageUpdate(message.clone()));
+    Ok(Json(message))
+}
+
+#[delete("/channels/<id>/messages/<mid>")]
+async fn delete_message(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, reason: Reason) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let message = Message::from_id(channel.id, mid, pool).await?;
+    let required = if message.author_id == user.id { Permissions::READ } else { Permissions::MANAGE_MESSAGES };
+    check_permission(&user, &channel, required, pool).await?;
+
+    message.delete(pool).await?;
+    bus.publish(channel.id, Event::MessageDelete { channel_id: channel.id, id: message.id });
+    if message.author_id != user.id {
+        // entries of a thread go to the log of its parent
+        AuditEntry::record(channel.parent_id.unwrap_or(channel.id), user.id, AuditAction::MessageDelete, Some(message.author_id), reason.0, Some(serde_json::json!({ "message_id": message.id })), pool).await?;
+    }
+    Ok(Status::NoContent)
+}
+
+#[get("/channels/<id>/messages/<mid>/edits")]
+async fn get_message_edits(pool: &State<Pool<MySql>>, user: User, id: Snowflake, mid: Snowflake) -> Result<Json<Vec<MessageEdit>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let message = Message::from_id(channel.id, mid, pool).await?;
+
+    Ok(Json(message.edits(pool).await?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create_message, get_messages, get_message, edit_message, delete_message, get_message_edits]
+}
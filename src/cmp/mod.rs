@@ -1,3 +1,34 @@
+pub mod applications;
+pub mod audit;
+pub mod automod;
+pub mod bookmarks;
+pub mod categories;
 pub mod channels;
+pub mod commands;
 pub mod cdn;
-pub mod errors;
\ No newline at end of file
+pub mod dms;
+pub mod embeds;
+pub mod errors;
+pub mod gateway;
+pub mod ids;
+pub mod interactions;
+pub mod invites;
+pub mod members;
+pub mod messages;
+pub mod moderation;
+pub mod oauth2;
+pub mod outgoing;
+pub mod permissions;
+pub mod pins;
+pub mod polls;
+pub mod presence;
+pub mod ratelimit;
+pub mod reactions;
+pub mod read_states;
+pub mod reports;
+pub mod scheduler;
+pub mod search;
+pub mod subscriptions;
+pub mod threads;
+pub mod users;
+pub mod webhooks;
\ No newline at end of file
@@ -0,0 +1,226 @@
+use chrono::{DateTime, Duration, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{MySql, Pool, Row};
+
+use super::{
+    audit::{self, AuditAction, AuditEntry, Reason},
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    members::{check_text_channel, Member},
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+/// A ban can wipe at most the last 7 days of messages.
+const MAX_DELETE_MESSAGE_SECONDS: u64 = 7 * 24 * 60 * 60;
+pub const MAX_TIMEOUT_SECONDS: u64 = 28 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Ban {
+    pub user_id: Snowflake,
+    pub banned_by: Snowflake,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct BanInput {
+    pub reason: Option<String>,
+    /// Deletes the messages the user sent in the channel and its threads during that many seconds.
+    #[serde(default)]
+    pub delete_message_seconds: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TimeoutInput {
+    pub duration_seconds: u64,
+    pub reason: Option<String>,
+}
+
+impl Ban {
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Ban>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM channel_bans WHERE `channel_id`=? ORDER BY `created_at` DESC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter()
+            .map(|row| Ok(Ban {
+                user_id: row.try_get("user_id")?,
+                banned_by: row.try_get("banned_by")?,
+                reason: row.try_get("reason")?,
+                created_at: row.try_get("created_at")?,
+            }))
+            .collect()
+    }
+
+    pub async fn is_banned(channel_id: Snowflake, user_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let row = sqlx::query("SELECT `user_id` FROM channel_bans WHERE `channel_id`=? AND `user_id`=?;")
+            .bind(channel_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+}
+
+/// Fails with a 403 if the user is banned from the channel.
+pub async fn check_not_banned(channel_id: Snowflake, user_id: Snowflake, pool: &Pool<MySql>) -> Result<(), Error> {
+    if !Ban::is_banned(channel_id, user_id, pool).await? { return Ok(()) }
+
+    Err(Error::new(Status::Forbidden, "This user is banned from the channel".to_string(), "Ask a channel moderator to lift the ban".to_string()))
+}
+
+/// Moderation actions cannot target the owner or yourself.
+fn check_target(channel: &Channel, user: &User, target_id: Snowflake) -> Result<(), Error> {
+    if target_id == channel.owner {
+        return Err(Error::new(Status::Forbidden, "The owner of the channel cannot be moderated".to_string(), "Check the user id".to_string()));
+    }
+    if target_id == user.id {
+        return Err(Error::new(Status::BadRequest, "You cannot moderate yourself".to_string(), "Check the user id".to_string()));
+    }
+
+    Ok(())
+}
+
+#[get("/channels/<id>/bans")]
+async fn get_bans(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Ban>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::BAN, pool).await?;
+
+    Ok(Json(Ban::list(channel.id, pool).await?))
+}
+
+#[put("/channels/<id>/bans/<user_id>", data = "<input>")]
+async fn ban_member(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake, input: Option<Json<BanInput>>) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    check_permission(&user, &channel, Permissions::BAN, pool).await?;
+    let target = User::from_id(user_id, pool).await?;
+
+    ban(&channel, &user, &target, input.map(|input| input.into_inner()).unwrap_or_default(), pool, bus).await?;
+    Ok(Status::NoContent)
+}
+
+/// Bans `target` and removes them from the channel, the caller checks the `BAN` permission.
+pub async fn ban(channel: &Channel, user: &User, target: &User, input: BanInput, pool: &Pool<MySql>, bus: &Bus) -> Result<(), Error> {
+    check_target(channel, user, target.id)?;
+    if input.delete_message_seconds > MAX_DELETE_MESSAGE_SECONDS {
+        return Err(Error::new(Status::BadRequest, "Messages can be deleted up to 7 days back".to_string(), format!("Set \"delete_message_seconds\" to at most {MAX_DELETE_MESSAGE_SECONDS}")));
+    }
+    audit::check_reason(input.reason.as_deref())?;
+
+    let now = Utc::now();
+    sqlx::query("REPLACE INTO channel_bans (`channel_id`, `user_id`, `banned_by`, `reason`, `created_at`) VALUES (?, ?, ?, ?, ?);")
+        .bind(channel.id)
+        .bind(target.id)
+        .bind(user.id)
+        .bind(&input.reason)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    if Member::remove(channel.id, target.id, pool).await? {
+        bus.publish(channel.id, Event::MemberLeave { channel_id: channel.id, user_id: target.id });
+    }
+
+    let mut deleted = 0;
+    if input.delete_message_seconds > 0 {
+        let since = now - Duration::seconds(input.delete_message_seconds as i64);
+        let q = sqlx::query("SELECT `id`, `channel_id` FROM messages WHERE `author_id`=? AND `created_at`>=? AND `deleted_at` IS NULL \
+            AND (`channel_id`=? OR `channel_id` IN (SELECT `channel_id` FROM threads WHERE `parent_id`=?));")
+            .bind(target.id)
+            .bind(since)
+            .bind(channel.id)
+            .bind(channel.id)
+            .fetch_all(pool)
+            .await?;
+
+        for row in q.iter() {
+            let (message_id, channel_id): (Snowflake, Snowflake) = (row.try_get("id")?, row.try_get("channel_id")?);
+            sqlx::query("UPDATE messages SET `deleted_at`=? WHERE `id`=?;")
+                .bind(now)
+                .bind(message_id)
+                .execute(pool)
+                .await?;
+            bus.publish(channel_id, Event::MessageDelete { channel_id, id: message_id });
+            deleted += 1;
+        }
+    }
+
+    AuditEntry::record(channel.id, user.id, AuditAction::MemberBan, Some(target.id), input.reason, Some(serde_json::json!({ "deleted_messages": deleted })), pool).await?;
+    Ok(())
+}
+
+#[delete("/channels/<id>/bans/<user_id>")]
+async fn unban_member(pool: &State<Pool<MySql>>, user: User, id: Snowflake, user_id: Snowflake, reason: Reason) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::BAN, pool).await?;
+
+    let q = sqlx::query("DELETE FROM channel_bans WHERE `channel_id`=? AND `user_id`=?;")
+        .bind(channel.id)
+        .bind(user_id)
+        .execute(pool.inner())
+        .await?;
+    if q.rows_affected() == 0 {
+        return Err(Error::new(Status::NotFound, "This user is not banned".to_string(), "Check the user id".to_string()));
+    }
+
+    AuditEntry::record(channel.id, user.id, AuditAction::MemberUnban, Some(user_id), reason.0, None, pool).await?;
+    Ok(Status::NoContent)
+}
+
+/// Returns `false` if the user is not a member, `None` lifts the timeout.
+pub async fn set_timeout(channel_id: Snowflake, user_id: Snowflake, until: Option<DateTime<Utc>>, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let q = sqlx::query("UPDATE channel_members SET `timeout_until`=? WHERE `channel_id`=? AND `user_id`=?;")
+        .bind(until)
+        .bind(channel_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(q.rows_affected() > 0)
+}
+
+/// Blocks sending and uploading in the channel and its threads until the deadline, reading stays allowed.
+#[put("/channels/<id>/timeouts/<user_id>", data = "<input>")]
+async fn timeout_member(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake, input: Json<TimeoutInput>) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    check_permission(&user, &channel, Permissions::MODERATE, pool).await?;
+    check_target(&channel, &user, user_id)?;
+    let input = input.into_inner();
+    if input.duration_seconds == 0 || input.duration_seconds > MAX_TIMEOUT_SECONDS {
+        return Err(Error::new(Status::BadRequest, "A timeout lasts between 1 second and 28 days".to_string(), format!("Set \"duration_seconds\" between 1 and {MAX_TIMEOUT_SECONDS}")));
+    }
+
+    let until = Utc::now() + Duration::seconds(input.duration_seconds as i64);
+    if !set_timeout(channel.id, user_id, Some(until), pool).await? {
+        return Err(Error::new(Status::NotFound, "This user is not a member of the channel".to_string(), "Check the user id".to_string()));
+    }
+
+    bus.publish(channel.id, Event::MemberTimeout { channel_id: channel.id, user_id, timeout_until: Some(until) });
+    AuditEntry::record(channel.id, user.id, AuditAction::MemberTimeout, Some(user_id), input.reason, Some(serde_json::json!({ "timeout_until": until })), pool).await?;
+    Ok(Status::NoContent)
+}
+
+#[delete("/channels/<id>/timeouts/<user_id>")]
+async fn remove_timeout(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, user_id: Snowflake, reason: Reason) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MODERATE, pool).await?;
+
+    if !set_timeout(channel.id, user_id, None, pool).await? {
+        return Err(Error::new(Status::NotFound, "This user is not a member of the channel".to_string(), "Check the user id".to_string()));
+    }
+
+    bus.publish(channel.id, Event::MemberTimeout { channel_id: channel.id, user_id, timeout_until: None });
+    AuditEntry::record(channel.id, user.id, AuditAction::MemberTimeoutRemove, Some(user_id), reason.0, None, pool).await?;
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_bans, ban_member, unban_member, timeout_member, remove_timeout]
+}
@@ -0,0 +1,537 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use rocket::form::Form;
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder};
+use rocket::{serde::json::Json, Request, Route, State};
+use sha2::{Digest, Sha256};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::{check_session, parse_scopes, Application, Scope},
+    errors::Error,
+    ids::{self, Snowflake},
+    users::{hash_token, User},
+};
+
+const TOKEN_LENGTH: usize = 64;
+/// 10 minutes.
+const CODE_LIFETIME: i64 = 10 * 60;
+/// 1 hour.
+const ACCESS_TOKEN_LIFETIME: i64 = 3600;
+/// 30 days, refreshing gives a new one.
+const REFRESH_TOKEN_LIFETIME: i64 = 30 * 24 * 3600;
+const MIN_VERIFIER_LENGTH: usize = 43;
+const MAX_VERIFIER_LENGTH: usize = 128;
+const MAX_STATE_LENGTH: usize = 1024;
+
+/// Sessions row kinds of OAuth2 tokens.
+const ACCESS: &str = "access";
+const REFRESH: &str = "refresh";
+
+/// Space separated scopes, `None` if one is unknown.
+pub fn parse_scope(source: &str) -> Option<Vec<Scope>> {
+    let mut scopes = source.split_whitespace().map(Scope::parse).collect::<Option<Vec<_>>>()?;
+    scopes.sort_by_key(Scope::as_str);
+    scopes.dedup();
+    Some(scopes)
+}
+
+pub fn scope_string(scopes: &[Scope]) -> String {
+    scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" ")
+}
+
+/// PKCE `S256`: the unpadded base64url SHA-256 of the verifier.
+pub fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Query of the authorization request, sent back in the body once the user decides.
+#[derive(Debug, Clone, FromForm, serde::Deserialize)]
+pub struct AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: Snowflake,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AuthorizeInput {
+    #[serde(flatten)]
+    pub request: AuthorizeRequest,
+    /// False when the user refuses.
+    pub approve: bool,
+}
+
+/// What the consent screen shows.
+#[derive(Debug, serde::Serialize)]
+pub struct AuthorizePrompt {
+    pub application: ClientInfo,
+    pub scopes: Vec<Scope>,
+    /// The user already granted every requested scope, the screen can be skipped.
+    pub consented: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ClientInfo {
+    pub id: Snowflake,
+    pub name: String,
+    pub description: String,
+}
+
+impl From<&Application> for ClientInfo {
+    fn from(application: &Application) -> Self {
+        Self { id: application.id, name: application.name.clone(), description: application.description.clone() }
+    }
+}
+
+/// Where to send the user, with either a code or an error.
+#[derive(Debug, serde::Serialize)]
+pub struct AuthorizeRedirect {
+    pub redirect_to: String,
+}
+
+/// Scopes a user granted to an application.
+#[derive(Debug, serde::Serialize)]
+pub struct Consent {
+    pub application: ClientInfo,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub client_id: Snowflake,
+    pub client_secret: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    /// Narrows the scopes when refreshing.
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub refresh_token: String,
+    pub scope: String,
+}
+
+/// Used by introspection and revocation, a `token_type_hint` is not needed.
+#[derive(Debug, FromForm)]
+pub struct TokenInput {
+    pub token: String,
+    pub client_id: Snowflake,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Snowflake>,
+    /// Id of the user who authorized the application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+}
+
+/// Error body of RFC 6749, returned by the token, introspection and revocation routes.
+#[derive(Debug, serde::Serialize)]
+pub struct OAuthError {
+    pub error: &'static str,
+    pub error_description: String,
+}
+
+#[derive(Debug)]
+pub struct OAuthFailure(Status, OAuthError);
+
+fn failure(status: Status, error: &'static str, description: &str) -> OAuthFailure {
+    OAuthFailure(status, OAuthError { error, error_description: description.to_string() })
+}
+
+fn invalid_grant(description: &str) -> OAuthFailure {
+    failure(Status::BadRequest, "invalid_grant", description)
+}
+
+impl From<sqlx::Error> for OAuthFailure {
+    fn from(err: sqlx::Error) -> Self {
+        eprintln!("\x1b[31mOAuth2 database error: {err}\x1b[0m");
+        failure(Status::InternalServerError, "server_error", "Retry later")
+    }
+}
+
+impl<'r> Responder<'r, 'static> for OAuthFailure {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = (self.0, Json(self.1)).respond_to(req)?;
+        if self.0 == Status::Unauthorized {
+            res.set_header(Header::new("WWW-Authenticate", "Basic realm=\"oauth2\""));
+        }
+        Ok(res)
+    }
+}
+
+/// Tokens must not be cached.
+pub struct NoStore<R>(R);
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for NoStore<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = self.0.respond_to(req)?;
+        res.set_header(Header::new("Cache-Control", "no-store"));
+        res.set_header(Header::new("Pragma", "no-cache"));
+        Ok(res)
+    }
+}
+
+/// A session row holding an OAuth2 token.
+struct Token {
+    kind: String,
+    user_id: Snowflake,
+    grant_id: Snowflake,
+    scopes: Vec<Scope>,
+    expires_at: DateTime<Utc>,
+}
+
+fn token_from_row(row: &MySqlRow) -> Result<Token, sqlx::error::Error> {
+    Ok(Token {
+        kind: row.try_get("kind")?,
+        user_id: row.try_get("user_id")?,
+        grant_id: row.try_get("grant_id")?,
+        scopes: parse_scopes(row.try_get::<Option<String>, _>("scopes")?.as_deref()),
+        expires_at: row.try_get("expires_at")?,
+    })
+}
+
+impl Token {
+    /// Unexpired tokens issued to the application.
+    async fn find(application_id: Snowflake, token: &str, pool: &Pool<MySql>) -> Result<Option<Token>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM sessions WHERE `token`=? AND `application_id`=? AND `kind` IN ('access', 'refresh') AND `expires_at`>?;")
+            .bind(hash_token(token))
+            .bind(application_id)
+            .bind(Utc::now())
+            .fetch_optional(pool)
+            .await?;
+
+        q.as_ref().map(token_from_row).transpose()
+    }
+}
+
+/// A new access and refresh token pair for the grant.
+async fn issue(user_id: Snowflake, application_id: Snowflake, grant_id: Snowflake, scopes: &[Scope], pool: &Pool<MySql>) -> Result<TokenResponse, sqlx::error::Error> {
+    let access_token = ids::random_string(TOKEN_LENGTH);
+    let refresh_token = ids::random_string(TOKEN_LENGTH);
+    let now = Utc::now();
+
+    let mut tx = pool.begin().await?;
+    for (token, kind, lifetime) in [(&access_token, ACCESS, ACCESS_TOKEN_LIFETIME), (&refresh_token, REFRESH, REFRESH_TOKEN_LIFETIME)] {
+        sqlx::query("INSERT INTO sessions (`token`, `user_id`, `kind`, `application_id`, `grant_id`, `scopes`, `expires_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+            .bind(hash_token(token))
+            .bind(user_id)
+            .bind(kind)
+            .bind(application_id)
+            .bind(grant_id)
+            .bind(serde_json::to_string(scopes).unwrap_or_default())
+            .bind(now + Duration::seconds(lifetime))
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(TokenResponse { access_token, token_type: "Bearer", expires_in: ACCESS_TOKEN_LIFETIME, refresh_token, scope: scope_string(scopes) })
+}
+
+async fn revoke_grant(grant_id: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    sqlx::query("DELETE FROM sessions WHERE `grant_id`=?;")
+        .bind(grant_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+async fn consented_scopes(user_id: Snowflake, application_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Scope>, sqlx::error::Error> {
+    let row = sqlx::query("SELECT `scopes` FROM oauth_consents WHERE `user_id`=? AND `application_id`=?;")
+        .bind(user_id)
+        .bind(application_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(match row {
+        Some(row) => parse_scopes(Some(&row.try_get::<String, _>("scopes")?)),
+        None => Vec::new(),
+    })
+}
+
+/// Adds the scopes to what the user already granted.
+async fn record_consent(user_id: Snowflake, application_id: Snowflake, scopes: &[Scope], pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    let mut granted = consented_scopes(user_id, application_id, pool).await?;
+    granted.extend_from_slice(scopes);
+    granted.sort_by_key(Scope::as_str);
+    granted.dedup();
+
+    let now = Utc::now();
+    sqlx::query("INSERT INTO oauth_consents (`user_id`, `application_id`, `scopes`, `created_at`, `updated_at`) VALUES (?, ?, ?, ?, ?) \
+        ON DUPLICATE KEY UPDATE `scopes`=VALUES(`scopes`), `updated_at`=VALUES(`updated_at`);")
+        .bind(user_id)
+        .bind(application_id)
+        .bind(serde_json::to_string(&granted).unwrap_or_default())
+        .bind(now)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::new(Status::BadRequest, message.to_string(), "Check the authorization request".to_string())
+}
+
+/// Errors here are shown to the user, the redirect uri cannot be trusted yet.
+async fn check_request(request: &AuthorizeRequest, pool: &Pool<MySql>) -> Result<(Application, Vec<Scope>), Error> {
+    if request.response_type != "code" { return Err(bad_request("Only the code response type is supported")) }
+    let application = match Application::by_id(request.client_id, pool).await {
+        Ok(application) => application,
+        Err(sqlx::Error::RowNotFound) => return Err(bad_request("Unknown client_id")),
+        Err(e) => return Err(e.into()),
+    };
+    if !application.redirect_uris.contains(&request.redirect_uri) {
+        return Err(bad_request("The redirect_uri is not registered for this application"));
+    }
+    if request.code_challenge_method != "S256" || !(MIN_VERIFIER_LENGTH..=MAX_VERIFIER_LENGTH).contains(&request.code_challenge.len()) {
+        return Err(bad_request("A PKCE code_challenge with the S256 method is required"));
+    }
+    if request.state.as_ref().is_some_and(|state| state.len() > MAX_STATE_LENGTH) {
+        return Err(bad_request("The state is too long"));
+    }
+    let scopes = parse_scope(&request.scope).filter(|scopes| !scopes.is_empty()).ok_or_else(|| bad_request("The scope is empty or holds an unknown scope"))?;
+
+    Ok((application, scopes))
+}
+
+fn redirect(request: &AuthorizeRequest, params: &[(&str, &str)]) -> Result<AuthorizeRedirect, Error> {
+    let mut url = reqwest::Url::parse(&request.redirect_uri).map_err(|_| bad_request("Invalid redirect_uri"))?;
+    {
+        let mut query = url.query_pairs_mut();
+        for (name, value) in params { query.append_pair(name, value); }
+        if let Some(state) = &request.state { query.append_pair("state", state); }
+    }
+
+    Ok(AuthorizeRedirect { redirect_to: url.to_string() })
+}
+
+/// Checks the client secret of confidential clients, public clients have none.
+pub async fn authenticate_client(client_id: Snowflake, client_secret: Option<&str>, pool: &Pool<MySql>) -> Result<Application, OAuthFailure> {
+    let invalid_client = || failure(Status::Unauthorized, "invalid_client", "Unknown client or wrong client secret");
+    let application = match Application::by_id(client_id, pool).await {
+        Ok(application) => application,
+        Err(sqlx::Error::RowNotFound) => return Err(invalid_client()),
+        Err(e) => return Err(e.into()),
+    };
+
+    match (&application.client_secret, client_secret) {
+        (None, _) => Ok(application),
+        (Some(hash), Some(secret)) if *hash == hash_token(secret) => Ok(application),
+        _ => Err(invalid_client()),
+    }
+}
+
+/// Describes the request for the consent screen.
+#[get("/oauth2/authorize?<request..>")]
+async fn get_authorize(pool: &State<Pool<MySql>>, user: User, request: AuthorizeRequest) -> Result<Json<AuthorizePrompt>, Error> {
+    check_session(&user)?;
+    let (application, scopes) = check_request(&request, pool).await?;
+    let granted = consented_scopes(user.id, application.id, pool).await?;
+
+    Ok(Json(AuthorizePrompt {
+        application: ClientInfo::from(&application),
+        consented: scopes.iter().all(|scope| granted.contains(scope)),
+        scopes,
+    }))
+}
+
+/// Records the decision of the user and gives the redirection carrying the code.
+#[post("/oauth2/authorize", data = "<input>")]
+async fn authorize(pool: &State<Pool<MySql>>, user: User, input: Json<AuthorizeInput>) -> Result<Json<AuthorizeRedirect>, Error> {
+    check_session(&user)?;
+    let AuthorizeInput { request, approve } = input.into_inner();
+    let (application, scopes) = check_request(&request, pool).await?;
+    if !approve { return Ok(Json(redirect(&request, &[("error", "access_denied")])?)) }
+
+    record_consent(user.id, application.id, &scopes, pool).await?;
+    let code = ids::random_string(TOKEN_LENGTH);
+    sqlx::query("INSERT INTO oauth_codes (`code`, `application_id`, `user_id`, `redirect_uri`, `scopes`, `code_challenge`, `expires_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+        .bind(hash_token(&code))
+        .bind(application.id)
+        .bind(user.id)
+        .bind(&request.redirect_uri)
+        .bind(serde_json::to_string(&scopes).unwrap_or_default())
+        .bind(&request.code_challenge)
+        .bind(Utc::now() + Duration::seconds(CODE_LIFETIME))
+        .execute(pool.inner())
+        .await?;
+
+    Ok(Json(redirect(&request, &[("code", &code)])?))
+}
+
+/// Codes are single use.
+async fn exchange_code(application: &Application, input: &TokenRequest, pool: &Pool<MySql>) -> Result<TokenResponse, OAuthFailure> {
+    let (Some(code), Some(verifier)) = (&input.code, &input.code_verifier) else {
+        return Err(failure(Status::BadRequest, "invalid_request", "code and code_verifier are required"));
+    };
+
+    let row = sqlx::query("SELECT * FROM oauth_codes WHERE `code`=?;")
+        .bind(hash_token(code))
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else { return Err(invalid_grant("Unknown or already used code")) };
+    let claimed = sqlx::query("DELETE FROM oauth_codes WHERE `code`=?;")
+        .bind(hash_token(code))
+        .execute(pool)
+        .await?;
+    if claimed.rows_affected() == 0 { return Err(invalid_grant("Unknown or already used code")) }
+
+    if row.try_get::<Snowflake, _>("application_id")? != application.id || row.try_get::<DateTime<Utc>, _>("expires_at")? < Utc::now() {
+        return Err(invalid_grant("Unknown or expired code"));
+    }
+    if input.redirect_uri.as_deref() != Some(row.try_get::<String, _>("redirect_uri")?.as_str()) {
+        return Err(invalid_grant("The redirect_uri does not match the authorization request"));
+    }
+    if !(MIN_VERIFIER_LENGTH..=MAX_VERIFIER_LENGTH).contains(&verifier.len()) || code_challenge(verifier) != row.try_get::<String, _>("code_challenge")? {
+        return Err(invalid_grant("The code_verifier does not match the code_challenge"));
+    }
+
+    let scopes = parse_scopes(Some(&row.try_get::<String, _>("scopes")?));
+    Ok(issue(row.try_get("user_id")?, application.id, ids::generate(), &scopes, pool).await?)
+}
+
+/// The refresh token is rotated, the grant keeps going.
+async fn refresh(application: &Application, input: &TokenRequest, pool: &Pool<MySql>) -> Result<TokenResponse, OAuthFailure> {
+    let Some(refresh_token) = &input.refresh_token else {
+        return Err(failure(Status::BadRequest, "invalid_request", "refresh_token is required"));
+    };
+    let token = match Token::find(application.id, refresh_token, pool).await? {
+        Some(token) if token.kind == REFRESH => token,
+        _ => return Err(invalid_grant("Unknown or expired refresh token")),
+    };
+
+    let scopes = match &input.scope {
+        Some(scope) => match parse_scope(scope) {
+            Some(scopes) if !scopes.is_empty() && scopes.iter().all(|scope| token.scopes.contains(scope)) => scopes,
+            _ => return Err(failure(Status::BadRequest, "invalid_scope", "A refreshed token can only narrow the granted scopes")),
+        },
+        None => token.scopes,
+    };
+
+    let used = sqlx::query("DELETE FROM sessions WHERE `token`=?;")
+        .bind(hash_token(refresh_token))
+        .execute(pool)
+        .await?;
+    if used.rows_affected() == 0 { return Err(invalid_grant("Unknown or expired refresh token")) }
+
+    Ok(issue(token.user_id, application.id, token.grant_id, &scopes, pool).await?)
+}
+
+#[post("/oauth2/token", data = "<input>")]
+async fn token(pool: &State<Pool<MySql>>, input: Form<TokenRequest>) -> Result<NoStore<Json<TokenResponse>>, OAuthFailure> {
+    let application = authenticate_client(input.client_id, input.client_secret.as_deref(), pool).await?;
+
+    let response = match input.grant_type.as_str() {
+        "authorization_code" => exchange_code(&application, &input, pool).await?,
+        "refresh_token" => refresh(&application, &input, pool).await?,
+        _ => return Err(failure(Status::BadRequest, "unsupported_grant_type", "Use authorization_code or refresh_token")),
+    };
+    Ok(NoStore(Json(response)))
+}
+
+/// Only tokens issued to the calling client are described.
+#[post("/oauth2/introspect", data = "<input>")]
+async fn introspect(pool: &State<Pool<MySql>>, input: Form<TokenInput>) -> Result<NoStore<Json<Introspection>>, OAuthFailure> {
+    let application = authenticate_client(input.client_id, input.client_secret.as_deref(), pool).await?;
+    let Some(token) = Token::find(application.id, &input.token, pool).await? else { return Ok(NoStore(Json(Introspection::default()))) };
+    let user = User::from_id(token.user_id, pool).await?;
+
+    Ok(NoStore(Json(Introspection {
+        active: true,
+        scope: Some(scope_string(&token.scopes)),
+        client_id: Some(application.id),
+        sub: Some(user.id),
+        username: Some(user.name),
+        token_type: Some(if token.kind == REFRESH { "refresh_token" } else { "access_token" }),
+        exp: Some(token.expires_at.timestamp()),
+    })))
+}
+
+/// Revoking a refresh token ends the whole grant. Unknown tokens are not an error.
+#[post("/oauth2/revoke", data = "<input>")]
+async fn revoke(pool: &State<Pool<MySql>>, input: Form<TokenInput>) -> Result<Status, OAuthFailure> {
+    let application = authenticate_client(input.client_id, input.client_secret.as_deref(), pool).await?;
+    let Some(token) = Token::find(application.id, &input.token, pool).await? else { return Ok(Status::Ok) };
+
+    if token.kind == REFRESH {
+        revoke_grant(token.grant_id, pool).await?;
+    } else {
+        sqlx::query("DELETE FROM sessions WHERE `token`=?;")
+            .bind(hash_token(&input.token))
+            .execute(pool.inner())
+            .await?;
+    }
+    Ok(Status::Ok)
+}
+
+/// Applications the user authorized.
+#[get("/users/@me/authorizations")]
+async fn get_consents(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<Consent>>, Error> {
+    check_session(&user)?;
+    let q = sqlx::query("SELECT c.`scopes`, c.`created_at`, c.`updated_at`, a.`id`, a.`name`, a.`description` FROM oauth_consents c JOIN applications a ON a.`id`=c.`application_id` WHERE c.`user_id`=? ORDER BY c.`updated_at` DESC;")
+        .bind(user.id)
+        .fetch_all(pool.inner())
+        .await?;
+
+    let consents = q.iter()
+        .map(|row| Ok(Consent {
+            application: ClientInfo { id: row.try_get("id")?, name: row.try_get("name")?, description: row.try_get("description")? },
+            scopes: parse_scopes(Some(&row.try_get::<String, _>("scopes")?)),
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        }))
+        .collect::<Result<Vec<Consent>, sqlx::Error>>()?;
+    Ok(Json(consents))
+}
+
+/// Forgets the consent and revokes every token the application holds for the user.
+#[delete("/users/@me/authorizations/<application_id>")]
+async fn delete_consent(pool: &State<Pool<MySql>>, user: User, application_id: Snowflake) -> Result<Status, Error> {
+    check_session(&user)?;
+
+    let mut tx = pool.begin().await?;
+    for query in [
+        "DELETE FROM oauth_consents WHERE `application_id`=? AND `user_id`=?;",
+        "DELETE FROM oauth_codes WHERE `application_id`=? AND `user_id`=?;",
+        "DELETE FROM sessions WHERE `application_id`=? AND `user_id`=?;",
+    ] {
+        sqlx::query(query).bind(application_id).bind(user.id).execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_authorize, authorize, token, introspect, revoke, get_consents, delete_consent]
+}
@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+use rocket::figment::Figment;
+use rocket::tokio::net::lookup_host;
+
+/// Whether an address is reachable on the internet: not loopback, private, link-local, shared, reserved...
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast()
+                || ip.is_documentation() || ip.is_multicast()
+                || a == 0
+                // shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        },
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() { return is_public(IpAddr::V4(ip)) }
+            let segments = ip.segments();
+            !(ip.is_multicast()
+                // unspecified, loopback and IPv4-compatible addresses
+                || segments[..6] == [0; 6]
+                // unique local
+                || segments[0] & 0xfe00 == 0xfc00
+                // link-local
+                || segments[0] & 0xffc0 == 0xfe80
+                // documentation
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                // NAT64, reaches IPv4 addresses
+                || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+        },
+    }
+}
+
+/// Only keeps the public addresses a host resolves to. Checking at connection time means
+/// a DNS record changed after a first check cannot lead to the internal network.
+struct PublicResolver {
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let allowed = self.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(&host));
+
+        Box::pin(async move {
+            let addrs = lookup_host((host.as_str(), 0)).await?
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() { return Err(format!("{host} does not resolve to a public address").into()) }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// HTTP client for URLs given by users: event subscriptions and interactions.
+/// Only public addresses are reached, apart from the hosts of `outgoing_allowed_hosts` in the config.
+/// Redirects are not followed and proxies are not used, both would skip the check.
+#[derive(Clone)]
+pub struct Outgoing {
+    client: reqwest::Client,
+    allowed_hosts: Arc<Vec<String>>,
+}
+
+impl Outgoing {
+    pub fn new(timeout: Duration, allowed_hosts: Vec<String>) -> reqwest::Result<Self> {
+        let allowed_hosts = Arc::new(allowed_hosts);
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver { allowed_hosts: allowed_hosts.clone() }))
+            .build()?;
+
+        Ok(Self { client, allowed_hosts })
+    }
+
+    /// Reads `outgoing_allowed_hosts` from the app config, empty when missing.
+    pub fn allowed_hosts(figment: &Figment) -> Vec<String> {
+        figment.extract_inner("outgoing_allowed_hosts").unwrap_or_default()
+    }
+
+    /// Fails if the host of `url` is an address that is not public. Names are checked once resolved,
+    /// but addresses are connected to directly.
+    pub fn check_url(&self, url: &str) -> Result<Url, String> {
+        let url = Url::parse(url).map_err(|e| format!("Invalid url: {e}"))?;
+        // the url crate writes every form of IPv4 address in dotted decimal, IPv6 ones in brackets
+        let host = url.host_str().ok_or("The url has no host")?.trim_start_matches('[').trim_end_matches(']');
+        let Ok(ip) = host.parse::<IpAddr>() else { return Ok(url) };
+
+        let allowed = self.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host));
+        if !allowed && !is_public(ip) { return Err(format!("{ip} is not a public address")) }
+        Ok(url)
+    }
+
+    pub fn post(&self, url: &str) -> Result<reqwest::RequestBuilder, String> {
+        Ok(self.client.post(self.check_url(url)?))
+    }
+}
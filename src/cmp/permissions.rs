@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::ops::{BitAnd, BitOr, Not};
+
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use sqlx::{MySql, Pool, Row};
+
+use super::{channels::{Channel, ChannelKind}, errors::Error, ids::Snowflake, users::User};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Permissions(u64);
+
+impl Permissions {
+    pub const NONE: Self = Self(0);
+    pub const READ: Self = Self(1 << 0);
+    pub const SEND: Self = Self(1 << 1);
+    pub const MANAGE_MESSAGES: Self = Self(1 << 2);
+    pub const MANAGE_CHANNEL: Self = Self(1 << 3);
+    pub const KICK: Self = Self(1 << 4);
+    pub const BAN: Self = Self(1 << 5);
+    pub const UPLOAD: Self = Self(1 << 6);
+    /// Time out members.
+    pub const MODERATE: Self = Self(1 << 7);
+    pub const ALL: Self = Self((1 << 8) - 1);
+
+    /// Granted to every member of a new channel.
+    pub const DEFAULT: Self = Self(Self::READ.0 | Self::SEND.0 | Self::UPLOAD.0);
+
+    const NAMES: [(Self, &'static str); 8] = [
+        (Self::READ, "READ"),
+        (Self::SEND, "SEND"),
+        (Self::MANAGE_MESSAGES, "MANAGE_MESSAGES"),
+        (Self::MANAGE_CHANNEL, "MANAGE_CHANNEL"),
+        (Self::KICK, "KICK"),
+        (Self::BAN, "BAN"),
+        (Self::UPLOAD, "UPLOAD"),
+        (Self::MODERATE, "MODERATE"),
+    ];
+
+    /// Unknown bits are dropped.
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES.iter().filter(|(p, _)| self.contains(*p)).map(|(_, n)| *n).collect()
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self { Self(self.0 | rhs.0) }
+}
+
+impl BitAnd for Permissions {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self { Self(self.0 & rhs.0) }
+}
+
+impl Not for Permissions {
+    type Output = Self;
+    fn not(self) -> Self { Self(!self.0 & Self::ALL.0) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverwriteTarget {
+    Role,
+    Member,
+}
+
+impl OverwriteTarget {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "role" => Some(Self::Role),
+            "member" => Some(Self::Member),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Role => "role",
+            Self::Member => "member",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Overwrite {
+    pub target_type: OverwriteTarget,
+    pub target_id: Snowflake,
+    pub allow: Permissions,
+    pub deny: Permissions,
+}
+
+/// Channel defaults, then roles, then role overwrites, then the member overwrite.
+pub fn resolve(default: Permissions, roles: &[(Snowflake, Permissions)], overwrites: &[Overwrite], user_id: Snowflake) -> Permissions {
+    let mut perms = roles.iter().fold(default, |acc, (_, p)| acc | *p);
+
+    let (mut allow, mut deny) = (Permissions::NONE, Permissions::NONE);
+    for ow in overwrites.iter().filter(|ow| ow.target_type == OverwriteTarget::Role && roles.iter().any(|(id, _)| *id == ow.target_id)) {
+        allow = allow | ow.allow;
+        deny = deny | ow.deny;
+    }
+    perms = (perms & !deny) | allow;
+
+    if let Some(ow) = overwrites.iter().find(|ow| ow.target_type == OverwriteTarget::Member && ow.target_id == user_id) {
+        perms = (perms & !ow.deny) | ow.allow;
+    }
+
+    perms
+}
+
+pub async fn overwrites(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Overwrite>, sqlx::error::Error> {
+    let q = sqlx::query("SELECT `target_type`, `target_id`, `allow`, `deny` FROM channel_overwrites WHERE `channel_id`=?;")
+        .bind(channel_id)
+        .fetch_all(pool)
+        .await?;
+
+    q.iter()
+        .map(|row| Ok(Overwrite {
+            target_type: OverwriteTarget::parse(&row.try_get::<String, _>("target_type")?).unwrap_or(OverwriteTarget::Member),
+            target_id: row.try_get("target_id")?,
+            allow: Permissions::from_bits(row.try_get("allow")?),
+            deny: Permissions::from_bits(row.try_get("deny")?),
+        }))
+        .collect()
+}
+
+/// Effective permissions of a user in a channel, `NONE` if they are not a member.
+pub async fn compute(user_id: Snowflake, channel: &Channel, pool: &Pool<MySql>) -> Result<Permissions, sqlx::error::Error> {
+    // threads share the permissions of their parent channel
+    let parent;
+    let channel = match (channel.kind, channel.parent_id) {
+        (ChannelKind::Thread, Some(parent_id)) => {
+            parent = Channel::from_id(parent_id, pool).await?;
+            &parent
+        },
+        _ => channel,
+    };
+
+    // in a DM, whoever opened it has no more rights than the other side
+    if channel.owner == user_id && channel.kind != ChannelKind::Dm { return Ok(Permissions::ALL) }
+
+    let member = sqlx::query("SELECT `timeout_until` FROM channel_members WHERE `channel_id`=? AND `user_id`=?;")
+        .bind(channel.id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(member) = member else { return Ok(Permissions::NONE) };
+    let timed_out = member.try_get::<Option<DateTime<Utc>>, _>("timeout_until")?.map(|until| until > Utc::now()).unwrap_or(false);
+
+    let roles = sqlx::query("SELECT r.`id`, r.`permissions` FROM member_roles mr JOIN channel_roles r ON r.`id`=mr.`role_id` WHERE mr.`channel_id`=? AND mr.`user_id`=?;")
+        .bind(channel.id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get::<Snowflake, _>("id")?, Permissions::from_bits(row.try_get("permissions")?))))
+        .collect::<Result<Vec<_>, sqlx::error::Error>>()?;
+
+    let perms = resolve(channel.default_permissions, &roles, &overwrites(channel.id, pool).await?, user_id);
+    // a timed out member can still read
+    if timed_out { return Ok(perms & !(Permissions::SEND | Permissions::UPLOAD)) }
+    Ok(perms)
+}
+
+/// Effective permissions of a user in every channel they are a member of, threads excluded.
+/// Same rules as `compute`, in three queries whatever the number of channels.
+pub async fn compute_all(user_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<(Snowflake, Permissions)>, sqlx::error::Error> {
+    let channels = sqlx::query("SELECT c.`id`, c.`kind`, c.`owner`, c.`default_permissions`, m.`timeout_until` FROM channel_members m JOIN channels c ON c.`id`=m.`channel_id` WHERE m.`user_id`=?;")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut roles = HashMap::<Snowflake, Vec<(Snowflake, Permissions)>>::new();
+    let q = sqlx::query("SELECT mr.`channel_id`, r.`id`, r.`permissions` FROM member_roles mr JOIN channel_roles r ON r.`id`=mr.`role_id` WHERE mr.`user_id`=?;")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    for row in &q {
+        roles.entry(row.try_get("channel_id")?).or_default().push((row.try_get("id")?, Permissions::from_bits(row.try_get("permissions")?)));
+    }
+
+    let mut overwrites = HashMap::<Snowflake, Vec<Overwrite>>::new();
+    let q = sqlx::query("SELECT o.`channel_id`, o.`target_type`, o.`target_id`, o.`allow`, o.`deny` FROM channel_overwrites o JOIN channel_members m ON m.`channel_id`=o.`channel_id` WHERE m.`user_id`=?;")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    for row in &q {
+        overwrites.entry(row.try_get("channel_id")?).or_default().push(Overwrite {
+            target_type: OverwriteTarget::parse(&row.try_get::<String, _>("target_type")?).unwrap_or(OverwriteTarget::Member),
+            target_id: row.try_get("target_id")?,
+            allow: Permissions::from_bits(row.try_get("allow")?),
+            deny: Permissions::from_bits(row.try_get("deny")?),
+        });
+    }
+
+    let now = Utc::now();
+    channels.iter()
+        .filter(|row| row.try_get::<String, _>("kind").map(|kind| ChannelKind::parse(&kind) != ChannelKind::Thread).unwrap_or(false))
+        .map(|row| {
+            let id: Snowflake = row.try_get("id")?;
+            let kind = ChannelKind::parse(&row.try_get::<String, _>("kind")?);
+            if row.try_get::<Snowflake, _>("owner")? == user_id && kind != ChannelKind::Dm { return Ok((id, Permissions::ALL)) }
+
+            let default = Permissions::from_bits(row.try_get("default_permissions")?);
+            let perms = resolve(default, roles.get(&id).map(Vec::as_slice).unwrap_or_default(), overwrites.get(&id).map(Vec::as_slice).unwrap_or_default(), user_id);
+            let timed_out = row.try_get::<Option<DateTime<Utc>>, _>("timeout_until")?.map(|until| until > now).unwrap_or(false);
+            Ok((id, if timed_out { perms & !(Permissions::SEND | Permissions::UPLOAD) } else { perms }))
+        })
+        .collect()
+}
+
+/// Fails with a 403 unless `user` has every permission of `perm` in `channel`, and the scopes of a bot allow them.
+pub async fn check_permission(user: &User, channel: &Channel, perm: Permissions, pool: &Pool<MySql>) -> Result<Permissions, Error> {
+    let perms = compute(user.id, channel, pool).await?;
+    let allowed = perms & user.allowed_permissions();
+    if allowed.contains(perm) { return Ok(allowed) }
+    if perms.contains(perm) {
+        return Err(Error::new(
+            Status::Forbidden,
+            format!("Missing scope for permission {}", (perm & !allowed).names().join(", ")),
+            "Add the scope to the application".to_string(),
+        ));
+    }
+
+    Err(Error::new(
+        Status::Forbidden,
+        format!("Missing permission {}", (perm & !perms).names().join(", ")),
+        "Ask a channel moderator for the permission".to_string(),
+    ))
+}
@@ -0,0 +1,113 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{MySql, Pool, Row};
+
+use super::{
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    messages::Message,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const MAX_PINS: i64 = 50;
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Pin {
+    pub message: Message,
+    pub pinned_by: Snowflake,
+    pub pinned_at: DateTime<Utc>,
+}
+
+impl Pin {
+    /// Pins of a channel, newest first. Deleted messages are left out.
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Pin>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT p.`message_id`, p.`pinned_by`, p.`pinned_at` FROM message_pins p JOIN messages m ON m.`id`=p.`message_id` \
+            WHERE p.`channel_id`=? AND m.`deleted_at` IS NULL ORDER BY p.`pinned_at` DESC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        let mut pins = Vec::with_capacity(q.len());
+        for row in &q {
+            pins.push(Pin {
+                message: Message::from_id(channel_id, row.try_get("message_id")?, pool).await?,
+                pinned_by: row.try_get("pinned_by")?,
+                pinned_at: row.try_get("pinned_at")?,
+            });
+        }
+        Ok(pins)
+    }
+
+    /// Returns `false` if the message was already pinned.
+    pub async fn add(message: &Message, pinned_by: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("INSERT IGNORE INTO message_pins (`channel_id`, `message_id`, `pinned_by`, `pinned_at`) VALUES (?, ?, ?, ?);")
+            .bind(message.channel_id)
+            .bind(message.id)
+            .bind(pinned_by)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+
+    /// Returns `false` if the message was not pinned.
+    pub async fn remove(channel_id: Snowflake, message_id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("DELETE FROM message_pins WHERE `channel_id`=? AND `message_id`=?;")
+            .bind(channel_id)
+            .bind(message_id)
+            .execute(pool)
+            .await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+
+    pub async fn count(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<i64, sqlx::error::Error> {
+        sqlx::query("SELECT COUNT(*) AS `count` FROM message_pins p JOIN messages m ON m.`id`=p.`message_id` WHERE p.`channel_id`=? AND m.`deleted_at` IS NULL;")
+            .bind(channel_id)
+            .fetch_one(pool)
+            .await?
+            .try_get("count")
+    }
+}
+
+#[get("/channels/<id>/pins")]
+async fn get_pins(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Pin>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Pin::list(channel.id, pool).await?))
+}
+
+#[put("/channels/<id>/pins/<mid>")]
+async fn pin_message(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+    let message = Message::from_id(channel.id, mid, pool).await?;
+
+    if Pin::count(channel.id, pool).await? >= MAX_PINS {
+        return Err(Error::new(Status::BadRequest, format!("A channel cannot have more than {MAX_PINS} pinned messages"), "Unpin an older message first".to_string()));
+    }
+    if Pin::add(&message, user.id, pool).await? {
+        bus.publish(channel.id, Event::MessagePin { channel_id: channel.id, message_id: message.id, pinned_by: user.id });
+    }
+    Ok(Status::NoContent)
+}
+
+#[delete("/channels/<id>/pins/<mid>")]
+async fn unpin_message(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+
+    if Pin::remove(channel.id, mid, pool).await? {
+        bus.publish(channel.id, Event::MessageUnpin { channel_id: channel.id, message_id: mid });
+    }
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_pins, pin_message, unpin_message]
+}
@@ -0,0 +1,380 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::{fairing::AdHoc, http::Status, serde::json::Json, tokio, Route, State};
+use sqlx::{MySql, Pool, Row, Transaction};
+
+use super::{
+    cdn::CdnId,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    messages::{Message, MessageKind},
+    permissions::{check_permission, Permissions},
+    reactions::Emoji,
+    users::User,
+};
+
+const MIN_OPTIONS: usize = 2;
+const MAX_OPTIONS: usize = 10;
+const MAX_QUESTION_LENGTH: usize = 300;
+const MAX_OPTION_LENGTH: usize = 100;
+/// Poll durations in seconds.
+const MIN_DURATION: u64 = 60;
+const MAX_DURATION: u64 = 14 * 24 * 3600;
+const DEFAULT_DURATION: u64 = 24 * 3600;
+const CLOSE_INTERVAL: StdDuration = StdDuration::from_secs(30);
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub allow_multiple: bool,
+    pub ends_at: DateTime<Utc>,
+    pub closed: bool,
+    /// Live while the poll is open, the stored snapshot once it closed.
+    pub results: PollResults,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PollOption {
+    pub text: String,
+    pub emoji: Option<Emoji>,
+    pub image: Option<CdnId>,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PollResults {
+    /// Votes per option, in the order of the options.
+    pub counts: Vec<u64>,
+    pub voters: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PollInput {
+    pub question: String,
+    pub options: Vec<PollOptionInput>,
+    #[serde(default)]
+    pub allow_multiple: bool,
+    /// In seconds, a day if omitted.
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PollOptionInput {
+    pub text: String,
+    /// A Unicode emoji or the hash of a custom emoji of the channel.
+    pub emoji: Option<String>,
+    pub image: Option<CdnId>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct VoteInput {
+    /// Positions of the chosen options.
+    pub options: Vec<u8>,
+}
+
+fn bad_request(message: String) -> Error {
+    Error::new(Status::BadRequest, message, "Check the body of your request".to_string())
+}
+
+pub async fn check_input(channel_id: Snowflake, input: &PollInput, pool: &Pool<MySql>) -> Result<(), Error> {
+    let question = input.question.trim();
+    if question.is_empty() || question.chars().count() > MAX_QUESTION_LENGTH {
+        return Err(bad_request(format!("A poll question must be between 1 and {MAX_QUESTION_LENGTH} caracters")));
+    }
+    if input.options.len() < MIN_OPTIONS || input.options.len() > MAX_OPTIONS {
+        return Err(bad_request(format!("A poll must have between {MIN_OPTIONS} and {MAX_OPTIONS} options")));
+    }
+    if let Some(duration) = input.duration {
+        if !(MIN_DURATION..=MAX_DURATION).contains(&duration) {
+            return Err(bad_request(format!("A poll must last between {MIN_DURATION} and {MAX_DURATION} seconds")));
+        }
+    }
+
+    for option in &input.options {
+        let text = option.text.trim();
+        if text.is_empty() || text.chars().count() > MAX_OPTION_LENGTH {
+            return Err(bad_request(format!("A poll option must be between 1 and {MAX_OPTION_LENGTH} caracters")));
+        }
+        if let Some(emoji) = &option.emoji {
+            Emoji::parse(channel_id, emoji, pool).await?;
+        }
+        if let Some(image) = &option.image {
+            if !image.exists(pool).await? {
+                return Err(bad_request(format!("No CDN file found with hash {}", image.as_str())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the poll of a new message, in the transaction creating it.
+pub async fn insert(message_id: Snowflake, input: &PollInput, tx: &mut Transaction<'_, MySql>) -> Result<(), sqlx::error::Error> {
+    let ends_at = Utc::now() + Duration::seconds(input.duration.unwrap_or(DEFAULT_DURATION) as i64);
+
+    sqlx::query("INSERT INTO polls (`message_id`, `question`, `allow_multiple`, `ends_at`) VALUES (?, ?, ?, ?);")
+        .bind(message_id)
+        .bind(input.question.trim())
+        .bind(input.allow_multiple)
+        .bind(ends_at)
+        .execute(&mut *tx)
+        .await?;
+
+    for (position, option) in input.options.iter().enumerate() {
+        sqlx::query("INSERT INTO poll_options (`message_id`, `position`, `text`, `emoji`, `image`) VALUES (?, ?, ?, ?, ?);")
+            .bind(message_id)
+            .bind(position as u8)
+            .bind(option.text.trim())
+            .bind(&option.emoji)
+            .bind(option.image.as_ref().map(CdnId::as_str))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn live_results(message_id: Snowflake, options: usize, pool: &Pool<MySql>) -> Result<PollResults, sqlx::error::Error> {
+    let q = sqlx::query("SELECT `position`, COUNT(*) AS `count` FROM poll_votes WHERE `message_id`=? GROUP BY `position`;")
+        .bind(message_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut counts = vec![0; options];
+    for row in &q {
+        let position: u8 = row.try_get("position")?;
+        if let Some(count) = counts.get_mut(position as usize) {
+            *count = row.try_get::<i64, _>("count")? as u64;
+        }
+    }
+
+    let voters = sqlx::query("SELECT COUNT(DISTINCT `user_id`) AS `voters` FROM poll_votes WHERE `message_id`=?;")
+        .bind(message_id)
+        .fetch_one(pool)
+        .await?
+        .try_get::<i64, _>("voters")? as u64;
+
+    Ok(PollResults { counts, voters })
+}
+
+impl Poll {
+    pub async fn from_message(message_id: Snowflake, pool: &Pool<MySql>) -> Result<Poll, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM polls WHERE `message_id`=?;")
+            .bind(message_id)
+            .fetch_one(pool)
+            .await?;
+
+        let rows = sqlx::query("SELECT o.`text`, o.`emoji`, o.`image`, e.`name` AS `emoji_name` FROM poll_options o \
+            JOIN messages m ON m.`id`=o.`message_id` LEFT JOIN custom_emojis e ON e.`channel_id`=m.`channel_id` AND e.`hash`=o.`emoji` \
+            WHERE o.`message_id`=? ORDER BY o.`position` ASC;")
+            .bind(message_id)
+            .fetch_all(pool)
+            .await?;
+
+        let mut options = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let emoji = match (row.try_get::<Option<String>, _>("emoji")?, row.try_get::<Option<String>, _>("emoji_name")?) {
+                (Some(hash), Some(name)) => Some(Emoji::Custom { hash: CdnId::new(hash), name }),
+                // a custom emoji deleted since is dropped, hashes are plain ASCII
+                (Some(key), None) if !key.is_ascii() => Some(Emoji::Unicode { name: key }),
+                _ => None,
+            };
+            options.push(PollOption {
+                text: row.try_get("text")?,
+                emoji,
+                image: row.try_get::<Option<String>, _>("image")?.map(CdnId::new),
+            });
+        }
+
+        let closed_at: Option<DateTime<Utc>> = q.try_get("closed_at")?;
+        let snapshot = q.try_get::<Option<String>, _>("results")?.and_then(|results| serde_json::from_str(&results).ok());
+        let results = match snapshot {
+            Some(results) => results,
+            None => live_results(message_id, options.len(), pool).await?,
+        };
+
+        Ok(Poll {
+            question: q.try_get("question")?,
+            options,
+            allow_multiple: q.try_get("allow_multiple")?,
+            ends_at: q.try_get("ends_at")?,
+            closed: closed_at.is_some(),
+            results,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.closed && self.ends_at > Utc::now()
+    }
+
+    /// Replaces the votes of a user.
+    pub async fn vote(message_id: Snowflake, user_id: Snowflake, options: &[u8], pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM poll_votes WHERE `message_id`=? AND `user_id`=?;")
+            .bind(message_id)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        for position in options {
+            sqlx::query("INSERT INTO poll_votes (`message_id`, `user_id`, `position`) VALUES (?, ?, ?);")
+                .bind(message_id)
+                .bind(user_id)
+                .bind(position)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn votes_of(message_id: Snowflake, user_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<u8>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT `position` FROM poll_votes WHERE `message_id`=? AND `user_id`=? ORDER BY `position` ASC;")
+            .bind(message_id)
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(|row| row.try_get("position")).collect()
+    }
+
+    /// Stores the final results, returns them if the poll was still open.
+    pub async fn close(message_id: Snowflake, pool: &Pool<MySql>) -> Result<Option<PollResults>, sqlx::error::Error> {
+        let poll = Poll::from_message(message_id, pool).await?;
+        if poll.closed { return Ok(None) }
+
+        let q = sqlx::query("UPDATE polls SET `closed_at`=?, `results`=? WHERE `message_id`=? AND `closed_at` IS NULL;")
+            .bind(Utc::now())
+            .bind(serde_json::to_string(&poll.results).unwrap_or_default())
+            .bind(message_id)
+            .execute(pool)
+            .await?;
+
+        Ok(Some(poll.results).filter(|_| q.rows_affected() > 0))
+    }
+}
+
+/// Fills the polls of poll messages.
+pub async fn fill(messages: &mut [Message], pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    for message in messages.iter_mut().filter(|m| m.kind == MessageKind::Poll) {
+        message.poll = Some(Poll::from_message(message.id, pool).await?);
+    }
+
+    Ok(())
+}
+
+async fn close_ended(pool: &Pool<MySql>, bus: &Bus) -> Result<(), sqlx::error::Error> {
+    let q = sqlx::query("SELECT p.`message_id`, m.`channel_id` FROM polls p JOIN messages m ON m.`id`=p.`message_id` WHERE p.`closed_at` IS NULL AND p.`ends_at`<=?;")
+        .bind(Utc::now())
+        .fetch_all(pool)
+        .await?;
+
+    for row in &q {
+        let (message_id, channel_id) = (row.try_get("message_id")?, row.try_get("channel_id")?);
+        if let Some(results) = Poll::close(message_id, pool).await? {
+            bus.publish(channel_id, Event::PollClose { channel_id, message_id, results });
+        }
+    }
+
+    Ok(())
+}
+
+/// Background task closing polls once their end time passed.
+pub fn closer() -> AdHoc {
+    AdHoc::on_liftoff("Poll closer", |rocket| Box::pin(async move {
+        let (Some(pool), Some(bus)) = (rocket.state::<Pool<MySql>>(), rocket.state::<Bus>()) else { return };
+        let (pool, bus) = (pool.clone(), bus.clone());
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLOSE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = close_ended(&pool, &bus).await {
+                    eprintln!("\x1b[31mCannot close ended polls: {e}\x1b[0m");
+                }
+            }
+        });
+    }))
+}
+
+async fn poll_message(channel: &Channel, message_id: Snowflake, pool: &Pool<MySql>) -> Result<(Message, Poll), Error> {
+    let mut message = Message::from_id(channel.id, message_id, pool).await?;
+    match message.poll.take() {
+        Some(poll) => Ok((message, poll)),
+        None => Err(Error::new(Status::BadRequest, "This message is not a poll".to_string(), "Check the message id".to_string())),
+    }
+}
+
+fn poll_closed() -> Error {
+    Error::new(Status::Gone, "This poll is closed".to_string(), "Votes cannot be changed after the end of a poll".to_string())
+}
+
+#[get("/channels/<id>/messages/<mid>/poll/votes/@me")]
+async fn get_own_votes(pool: &State<Pool<MySql>>, user: User, id: Snowflake, mid: Snowflake) -> Result<Json<Vec<u8>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let (message, _) = poll_message(&channel, mid, pool).await?;
+
+    Ok(Json(Poll::votes_of(message.id, user.id, pool).await?))
+}
+
+#[put("/channels/<id>/messages/<mid>/poll/votes/@me", data = "<input>")]
+async fn vote(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, input: Json<VoteInput>) -> Result<Json<PollResults>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::SEND, pool).await?;
+    let (message, poll) = poll_message(&channel, mid, pool).await?;
+    if !poll.is_open() { return Err(poll_closed()) }
+
+    let mut options = input.into_inner().options;
+    options.sort();
+    options.dedup();
+    if options.is_empty() || (!poll.allow_multiple && options.len() > 1) {
+        return Err(bad_request(if poll.allow_multiple { "Choose at least one option".to_string() } else { "Choose exactly one option".to_string() }));
+    }
+    if options.iter().any(|position| *position as usize >= poll.options.len()) {
+        return Err(bad_request(format!("This poll only has {} options", poll.options.len())));
+    }
+
+    Poll::vote(message.id, user.id, &options, pool).await?;
+    let results = live_results(message.id, poll.options.len(), pool).await?;
+    bus.publish(channel.id, Event::PollUpdate { channel_id: channel.id, message_id: message.id, results: results.clone() });
+    Ok(Json(results))
+}
+
+#[delete("/channels/<id>/messages/<mid>/poll/votes/@me")]
+async fn remove_vote(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let (message, poll) = poll_message(&channel, mid, pool).await?;
+    if !poll.is_open() { return Err(poll_closed()) }
+
+    Poll::vote(message.id, user.id, &[], pool).await?;
+    let results = live_results(message.id, poll.options.len(), pool).await?;
+    bus.publish(channel.id, Event::PollUpdate { channel_id: channel.id, message_id: message.id, results });
+    Ok(Status::NoContent)
+}
+
+/// Ends a poll early, its author or anyone managing messages can.
+#[post("/channels/<id>/messages/<mid>/poll/close")]
+async fn close_poll(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake) -> Result<Json<PollResults>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let (message, poll) = poll_message(&channel, mid, pool).await?;
+    let required = if message.author_id == user.id { Permissions::READ } else { Permissions::MANAGE_MESSAGES };
+    check_permission(&user, &channel, required, pool).await?;
+
+    match Poll::close(message.id, pool).await? {
+        Some(results) => {
+            bus.publish(channel.id, Event::PollClose { channel_id: channel.id, message_id: message.id, results: results.clone() });
+            Ok(Json(results))
+        },
+        None => Ok(Json(poll.results)),
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_own_votes, vote, remove_vote, close_poll]
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rocket::{http::Status as HttpStatus, serde::json::Json, tokio, Route, State};
+use sqlx::{MySql, Pool, Row};
+
+use super::{
+    applications::check_not_token,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const MAX_CUSTOM_STATUS_LENGTH: usize = 128;
+/// How long clients show a typing indicator, it is sent again while the user keeps typing.
+const TYPING_DURATION: Duration = Duration::from_secs(10);
+/// Typing events of the same user in the same channel are not repeated more often than this.
+const TYPING_THROTTLE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Idle,
+    Dnd,
+    /// Chosen while connected, the user appears offline.
+    Offline,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Presence {
+    pub user_id: Snowflake,
+    pub status: Status,
+    pub custom_status: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PresenceInput {
+    pub status: Option<Status>,
+    /// An empty string clears it.
+    pub custom_status: Option<String>,
+}
+
+struct Entry {
+    connections: u32,
+    status: Status,
+    custom_status: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    users: HashMap<Snowflake, Entry>,
+    // (user, channel) -> last typing event
+    typing: HashMap<(Snowflake, Snowflake), Instant>,
+}
+
+/// In-memory presence of users, derived from their gateway connections. Nothing is stored in MySQL.
+#[derive(Clone, Default)]
+pub struct Presences {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Presences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Invisible users look exactly like disconnected ones, custom status included.
+    pub fn get(&self, user_id: Snowflake) -> Presence {
+        let presences = self.lock();
+        match presences.users.get(&user_id) {
+            Some(entry) if entry.connections > 0 && entry.status != Status::Offline => Presence { user_id, status: entry.status, custom_status: entry.custom_status.clone() },
+            _ => Presence { user_id, status: Status::Offline, custom_status: None },
+        }
+    }
+
+    /// Counts a new gateway connection, the user stays online as long as the returned guard lives.
+    pub fn connect(&self, user_id: Snowflake, pool: &Pool<MySql>, bus: &Bus) -> Connection {
+        let before = self.get(user_id);
+        self.lock().users.entry(user_id)
+            .or_insert(Entry { connections: 0, status: Status::Online, custom_status: None })
+            .connections += 1;
+        self.announce(before, pool, bus);
+
+        Connection { presences: self.clone(), pool: pool.clone(), bus: bus.clone(), user_id }
+    }
+
+    /// Returns `None` if the user is not connected.
+    pub fn set(&self, user_id: Snowflake, input: PresenceInput, pool: &Pool<MySql>, bus: &Bus) -> Option<Presence> {
+        let before = self.get(user_id);
+        {
+            let mut presences = self.lock();
+            let entry = presences.users.get_mut(&user_id).filter(|entry| entry.connections > 0)?;
+            if let Some(status) = input.status { entry.status = status }
+            if let Some(custom_status) = input.custom_status {
+                entry.custom_status = Some(custom_status).filter(|s| !s.is_empty());
+            }
+        }
+        self.announce(before, pool, bus);
+
+        Some(self.get(user_id))
+    }
+
+    /// Whether a typing event should be sent, repeated keystrokes are throttled.
+    pub fn typing(&self, user_id: Snowflake, channel_id: Snowflake) -> bool {
+        let mut presences = self.lock();
+        let now = Instant::now();
+        presences.typing.retain(|_, at| now.duration_since(*at) < TYPING_DURATION);
+
+        match presences.typing.get(&(user_id, channel_id)) {
+            Some(at) if now.duration_since(*at) < TYPING_THROTTLE => false,
+            _ => {
+                presences.typing.insert((user_id, channel_id), now);
+                true
+            },
+        }
+    }
+
+    /// Sends the presence to the channels of the user if it changed since `before`.
+    fn announce(&self, before: Presence, pool: &Pool<MySql>, bus: &Bus) {
+        let after = self.get(before.user_id);
+        if after == before { return }
+
+        let (pool, bus) = (pool.clone(), bus.clone());
+        tokio::spawn(async move {
+            if let Err(e) = broadcast(after, &pool, &bus).await {
+                eprintln!("\x1b[31mCannot broadcast presence: {e}\x1b[0m");
+            }
+        });
+    }
+}
+
+/// Held by a gateway connection, the user goes offline when the last one is dropped.
+pub struct Connection {
+    presences: Presences,
+    pool: Pool<MySql>,
+    bus: Bus,
+    user_id: Snowflake,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let before = self.presences.get(self.user_id);
+        if let Some(entry) = self.presences.lock().users.get_mut(&self.user_id) {
+            entry.connections = entry.connections.saturating_sub(1);
+        }
+        self.presences.announce(before, &self.pool, &self.bus);
+    }
+}
+
+async fn broadcast(presence: Presence, pool: &Pool<MySql>, bus: &Bus) -> Result<(), sqlx::error::Error> {
+    let q = sqlx::query("SELECT `channel_id` FROM channel_members WHERE `user_id`=?;")
+        .bind(presence.user_id)
+        .fetch_all(pool)
+        .await?;
+
+    for row in &q {
+        bus.publish(row.try_get("channel_id")?, Event::PresenceUpdate(presence.clone()));
+    }
+    Ok(())
+}
+
+/// Whether both users are members of a same channel, the ones presences are broadcast to.
+async fn shares_channel(a: Snowflake, b: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let row = sqlx::query("SELECT ma.`channel_id` FROM channel_members ma JOIN channel_members mb ON mb.`channel_id`=ma.`channel_id` WHERE ma.`user_id`=? AND mb.`user_id`=? LIMIT 1;")
+        .bind(a)
+        .bind(b)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+pub fn check_custom_status(input: &PresenceInput) -> Result<(), Error> {
+    match &input.custom_status {
+        Some(custom_status) if custom_status.chars().count() > MAX_CUSTOM_STATUS_LENGTH => Err(Error::new(
+            HttpStatus::BadRequest,
+            format!("A custom status cannot be longer than {MAX_CUSTOM_STATUS_LENGTH} caracters"),
+            "Check the body of your request".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[get("/users/<id>/presence")]
+async fn get_presence(pool: &State<Pool<MySql>>, presences: &State<Presences>, user: User, id: Snowflake) -> Result<Json<Presence>, Error> {
+    if id != user.id && !shares_channel(user.id, id, pool).await? {
+        return Err(Error::new(HttpStatus::NotFound, "Unknown user".to_string(), "You can only see the presence of users sharing a channel with you".to_string()));
+    }
+
+    Ok(Json(presences.get(id)))
+}
+
+#[patch("/users/@me/presence", data = "<input>")]
+async fn edit_presence(pool: &State<Pool<MySql>>, bus: &State<Bus>, presences: &State<Presences>, user: User, input: Json<PresenceInput>) -> Result<Json<Presence>, Error> {
+    check_not_token(&user)?;
+    let input = input.into_inner();
+    check_custom_status(&input)?;
+
+    presences.set(user.id, input, pool, bus)
+        .map(Json)
+        .ok_or_else(|| Error::new(HttpStatus::Conflict, "You are not connected to the gateway".to_string(), "Open a gateway connection first".to_string()))
+}
+
+/// Ephemeral, clients show the indicator until `expires_at` or the next message of the user.
+#[post("/channels/<id>/typing")]
+async fn start_typing(pool: &State<Pool<MySql>>, bus: &State<Bus>, presences: &State<Presences>, user: User, id: Snowflake) -> Result<HttpStatus, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::SEND, pool).await?;
+
+    if presences.typing(user.id, channel.id) {
+        let expires_at: DateTime<Utc> = Utc::now() + chrono::Duration::seconds(TYPING_DURATION.as_secs() as i64);
+        bus.publish(channel.id, Event::TypingStart { channel_id: channel.id, user_id: user.id, expires_at });
+    }
+    Ok(HttpStatus::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_presence, edit_presence, start_typing]
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{uri::Origin, Header, Method, RawStr, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Build, Data, Request, Response, Rocket};
+use sqlx::{MySql, Pool};
+
+use super::{errors::Error, ids::Snowflake, oauth2, users::User, webhooks::Webhook};
+
+/// Internal route limited requests are rerouted to, so their handler never runs.
+const LIMITED_PATH: &str = "/__rate_limited";
+/// The memory store drops refilled buckets once it holds that many, at most once per `PRUNE_INTERVAL`.
+const PRUNE_THRESHOLD: usize = 10_000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// OAuth2 endpoints authenticating the client in their form body.
+const OAUTH2_PATHS: [&str; 3] = ["/oauth2/token", "/oauth2/introspect", "/oauth2/revoke"];
+
+/// `limit` requests per `period` seconds, refilled continuously.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct Limit {
+    pub limit: u32,
+    pub period: u64,
+}
+
+impl Limit {
+    fn rate(&self) -> f64 {
+        self.limit as f64 / self.period.max(1) as f64
+    }
+}
+
+/// Read from the `rate_limits` table of the app config (`Rocket.toml`).
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_limit")]
+    pub default: Limit,
+    /// Requests with rejected credentials, counted per IP to slow down token guessing.
+    #[serde(default = "auth_limit")]
+    pub auth: Limit,
+    #[serde(default = "cdn_limit")]
+    pub cdn: Limit,
+    #[serde(default = "messages_limit")]
+    pub messages: Limit,
+    /// Counted per webhook rather than per caller.
+    #[serde(default = "webhooks_limit")]
+    pub webhooks: Limit,
+    /// Counted per OAuth2 client on the endpoints it authenticates to.
+    #[serde(default = "oauth2_limit")]
+    pub oauth2: Limit,
+}
+
+fn default_limit() -> Limit { Limit { limit: 120, period: 60 } }
+fn auth_limit() -> Limit { Limit { limit: 5, period: 60 } }
+fn cdn_limit() -> Limit { Limit { limit: 100, period: 10 } }
+fn messages_limit() -> Limit { Limit { limit: 5, period: 5 } }
+fn webhooks_limit() -> Limit { Limit { limit: 5, period: 2 } }
+fn oauth2_limit() -> Limit { Limit { limit: 30, period: 60 } }
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self { default: default_limit(), auth: auth_limit(), cdn: cdn_limit(), messages: messages_limit(), webhooks: webhooks_limit(), oauth2: oauth2_limit() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Default,
+    Auth,
+    Cdn,
+    Messages,
+    Webhooks,
+    OAuth2,
+}
+
+impl Group {
+    fn of(req: &Request<'_>) -> Self {
+        let path = req.uri().path();
+        if path.starts_with("/cdn/") { return Self::Cdn }
+        if req.method() == Method::Post && path.starts_with("/channels/") && path.ends_with("/messages") { return Self::Messages }
+        if req.method() == Method::Post && path.starts_with("/webhooks/") { return Self::Webhooks }
+        if req.method() == Method::Post && OAUTH2_PATHS.contains(&path.as_str()) { return Self::OAuth2 }
+        Self::Default
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Auth => "auth",
+            Self::Cdn => "cdn",
+            Self::Messages => "messages",
+            Self::Webhooks => "webhooks",
+            Self::OAuth2 => "oauth2",
+        }
+    }
+
+    fn limit(&self, config: &RateLimitConfig) -> Limit {
+        match self {
+            Self::Default => config.default,
+            Self::Auth => config.auth,
+            Self::Cdn => config.cdn,
+            Self::Messages => config.messages,
+            Self::Webhooks => config.webhooks,
+            Self::OAuth2 => config.oauth2,
+        }
+    }
+}
+
+/// Outcome of taking a token, durations in seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the bucket is full again.
+    pub reset_after: f64,
+    /// Until the next token, 0 when allowed.
+    pub retry_after: f64,
+}
+
+/// Where buckets live. The memory store only limits a single instance, a shared store can implement this.
+#[rocket::async_trait]
+pub trait Store: Send + Sync + 'static {
+    /// Takes a token from the bucket of `key`, a new bucket starts full.
+    async fn take(&self, key: &str, limit: Limit) -> Decision;
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: Limit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate()).min(self.limit.limit as f64);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    pruned: Instant,
+}
+
+#[derive(Debug)]
+pub struct MemoryStore {
+    buckets: Mutex<Buckets>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self { buckets: Mutex::new(Buckets { buckets: HashMap::new(), pruned: Instant::now() }) }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[rocket::async_trait]
+impl Store for MemoryStore {
+    async fn take(&self, key: &str, limit: Limit) -> Decision {
+        let now = Instant::now();
+        let mut store = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        // a full map is only walked once per interval, whoever keeps it full
+        if store.buckets.len() >= PRUNE_THRESHOLD && now.duration_since(store.pruned) >= PRUNE_INTERVAL {
+            store.pruned = now;
+            // a full bucket is the same as no bucket
+            store.buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.limit.limit as f64
+            });
+        }
+
+        let bucket = store.buckets.entry(key.to_string()).or_insert(Bucket { tokens: limit.limit as f64, updated: now, limit });
+        bucket.limit = limit;
+        bucket.refill(now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed { bucket.tokens -= 1.0 }
+        let rate = limit.rate();
+
+        Decision {
+            allowed,
+            limit: limit.limit,
+            remaining: bucket.tokens.floor() as u32,
+            reset_after: (limit.limit as f64 - bucket.tokens) / rate,
+            retry_after: if allowed { 0.0 } else { (1.0 - bucket.tokens) / rate },
+        }
+    }
+}
+
+/// Token bucket limits per route group, keyed by webhook, OAuth2 client, authenticated user or else by IP.
+/// Limited requests are answered with a 429 before reaching their route.
+pub struct RateLimiter {
+    store: Box<dyn Store>,
+    config: OnceLock<RateLimitConfig>,
+}
+
+impl RateLimiter {
+    pub fn new(store: impl Store) -> Self {
+        Self { store: Box::new(store), config: OnceLock::new() }
+    }
+}
+
+/// Id of the webhook targeted by `/webhooks/<id>/<token>`, if the token is right.
+async fn webhook(req: &Request<'_>) -> Option<Snowflake> {
+    let segments = req.uri().path().segments();
+    let id = segments.get(1)?.parse().ok()?;
+    let token = segments.get(2)?;
+    let pool = req.rocket().state::<Pool<MySql>>()?;
+    Webhook::from_token(id, token, pool).await.ok().map(|webhook| webhook.id)
+}
+
+/// Key of the OAuth2 client named in the form body, if it authenticates. Anyone can name a public client,
+/// which has no secret, so its requests are also counted per IP.
+async fn oauth2_client(req: &Request<'_>, data: &mut Data<'_>, ip: Option<&str>) -> Option<String> {
+    let body = std::str::from_utf8(data.peek(512).await).ok()?;
+    let mut client_id = None;
+    let mut client_secret = None;
+    for (name, value) in body.split('&').filter_map(|field| field.split_once('=')) {
+        match name {
+            "client_id" => client_id = RawStr::new(value).url_decode_lossy().parse::<Snowflake>().ok(),
+            "client_secret" => client_secret = Some(RawStr::new(value).url_decode_lossy().into_owned()),
+            _ => {},
+        }
+    }
+
+    let pool = req.rocket().state::<Pool<MySql>>()?;
+    let application = oauth2::authenticate_client(client_id?, client_secret.as_deref(), pool).await.ok()?;
+    Some(match application.client_secret {
+        Some(_) => format!("client:{}", application.id),
+        None => format!("client:{}:ip:{}", application.id, ip?),
+    })
+}
+
+/// The decision taken for the current request, if it went through the limiter.
+struct Limited(Option<(Group, Decision)>);
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info { name: "Rate limiter", kind: Kind::Ignite | Kind::Request | Kind::Response }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        let config = match rocket.figment().find_value("rate_limits") {
+            Ok(_) => match rocket.figment().extract_inner::<RateLimitConfig>("rate_limits") {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("\x1b[31mInvalid rate_limits config: {e}\x1b[0m");
+                    return Err(rocket);
+                },
+            },
+            Err(_) => RateLimitConfig::default(),
+        };
+        let _ = self.config.set(config);
+
+        Ok(rocket.mount("/", routes![rate_limited]))
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, data: &mut Data<'_>) {
+        let Some(config) = self.config.get() else { return };
+        let mut group = Group::of(req);
+        let ip = req.client_ip().map(|ip| ip.to_string());
+        let ip_key = |group: Group| ip.as_ref().map(|ip| format!("{}:ip:{}", group.as_str(), ip));
+
+        let key = if group == Group::Webhooks {
+            // webhook ids are public, only a valid token takes from the bucket of the webhook
+            match webhook(req).await {
+                Some(webhook_id) => Some(format!("{}:webhook:{}", group.as_str(), webhook_id)),
+                None => {
+                    group = Group::Auth;
+                    ip_key(group)
+                },
+            }
+        } else if group == Group::OAuth2 {
+            match oauth2_client(req, data, ip.as_deref()).await {
+                Some(client) => Some(format!("{}:{}", group.as_str(), client)),
+                None => {
+                    group = Group::Auth;
+                    ip_key(group)
+                },
+            }
+        } else {
+            match req.guard::<User>().await.succeeded() {
+                Some(user) => Some(format!("{}:user:{}", group.as_str(), user.id)),
+                None => {
+                    if req.headers().contains("Authorization") { group = Group::Auth }
+                    ip_key(group)
+                },
+            }
+        };
+        // only local transports have no address, rather than sharing one bucket they are not limited
+        let Some(key) = key else { return };
+        let decision = self.store.take(&key, group.limit(config)).await;
+        req.local_cache(|| Limited(Some((group, decision))));
+
+        if !decision.allowed {
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(LIMITED_PATH).expect("valid rate limited path"));
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Limited(Some((group, decision))) = req.local_cache(|| Limited(None)) else { return };
+
+        res.set_header(Header::new("X-RateLimit-Bucket", group.as_str()));
+        res.set_header(Header::new("X-RateLimit-Limit", decision.limit.to_string()));
+        res.set_header(Header::new("X-RateLimit-Remaining", decision.remaining.to_string()));
+        res.set_header(Header::new("X-RateLimit-Reset-After", format!("{:.3}", decision.reset_after)));
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Limited {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(req.local_cache(|| Limited(None)))
+    }
+}
+
+#[get("/__rate_limited")]
+fn rate_limited(limited: &Limited) -> Error {
+    match limited.0 {
+        Some((group, decision)) if !decision.allowed => Error::too_many_requests(
+            format!("You are being rate limited on the {} routes", group.as_str()),
+            decision.retry_after.ceil() as u64,
+        ),
+        _ => Error::new(Status::NotFound, "No ressource found at this address".to_string(), "Check the path of your request".to_string()),
+    }
+}
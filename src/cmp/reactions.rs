@@ -0,0 +1,305 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    cdn::CdnId,
+    channels::Channel,
+    dms,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    messages::Message,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+/// Distinct emojis a single message can be reacted with.
+const MAX_REACTIONS: usize = 20;
+const MAX_UNICODE_LENGTH: usize = 32;
+const MAX_EMOJI_NAME_LENGTH: usize = 32;
+const DEFAULT_PAGE_SIZE: u32 = 25;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Emoji {
+    Unicode { name: String },
+    Custom { hash: CdnId, name: String },
+}
+
+impl Emoji {
+    /// Value stored in `message_reactions` and used in routes.
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Unicode { name } => name,
+            Self::Custom { hash, .. } => hash.as_str(),
+        }
+    }
+
+    /// Reads an emoji from a route, either a Unicode emoji or the hash of a custom emoji of the channel.
+    pub async fn parse(channel_id: Snowflake, source: &str, pool: &Pool<MySql>) -> Result<Emoji, Error> {
+        if let Some(emoji) = CustomEmoji::from_hash(channel_id, source, pool).await? {
+            return Ok(Self::Custom { hash: emoji.hash, name: emoji.name });
+        }
+
+        // emojis are never plain ASCII, this keeps hashes and names out
+        if source.is_ascii() || source.len() > MAX_UNICODE_LENGTH || source.chars().any(char::is_whitespace) {
+            return Err(Error::new(Status::BadRequest, format!("{source} is not an emoji"), "Use a Unicode emoji or the hash of a custom emoji of this channel".to_string()));
+        }
+        Ok(Self::Unicode { name: source.to_string() })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Reaction {
+    pub emoji: Emoji,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CustomEmoji {
+    pub hash: CdnId,
+    pub channel_id: Snowflake,
+    pub name: String,
+    pub creator_id: Snowflake,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CustomEmojiInput {
+    pub hash: CdnId,
+    pub name: String,
+}
+
+fn emoji_from_row(row: &MySqlRow) -> Result<CustomEmoji, sqlx::error::Error> {
+    Ok(CustomEmoji {
+        hash: CdnId::new(row.try_get("hash")?),
+        channel_id: row.try_get("channel_id")?,
+        name: row.try_get("name")?,
+        creator_id: row.try_get("creator_id")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl CustomEmoji {
+    pub async fn from_hash(channel_id: Snowflake, hash: &str, pool: &Pool<MySql>) -> Result<Option<CustomEmoji>, sqlx::error::Error> {
+        let row = sqlx::query("SELECT * FROM custom_emojis WHERE `channel_id`=? AND `hash`=?;")
+            .bind(channel_id)
+            .bind(hash)
+            .fetch_optional(pool)
+            .await?;
+
+        row.as_ref().map(emoji_from_row).transpose()
+    }
+
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<CustomEmoji>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM custom_emojis WHERE `channel_id`=? ORDER BY `name` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(emoji_from_row).collect()
+    }
+
+    /// Returns `false` if the name or the image is already used in the channel.
+    pub async fn create(&self, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let q = sqlx::query("INSERT IGNORE INTO custom_emojis (`channel_id`, `hash`, `name`, `creator_id`, `created_at`) VALUES (?, ?, ?, ?, ?);")
+            .bind(self.channel_id)
+            .bind(self.hash.as_str())
+            .bind(&self.name)
+            .bind(self.creator_id)
+            .bind(self.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(q.rows_affected() > 0)
+    }
+
+    /// Also removes the reactions using it from the messages of the channel.
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE r FROM message_reactions r JOIN messages m ON m.`id`=r.`message_id` WHERE m.`channel_id`=? AND r.`emoji`=?;")
+            .bind(self.channel_id)
+            .bind(self.hash.as_str())
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM custom_emojis WHERE `channel_id`=? AND `hash`=?;")
+            .bind(self.channel_id)
+            .bind(self.hash.as_str())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Fills the reaction counts of messages in one query.
+pub async fn fill(messages: &mut [Message], pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    if messages.is_empty() { return Ok(()) }
+
+    let sql = format!(
+        "SELECT r.`message_id`, r.`emoji`, e.`name`, COUNT(*) AS `count`, MIN(r.`created_at`) AS `first_at` FROM message_reactions r \
+        JOIN messages m ON m.`id`=r.`message_id` LEFT JOIN custom_emojis e ON e.`channel_id`=m.`channel_id` AND e.`hash`=r.`emoji` \
+        WHERE r.`message_id` IN ({}) GROUP BY r.`message_id`, r.`emoji`, e.`name` ORDER BY `first_at` ASC;",
+        vec!["?"; messages.len()].join(", "),
+    );
+    let mut query = sqlx::query(&sql);
+    for message in messages.iter() {
+        query = query.bind(message.id);
+    }
+
+    for row in query.fetch_all(pool).await? {
+        let message_id: Snowflake = row.try_get("message_id")?;
+        let key: String = row.try_get("emoji")?;
+        let emoji = match row.try_get::<Option<String>, _>("name")? {
+            Some(name) => Emoji::Custom { hash: CdnId::new(key), name },
+            None => Emoji::Unicode { name: key },
+        };
+        let count = row.try_get::<i64, _>("count")? as u32;
+
+        if let Some(message) = messages.iter_mut().find(|m| m.id == message_id) {
+            message.reactions.push(Reaction { emoji, count });
+        }
+    }
+
+    Ok(())
+}
+
+/// The message, checking the user can react in the channel.
+async fn reactable(user: &User, channel_id: Snowflake, message_id: Snowflake, pool: &Pool<MySql>) -> Result<(Channel, Message), Error> {
+    let channel = Channel::from_id(channel_id, pool).await?;
+    check_permission(user, &channel, Permissions::SEND, pool).await?;
+    if dms::is_blocked_dm(&channel, user.id, pool).await? {
+        return Err(Error::new(Status::Forbidden, "You cannot react in this conversation".to_string(), "One of you blocked the other".to_string()));
+    }
+    let message = Message::from_id(channel.id, message_id, pool).await?;
+
+    Ok((channel, message))
+}
+
+#[put("/channels/<id>/messages/<mid>/reactions/<emoji>/@me")]
+async fn add_reaction(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, emoji: String) -> Result<Status, Error> {
+    let (channel, message) = reactable(&user, id, mid, pool).await?;
+    let emoji = Emoji::parse(channel.id, &emoji, pool).await?;
+
+    let new_emoji = !message.reactions.iter().any(|r| r.emoji == emoji);
+    if new_emoji && message.reactions.len() >= MAX_REACTIONS {
+        return Err(Error::new(Status::BadRequest, format!("A message cannot have more than {MAX_REACTIONS} different reactions"), "React with an existing emoji".to_string()));
+    }
+
+    let q = sqlx::query("INSERT IGNORE INTO message_reactions (`message_id`, `emoji`, `user_id`, `created_at`) VALUES (?, ?, ?, ?);")
+        .bind(message.id)
+        .bind(emoji.key())
+        .bind(user.id)
+        .bind(Utc::now())
+        .execute(pool.inner())
+        .await?;
+
+    if q.rows_affected() > 0 {
+        bus.publish(channel.id, Event::ReactionAdd { channel_id: channel.id, message_id: message.id, user_id: user.id, emoji });
+    }
+    Ok(Status::NoContent)
+}
+
+async fn remove_reaction(pool: &Pool<MySql>, bus: &Bus, channel: &Channel, message_id: Snowflake, emoji: &str, user_id: Snowflake) -> Result<Status, Error> {
+    let message = Message::from_id(channel.id, message_id, pool).await?;
+    // the custom emoji may be gone, its reactions were removed with it
+    let Some(reaction) = message.reactions.into_iter().find(|r| r.emoji.key() == emoji) else { return Ok(Status::NoContent) };
+
+    let q = sqlx::query("DELETE FROM message_reactions WHERE `message_id`=? AND `emoji`=? AND `user_id`=?;")
+        .bind(message.id)
+        .bind(emoji)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    if q.rows_affected() > 0 {
+        bus.publish(channel.id, Event::ReactionRemove { channel_id: channel.id, message_id: message.id, user_id, emoji: reaction.emoji });
+    }
+    Ok(Status::NoContent)
+}
+
+#[delete("/channels/<id>/messages/<mid>/reactions/<emoji>/@me")]
+async fn remove_own_reaction(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, emoji: String) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    remove_reaction(pool, bus, &channel, mid, &emoji, user.id).await
+}
+
+#[delete("/channels/<id>/messages/<mid>/reactions/<emoji>/<user_id>", rank = 2)]
+async fn remove_user_reaction(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, emoji: String, user_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+
+    remove_reaction(pool, bus, &channel, mid, &emoji, user_id).await
+}
+
+/// Users who reacted with an emoji, paged by user id.
+#[get("/channels/<id>/messages/<mid>/reactions/<emoji>?<after>&<limit>")]
+async fn get_reaction_users(pool: &State<Pool<MySql>>, user: User, id: Snowflake, mid: Snowflake, emoji: String, after: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<User>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let message = Message::from_id(channel.id, mid, pool).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let q = sqlx::query("SELECT u.`id`, u.`name`, u.`bot` FROM message_reactions r JOIN users u ON u.`id`=r.`user_id` \
+        WHERE r.`message_id`=? AND r.`emoji`=? AND u.`id`>? ORDER BY u.`id` ASC LIMIT ?;")
+        .bind(message.id)
+        .bind(&emoji)
+        .bind(after.map(Snowflake::get).unwrap_or(0))
+        .bind(limit)
+        .fetch_all(pool.inner())
+        .await?;
+
+    let users = q.iter()
+        .map(|row| Ok(User { id: row.try_get("id")?, name: row.try_get("name")?, bot: row.try_get("bot")?, scopes: None }))
+        .collect::<Result<Vec<User>, sqlx::Error>>()?;
+    Ok(Json(users))
+}
+
+#[get("/channels/<id>/emojis")]
+async fn get_emojis(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<CustomEmoji>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(CustomEmoji::list(channel.id, pool).await?))
+}
+
+#[post("/channels/<id>/emojis", data = "<input>")]
+async fn create_emoji(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<CustomEmojiInput>) -> Result<Json<CustomEmoji>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let input = input.into_inner();
+
+    let name_length = input.name.chars().count();
+    if !(2..=MAX_EMOJI_NAME_LENGTH).contains(&name_length) || !input.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(Error::new(Status::BadRequest, format!("An emoji name must be 2 to {MAX_EMOJI_NAME_LENGTH} letters, digits or underscores"), "Check the body of your request".to_string()));
+    }
+    if !input.hash.exists(pool).await? {
+        return Err(Error::new(Status::BadRequest, format!("No CDN file found with hash {}", input.hash.as_str()), "Upload the image first".to_string()));
+    }
+
+    let emoji = CustomEmoji { hash: input.hash, channel_id: channel.id, name: input.name, creator_id: user.id, created_at: Utc::now() };
+    if !emoji.create(pool).await? {
+        return Err(Error::new(Status::Conflict, "This channel already has an emoji with this name or image".to_string(), "Pick another name".to_string()));
+    }
+    Ok(Json(emoji))
+}
+
+#[delete("/channels/<id>/emojis/<hash>")]
+async fn delete_emoji(pool: &State<Pool<MySql>>, user: User, id: Snowflake, hash: String) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let emoji = CustomEmoji::from_hash(channel.id, &hash, pool).await?.ok_or(sqlx::Error::RowNotFound)?;
+
+    emoji.delete(pool).await?;
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![add_reaction, remove_own_reaction, remove_user_reaction, get_reaction_users, get_emojis, create_emoji, delete_emoji]
+}
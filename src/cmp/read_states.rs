@@ -0,0 +1,116 @@
+use chrono::Utc;
+use rocket::{serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::check_session,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::Snowflake,
+    messages::Message,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+// unread messages are the ones after the last read one, not written by the user
+const SELECT_READ_STATE: &str = "SELECT cm.`channel_id`, rs.`last_read_id`, \
+    (SELECT MAX(m.`id`) FROM messages m WHERE m.`channel_id`=cm.`channel_id` AND m.`deleted_at` IS NULL) AS `last_message_id`, \
+    (SELECT COUNT(*) FROM messages m WHERE m.`channel_id`=cm.`channel_id` AND m.`deleted_at` IS NULL \
+        AND m.`author_id`<>cm.`user_id` AND m.`id`>IFNULL(rs.`last_read_id`, 0)) AS `unread_count`, \
+    (SELECT COUNT(*) FROM message_mentions mm JOIN messages m ON m.`id`=mm.`message_id` WHERE mm.`user_id`=cm.`user_id` \
+        AND mm.`channel_id`=cm.`channel_id` AND m.`deleted_at` IS NULL AND mm.`message_id`>IFNULL(rs.`last_read_id`, 0)) AS `mention_count` \
+    FROM channel_members cm LEFT JOIN read_states rs ON rs.`user_id`=cm.`user_id` AND rs.`channel_id`=cm.`channel_id`";
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ReadState {
+    pub channel_id: Snowflake,
+    /// `None` if the user never read the channel.
+    pub last_read_id: Option<Snowflake>,
+    pub last_message_id: Option<Snowflake>,
+    pub unread_count: u64,
+    pub mention_count: u64,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct AckInput {
+    /// Defaults to the last message of the channel.
+    pub message_id: Option<Snowflake>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<ReadState, sqlx::error::Error> {
+    Ok(ReadState {
+        channel_id: row.try_get("channel_id")?,
+        last_read_id: row.try_get("last_read_id")?,
+        last_message_id: row.try_get("last_message_id")?,
+        unread_count: row.try_get::<i64, _>("unread_count")? as u64,
+        mention_count: row.try_get::<i64, _>("mention_count")? as u64,
+    })
+}
+
+impl ReadState {
+    pub async fn from_channel(user_id: Snowflake, channel_id: Snowflake, pool: &Pool<MySql>) -> Result<ReadState, sqlx::error::Error> {
+        let q = sqlx::query(&format!("{SELECT_READ_STATE} WHERE cm.`user_id`=? AND cm.`channel_id`=?;"))
+            .bind(user_id)
+            .bind(channel_id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    /// Read states of every channel and thread the user is a member of.
+    pub async fn list(user_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<ReadState>, sqlx::error::Error> {
+        let q = sqlx::query(&format!("{SELECT_READ_STATE} WHERE cm.`user_id`=?;"))
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    /// Marks messages up to `message_id` as read, never moving backward.
+    pub async fn ack(user_id: Snowflake, channel_id: Snowflake, message_id: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("INSERT INTO read_states (`user_id`, `channel_id`, `last_read_id`, `updated_at`) VALUES (?, ?, ?, ?) \
+            ON DUPLICATE KEY UPDATE `last_read_id`=GREATEST(`last_read_id`, VALUES(`last_read_id`)), `updated_at`=VALUES(`updated_at`);")
+            .bind(user_id)
+            .bind(channel_id)
+            .bind(message_id)
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The new read state is also sent to every gateway session of the user, so their other clients clear the badge.
+#[post("/channels/<id>/ack", data = "<input>")]
+async fn ack_channel(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, input: Option<Json<AckInput>>) -> Result<Json<ReadState>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+    let state = ReadState::from_channel(user.id, channel.id, pool).await?;
+
+    let message_id = match input.and_then(|input| input.into_inner().message_id) {
+        Some(message_id) => Message::from_id(channel.id, message_id, pool).await?.id,
+        None => match state.last_message_id {
+            Some(message_id) => message_id,
+            None => return Ok(Json(state)),
+        },
+    };
+
+    ReadState::ack(user.id, channel.id, message_id, pool).await?;
+    let state = ReadState::from_channel(user.id, channel.id, pool).await?;
+    bus.publish_to_user(user.id, Event::ReadStateUpdate(state.clone()));
+    Ok(Json(state))
+}
+
+#[get("/users/@me/read-states")]
+async fn get_read_states(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<ReadState>>, Error> {
+    check_session(&user)?;
+    Ok(Json(ReadState::list(user.id, pool).await?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![ack_channel, get_read_states]
+}
@@ -0,0 +1,368 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::check_session,
+    audit::{AuditAction, AuditEntry},
+    cdn::CdnId,
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    members::Member,
+    messages::Message,
+    moderation::{self, BanInput},
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const MAX_COMMENT_LENGTH: usize = 1000;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReportTarget {
+    Message { message_id: Snowflake },
+    User { user_id: Snowflake },
+    File { hash: CdnId },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportCategory {
+    Spam,
+    Harassment,
+    Hate,
+    Nsfw,
+    Violence,
+    Other,
+}
+
+impl ReportCategory {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "spam" => Some(Self::Spam),
+            "harassment" => Some(Self::Harassment),
+            "hate" => Some(Self::Hate),
+            "nsfw" => Some(Self::Nsfw),
+            "violence" => Some(Self::Violence),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Spam => "spam",
+            Self::Harassment => "harassment",
+            Self::Hate => "hate",
+            Self::Nsfw => "nsfw",
+            Self::Violence => "violence",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Dismiss,
+    DeleteContent,
+    Ban,
+}
+
+impl Resolution {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "dismiss" => Some(Self::Dismiss),
+            "delete_content" => Some(Self::DeleteContent),
+            "ban" => Some(Self::Ban),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dismiss => "dismiss",
+            Self::DeleteContent => "delete_content",
+            Self::Ban => "ban",
+        }
+    }
+}
+
+/// Reports go to the queue of the channel the content was seen in, or of its parent for a thread.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Report {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    /// Where the content was reported from, a thread of `channel_id` or the channel itself.
+    pub origin_channel_id: Snowflake,
+    pub reporter_id: Snowflake,
+    pub target: ReportTarget,
+    /// The author of the message or the reported user, `None` for files.
+    pub target_user_id: Option<Snowflake>,
+    pub category: ReportCategory,
+    pub comment: Option<String>,
+    /// The content as it was when reported, kept if it is deleted since.
+    pub snapshot: serde_json::Value,
+    /// `None` while the report is open.
+    pub resolution: Option<Resolution>,
+    pub resolved_by: Option<Snowflake>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ReportInput {
+    pub channel_id: Snowflake,
+    pub target: ReportTarget,
+    pub category: ReportCategory,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ResolveInput {
+    pub action: Resolution,
+    pub reason: Option<String>,
+    /// Only used when banning.
+    #[serde(default)]
+    pub delete_message_seconds: u64,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Report, sqlx::error::Error> {
+    let decode = |column: &str| sqlx::Error::ColumnDecode { index: column.to_string(), source: "invalid report".into() };
+
+    Ok(Report {
+        id: row.try_get("id")?,
+        channel_id: row.try_get("channel_id")?,
+        origin_channel_id: row.try_get("origin_channel_id")?,
+        reporter_id: row.try_get("reporter_id")?,
+        target: serde_json::from_str(&row.try_get::<String, _>("target")?).map_err(|_| decode("target"))?,
+        target_user_id: row.try_get("target_user_id")?,
+        category: ReportCategory::parse(&row.try_get::<String, _>("category")?).unwrap_or(ReportCategory::Other),
+        comment: row.try_get("comment")?,
+        snapshot: serde_json::from_str(&row.try_get::<String, _>("snapshot")?).unwrap_or_default(),
+        resolution: row.try_get::<Option<String>, _>("resolution")?.and_then(|r| Resolution::parse(&r)),
+        resolved_by: row.try_get("resolved_by")?,
+        resolved_at: row.try_get("resolved_at")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+impl Report {
+    pub async fn from_id(channel_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Report, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM reports WHERE `channel_id`=? AND `id`=?;")
+            .bind(channel_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    /// Newest first.
+    pub async fn list(channel_id: Snowflake, resolved: bool, before: Option<Snowflake>, limit: u32, pool: &Pool<MySql>) -> Result<Vec<Report>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM reports WHERE `channel_id`=? AND (`resolution` IS NOT NULL)=? AND `id`<? ORDER BY `id` DESC LIMIT ?;")
+            .bind(channel_id)
+            .bind(resolved)
+            .bind(before.map(Snowflake::get).unwrap_or(u64::MAX))
+            .bind(limit)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    pub async fn create(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("INSERT INTO reports (`id`, `channel_id`, `origin_channel_id`, `reporter_id`, `target`, `target_user_id`, `category`, `comment`, `snapshot`, `created_at`) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?);")
+            .bind(self.id)
+            .bind(self.channel_id)
+            .bind(self.origin_channel_id)
+            .bind(self.reporter_id)
+            .bind(serde_json::to_string(&self.target).unwrap_or_default())
+            .bind(self.target_user_id)
+            .bind(self.category.as_str())
+            .bind(&self.comment)
+            .bind(self.snapshot.to_string())
+            .bind(self.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Closes every open report on the same content, so duplicates leave the queue together.
+    pub async fn resolve(&mut self, resolution: Resolution, resolved_by: Snowflake, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let now = Utc::now();
+        sqlx::query("UPDATE reports SET `resolution`=?, `resolved_by`=?, `resolved_at`=? WHERE `channel_id`=? AND `target`=? AND `resolution` IS NULL;")
+            .bind(resolution.as_str())
+            .bind(resolved_by)
+            .bind(now)
+            .bind(self.channel_id)
+            .bind(serde_json::to_string(&self.target).unwrap_or_default())
+            .execute(pool)
+            .await?;
+
+        self.resolution = Some(resolution);
+        self.resolved_by = Some(resolved_by);
+        self.resolved_at = Some(now);
+        Ok(())
+    }
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::new(Status::BadRequest, message.to_string(), "Check the body of your request".to_string())
+}
+
+/// Checks the reported content exists in the channel, returns its author and a snapshot of it.
+async fn snapshot(channel: &Channel, reporter: &User, target: &ReportTarget, pool: &Pool<MySql>) -> Result<(Option<Snowflake>, serde_json::Value), Error> {
+    match target {
+        ReportTarget::Message { message_id } => {
+            let message = Message::from_id(channel.id, *message_id, pool).await?;
+            if message.author_id == reporter.id { return Err(bad_request("You cannot report your own message")) }
+            // A webhook message has no user behind it to ban
+            let author_id = message.webhook.is_none().then_some(message.author_id);
+            Ok((author_id, serde_json::to_value(&message).unwrap_or_default()))
+        },
+        ReportTarget::User { user_id } => {
+            if *user_id == reporter.id { return Err(bad_request("You cannot report yourself")) }
+            if !Member::is_member(channel.parent_id.unwrap_or(channel.id), *user_id, pool).await? {
+                return Err(Error::new(Status::NotFound, "Unknown member".to_string(), "Only members of the channel can be reported".to_string()));
+            }
+            let user = User::from_id(*user_id, pool).await?;
+            Ok((Some(user.id), serde_json::to_value(&user).unwrap_or_default()))
+        },
+        ReportTarget::File { hash } => {
+            if !hash.exists(pool).await? {
+                return Err(bad_request(&format!("No CDN file found with hash {}", hash.as_str())));
+            }
+            Ok((None, serde_json::json!({ "hash": hash, "url": hash.url(pool).await? })))
+        },
+    }
+}
+
+/// Detaches a file from the messages of a channel and its threads, the file itself stays on the CDN.
+async fn detach_file(channel_id: Snowflake, hash: &CdnId, pool: &Pool<MySql>, bus: &Bus) -> Result<(), Error> {
+    let q = sqlx::query("SELECT DISTINCT m.`id`, m.`channel_id` FROM message_attachments a JOIN messages m ON m.`id`=a.`message_id` \
+        WHERE a.`hash`=? AND m.`deleted_at` IS NULL AND (m.`channel_id`=? OR m.`channel_id` IN (SELECT `channel_id` FROM threads WHERE `parent_id`=?));")
+        .bind(hash.as_str())
+        .bind(channel_id)
+        .bind(channel_id)
+        .fetch_all(pool)
+        .await?;
+
+    for row in &q {
+        let (message_id, message_channel_id): (Snowflake, Snowflake) = (row.try_get("id")?, row.try_get("channel_id")?);
+        sqlx::query("DELETE FROM message_attachments WHERE `message_id`=? AND `hash`=?;")
+            .bind(message_id)
+            .bind(hash.as_str())
+            .execute(pool)
+            .await?;
+        bus.publish(message_channel_id, Event::MessageUpdate(Message::from_id(message_channel_id, message_id, pool).await?));
+    }
+
+    Ok(())
+}
+
+#[post("/reports", data = "<input>")]
+async fn create_report(pool: &State<Pool<MySql>>, user: User, input: Json<ReportInput>) -> Result<Json<Report>, Error> {
+    check_session(&user)?;
+    let input = input.into_inner();
+    let origin = Channel::from_id(input.channel_id, pool).await?;
+    check_permission(&user, &origin, Permissions::READ, pool).await?;
+    if input.comment.as_ref().is_some_and(|comment| comment.chars().count() > MAX_COMMENT_LENGTH) {
+        return Err(bad_request(&format!("A comment must be at most {MAX_COMMENT_LENGTH} caracters")));
+    }
+
+    let channel_id = origin.parent_id.unwrap_or(origin.id);
+    let (target_user_id, snapshot) = snapshot(&origin, &user, &input.target, pool).await?;
+    let id = ids::generate();
+    let report = Report {
+        id,
+        channel_id,
+        origin_channel_id: origin.id,
+        reporter_id: user.id,
+        target: input.target,
+        target_user_id,
+        category: input.category,
+        comment: input.comment.filter(|comment| !comment.trim().is_empty()),
+        snapshot,
+        resolution: None,
+        resolved_by: None,
+        resolved_at: None,
+        created_at: id.created_at(),
+    };
+    // The `reports_open` key rejects a second open report on the same content
+    match report.create(pool).await {
+        Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("23000") => {
+            return Err(Error::new(Status::Conflict, "You already reported this".to_string(), "Wait for a moderator to review your report".to_string()));
+        },
+        result => result?,
+    }
+
+    Ok(Json(report))
+}
+
+/// Open reports by default, `resolved=true` lists the closed ones.
+#[get("/channels/<id>/reports?<resolved>&<before>&<limit>")]
+async fn get_reports(pool: &State<Pool<MySql>>, user: User, id: Snowflake, resolved: Option<bool>, before: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<Report>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    Ok(Json(Report::list(channel.id, resolved.unwrap_or(false), before, limit, pool).await?))
+}
+
+#[get("/channels/<id>/reports/<report_id>")]
+async fn get_report(pool: &State<Pool<MySql>>, user: User, id: Snowflake, report_id: Snowflake) -> Result<Json<Report>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_MESSAGES, pool).await?;
+
+    Ok(Json(Report::from_id(channel.id, report_id, pool).await?))
+}
+
+#[post("/channels/<id>/reports/<report_id>/resolve", data = "<input>")]
+async fn resolve_report(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, report_id: Snowflake, input: Json<ResolveInput>) -> Result<Json<Report>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    let required = if input.action == Resolution::Ban { Permissions::MANAGE_MESSAGES | Permissions::BAN } else { Permissions::MANAGE_MESSAGES };
+    check_permission(&user, &channel, required, pool).await?;
+    let mut report = Report::from_id(channel.id, report_id, pool).await?;
+    if report.resolution.is_some() {
+        return Err(Error::new(Status::Conflict, "This report is already resolved".to_string(), "Refresh the queue".to_string()));
+    }
+    let input = input.into_inner();
+
+    match (input.action, &report.target) {
+        (Resolution::Dismiss, _) => (),
+        (Resolution::DeleteContent, ReportTarget::Message { message_id }) => {
+            // it may have been deleted since
+            if let Ok(message) = Message::from_id(report.origin_channel_id, *message_id, pool).await {
+                message.delete(pool).await?;
+                bus.publish(message.channel_id, Event::MessageDelete { channel_id: message.channel_id, id: message.id });
+                AuditEntry::record(channel.id, user.id, AuditAction::MessageDelete, Some(message.author_id), input.reason.clone(), Some(serde_json::json!({ "message_id": message.id })), pool).await?;
+            }
+        },
+        (Resolution::DeleteContent, ReportTarget::File { hash }) => detach_file(channel.id, hash, pool, bus).await?,
+        (Resolution::DeleteContent, ReportTarget::User { .. }) => return Err(bad_request("A user has no content to delete, ban them instead")),
+        (Resolution::Ban, _) => {
+            let Some(target_id) = report.target_user_id else { return Err(bad_request("Nobody can be banned for a file report")) };
+            if !Member::is_member(channel.id, target_id, pool).await? {
+                return Err(Error::new(Status::NotFound, "Unknown member".to_string(), "The reported user already left the channel".to_string()));
+            }
+            let target = User::from_id(target_id, pool).await?;
+            let ban = BanInput { reason: input.reason.clone(), delete_message_seconds: input.delete_message_seconds };
+            moderation::ban(&channel, &user, &target, ban, pool, bus).await?;
+        },
+    }
+
+    report.resolve(input.action, user.id, pool).await?;
+    AuditEntry::record(channel.id, user.id, AuditAction::ReportResolve, report.target_user_id, input.reason, Some(serde_json::json!({ "report_id": report.id, "resolution": input.action })), pool).await?;
+    Ok(Json(report))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create_report, get_reports, get_report, resolve_report]
+}
@@ -0,0 +1,279 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::{fairing::AdHoc, http::Status, serde::json::Json, tokio, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::{check_not_token, check_scope, Scope},
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    messages::{self, Message, MessageInput},
+    users::User,
+};
+
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+const BATCH_SIZE: u32 = 50;
+/// A job taking longer than this is considered crashed and picked up again.
+const LOCK_DURATION: i64 = 60;
+const MAX_ATTEMPTS: u32 = 5;
+/// 30 days.
+const MAX_SCHEDULE_DELAY: i64 = 30 * 24 * 3600;
+
+/// Work done later by the scheduler, persisted in MySQL so it survives restarts.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Job {
+    SendMessage { channel_id: Snowflake, author_id: Snowflake, message: Box<MessageInput> },
+    DeleteMessage { channel_id: Snowflake, message_id: Snowflake },
+}
+
+impl Job {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::SendMessage { .. } => "send_message",
+            Self::DeleteMessage { .. } => "delete_message",
+        }
+    }
+
+    fn channel_id(&self) -> Snowflake {
+        match self {
+            Self::SendMessage { channel_id, .. } | Self::DeleteMessage { channel_id, .. } => *channel_id,
+        }
+    }
+
+    fn user_id(&self) -> Option<Snowflake> {
+        match self {
+            Self::SendMessage { author_id, .. } => Some(*author_id),
+            Self::DeleteMessage { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ScheduledMessage {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub scheduled_at: DateTime<Utc>,
+    pub message: MessageInput,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ScheduledMessageInput {
+    pub scheduled_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub message: MessageInput,
+}
+
+pub async fn schedule(job: &Job, run_at: DateTime<Utc>, pool: &Pool<MySql>) -> Result<Snowflake, sqlx::error::Error> {
+    let id = ids::generate();
+    sqlx::query("INSERT INTO jobs (`id`, `kind`, `channel_id`, `user_id`, `payload`, `run_at`) VALUES (?, ?, ?, ?, ?, ?);")
+        .bind(id)
+        .bind(job.kind())
+        .bind(job.channel_id())
+        .bind(job.user_id())
+        .bind(serde_json::to_string(job).unwrap_or_default())
+        .bind(run_at)
+        .execute(pool)
+        .await?;
+
+    Ok(id)
+}
+
+/// Why a job failed, and whether running it again may help.
+enum Failure {
+    Retry(String),
+    Drop(String),
+}
+
+impl From<sqlx::Error> for Failure {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Retry(err.to_string())
+    }
+}
+
+fn gone(err: sqlx::Error) -> Failure {
+    match err {
+        sqlx::Error::RowNotFound => Failure::Drop(err.to_string()),
+        err => err.into(),
+    }
+}
+
+async fn run(job: Job, pool: &Pool<MySql>, bus: &Bus) -> Result<(), Failure> {
+    match job {
+        Job::SendMessage { channel_id, author_id, message } => {
+            // the channel or the author is gone, retrying will not bring them back
+            let channel = Channel::from_id(channel_id, pool).await.map_err(gone)?;
+            let author = User::from_id(author_id, pool).await.map_err(gone)?;
+            // permissions are checked again, they may have changed since
+            match messages::send(&channel, &author, *message, pool, bus).await {
+                Ok(_) => Ok(()),
+                // a slowmode wait is retried like a server error
+                Err(e) if e.status().code >= 500 || e.status().code == 429 => Err(Failure::Retry(format!("{e:?}"))),
+                Err(e) => Err(Failure::Drop(format!("{e:?}"))),
+            }
+        },
+        Job::DeleteMessage { channel_id, message_id } => {
+            let row = sqlx::query("SELECT `deleted_at` FROM messages WHERE `channel_id`=? AND `id`=?;")
+                .bind(channel_id)
+                .bind(message_id)
+                .fetch_optional(pool)
+                .await?;
+            let Some(row) = row else { return Ok(()) };
+
+            // a message soft deleted by someone is still purged, its attachments must be freed
+            Message::purge(message_id, pool).await?;
+            if row.try_get::<Option<DateTime<Utc>>, _>("deleted_at")?.is_none() {
+                bus.publish(channel_id, Event::MessageDelete { channel_id, id: message_id });
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Locks a due job so no other instance runs it at the same time.
+async fn claim(id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let now = Utc::now();
+    let q = sqlx::query("UPDATE jobs SET `locked_until`=? WHERE `id`=? AND (`locked_until` IS NULL OR `locked_until`<?);")
+        .bind(now + Duration::seconds(LOCK_DURATION))
+        .bind(id)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(q.rows_affected() > 0)
+}
+
+async fn finish(row: &MySqlRow, result: Result<(), Failure>, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    let id: Snowflake = row.try_get("id")?;
+    let attempts = row.try_get::<u32, _>("attempts")? + 1;
+
+    match result {
+        Ok(()) => {},
+        Err(Failure::Retry(error)) if attempts < MAX_ATTEMPTS => {
+            // 10s, 20s, 40s...
+            let delay = Duration::seconds(10 << (attempts - 1));
+            sqlx::query("UPDATE jobs SET `attempts`=?, `run_at`=?, `locked_until`=NULL, `last_error`=? WHERE `id`=?;")
+                .bind(attempts)
+                .bind(Utc::now() + delay)
+                .bind(error)
+                .bind(id)
+                .execute(pool)
+                .await?;
+            return Ok(());
+        },
+        Err(Failure::Retry(error)) | Err(Failure::Drop(error)) => {
+            eprintln!("\x1b[31mJob {id} dropped after {attempts} attempt(s): {error}\x1b[0m");
+        },
+    }
+
+    sqlx::query("DELETE FROM jobs WHERE `id`=?;")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn run_due(pool: &Pool<MySql>, bus: &Bus) -> Result<(), sqlx::error::Error> {
+    let now = Utc::now();
+    let q = sqlx::query("SELECT * FROM jobs WHERE `run_at`<=? AND (`locked_until` IS NULL OR `locked_until`<?) ORDER BY `run_at` ASC LIMIT ?;")
+        .bind(now)
+        .bind(now)
+        .bind(BATCH_SIZE)
+        .fetch_all(pool)
+        .await?;
+
+    for row in &q {
+        if !claim(row.try_get("id")?, pool).await? { continue }
+
+        let result = match serde_json::from_str::<Job>(&row.try_get::<String, _>("payload")?) {
+            Ok(job) => run(job, pool, bus).await,
+            Err(e) => Err(Failure::Drop(format!("invalid payload: {e}"))),
+        };
+        finish(row, result, pool).await?;
+    }
+
+    Ok(())
+}
+
+/// Background task running due jobs.
+pub fn runner() -> AdHoc {
+    AdHoc::on_liftoff("Job scheduler", |rocket| Box::pin(async move {
+        let (Some(pool), Some(bus)) = (rocket.state::<Pool<MySql>>(), rocket.state::<Bus>()) else { return };
+        let (pool, bus) = (pool.clone(), bus.clone());
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = run_due(&pool, &bus).await {
+                    eprintln!("\x1b[31mCannot run scheduled jobs: {e}\x1b[0m");
+                }
+            }
+        });
+    }))
+}
+
+fn scheduled_from_row(row: &MySqlRow) -> Result<Option<ScheduledMessage>, sqlx::error::Error> {
+    let job = serde_json::from_str::<Job>(&row.try_get::<String, _>("payload")?).ok();
+    let Some(Job::SendMessage { channel_id, message, .. }) = job else { return Ok(None) };
+
+    Ok(Some(ScheduledMessage { id: row.try_get("id")?, channel_id, scheduled_at: row.try_get("run_at")?, message: *message }))
+}
+
+#[post("/channels/<id>/scheduled-messages", data = "<input>")]
+async fn schedule_message(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<ScheduledMessageInput>) -> Result<Json<ScheduledMessage>, Error> {
+    // the job runs as the author rebuilt from their id, which would outlive a revoked OAuth2 grant
+    check_not_token(&user)?;
+    let channel = Channel::from_id(id, pool).await?;
+    let input = input.into_inner();
+    let delay = input.scheduled_at - Utc::now();
+    if delay <= Duration::zero() || delay > Duration::seconds(MAX_SCHEDULE_DELAY) {
+        return Err(Error::new(Status::BadRequest, "A message must be scheduled in the next 30 days".to_string(), "Check scheduled_at".to_string()));
+    }
+    messages::check_message(&channel, &user, &input.message, pool).await?;
+
+    let job = Job::SendMessage { channel_id: channel.id, author_id: user.id, message: Box::new(input.message.clone()) };
+    let id = schedule(&job, input.scheduled_at, pool).await?;
+
+    Ok(Json(ScheduledMessage { id, channel_id: channel.id, scheduled_at: input.scheduled_at, message: input.message }))
+}
+
+/// Messages the user scheduled in the channel.
+#[get("/channels/<id>/scheduled-messages")]
+async fn get_scheduled_messages(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<ScheduledMessage>>, Error> {
+    check_scope(&user, Scope::MessagesWrite)?;
+    let q = sqlx::query("SELECT `id`, `payload`, `run_at` FROM jobs WHERE `channel_id`=? AND `kind`='send_message' AND `user_id`=? ORDER BY `run_at` ASC;")
+        .bind(id)
+        .bind(user.id)
+        .fetch_all(pool.inner())
+        .await?;
+
+    let mut scheduled = Vec::with_capacity(q.len());
+    for row in &q {
+        if let Some(message) = scheduled_from_row(row)? { scheduled.push(message) }
+    }
+    Ok(Json(scheduled))
+}
+
+#[delete("/channels/<id>/scheduled-messages/<sid>")]
+async fn cancel_scheduled_message(pool: &State<Pool<MySql>>, user: User, id: Snowflake, sid: Snowflake) -> Result<Status, Error> {
+    check_scope(&user, Scope::MessagesWrite)?;
+    // a job already running cannot be cancelled
+    let q = sqlx::query("DELETE FROM jobs WHERE `id`=? AND `channel_id`=? AND `kind`='send_message' AND `user_id`=? AND (`locked_until` IS NULL OR `locked_until`<?);")
+        .bind(sid)
+        .bind(id)
+        .bind(user.id)
+        .bind(Utc::now())
+        .execute(pool.inner())
+        .await?;
+
+    if q.rows_affected() == 0 { return Err(sqlx::Error::RowNotFound.into()) }
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![schedule_message, get_scheduled_messages, cancel_scheduled_message]
+}
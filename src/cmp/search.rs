@@ -0,0 +1,184 @@
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{MySql, Pool, QueryBuilder, Row};
+
+use super::{
+    channels::Channel,
+    errors::Error,
+    ids::Snowflake,
+    messages::{self, Message, SELECT_MESSAGE},
+    permissions::{self, check_permission, Permissions},
+    users::User,
+};
+
+const MAX_QUERY_LENGTH: usize = 200;
+const DEFAULT_PAGE_SIZE: u32 = 25;
+const MAX_PAGE_SIZE: u32 = 100;
+/// Caracters of context kept before the first match.
+const SNIPPET_CONTEXT: usize = 60;
+const SNIPPET_LENGTH: usize = 200;
+
+#[derive(Debug, serde::Serialize)]
+pub struct SearchResult {
+    pub message: Message,
+    /// HTML escaped excerpt with matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    /// Pass it back as `cursor` for the next page, `None` on the last one.
+    pub cursor: Option<Snowflake>,
+}
+
+fn bad_request(message: &str) -> Error {
+    Error::new(Status::BadRequest, message.to_string(), "Check the parameters of your request".to_string())
+}
+
+/// Channels the user can read: the ones they are a member of and the threads in them.
+async fn readable_channels(user: &User, pool: &Pool<MySql>) -> Result<Vec<Snowflake>, sqlx::error::Error> {
+    let mut channels = permissions::compute_all(user.id, pool).await?
+        .into_iter()
+        .filter(|(_, perms)| (*perms & user.allowed_permissions()).contains(Permissions::READ))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    if channels.is_empty() { return Ok(channels) }
+
+    let mut threads = QueryBuilder::<MySql>::new("SELECT `channel_id` FROM threads WHERE `parent_id` IN (");
+    let mut parents = threads.separated(", ");
+    for id in &channels { parents.push_bind(*id); }
+    threads.push(");");
+    for row in threads.build().fetch_all(pool).await? {
+        channels.push(row.try_get("channel_id")?);
+    }
+    Ok(channels)
+}
+
+/// Lowercased words of the query, used to highlight the matches.
+fn terms(query: &str) -> Vec<Vec<char>> {
+    query.split_whitespace()
+        .map(|word| word.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect::<Vec<_>>())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+fn push_escaped(out: &mut String, chars: &[char]) {
+    for c in chars {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(*c),
+        }
+    }
+}
+
+/// Excerpt of the content around the first match, with every match highlighted.
+fn snippet(content: &str, terms: &[Vec<char>]) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    // one char per char so indexes stay aligned with `chars`
+    let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+
+    let mut matches = Vec::new();
+    let mut i = 0;
+    while i < lower.len() {
+        match terms.iter().find(|term| lower[i..].starts_with(term)) {
+            Some(term) => {
+                matches.push((i, i + term.len()));
+                i += term.len();
+            },
+            None => i += 1,
+        }
+    }
+
+    let start = matches.first().map(|(start, _)| start.saturating_sub(SNIPPET_CONTEXT)).unwrap_or(0);
+    let end = (start + SNIPPET_LENGTH).min(chars.len());
+
+    let mut out = String::new();
+    if start > 0 { out.push('…') }
+    let mut position = start;
+    for (from, to) in matches.into_iter().filter(|(from, to)| *from >= start && *to <= end) {
+        push_escaped(&mut out, &chars[position..from]);
+        out.push_str("<mark>");
+        push_escaped(&mut out, &chars[from..to]);
+        out.push_str("</mark>");
+        position = to;
+    }
+    push_escaped(&mut out, &chars[position..end]);
+    if end < chars.len() { out.push('…') }
+
+    out
+}
+
+/// Newest messages first. `before` and `after` are message IDs, which sort by creation time.
+#[allow(clippy::too_many_arguments)]
+#[get("/search/messages?<q>&<channel>&<author>&<has>&<before>&<after>&<cursor>&<limit>")]
+async fn search_messages(pool: &State<Pool<MySql>>, user: User, q: Option<String>, channel: Option<Snowflake>, author: Option<Snowflake>, has: Option<String>, before: Option<Snowflake>, after: Option<Snowflake>, cursor: Option<Snowflake>, limit: Option<u32>) -> Result<Json<SearchPage>, Error> {
+    let q = q.map(|q| q.trim().to_string()).filter(|q| !q.is_empty());
+    if let Some(q) = &q {
+        if q.chars().count() > MAX_QUERY_LENGTH {
+            return Err(bad_request(&format!("A search cannot be longer than {MAX_QUERY_LENGTH} caracters")));
+        }
+    }
+    if q.is_none() && author.is_none() && has.is_none() {
+        return Err(bad_request("A search needs a query, an author or a has filter"));
+    }
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let channels = match channel {
+        Some(id) => {
+            let channel = Channel::from_id(id, pool).await?;
+            check_permission(&user, &channel, Permissions::READ, pool).await?;
+            vec![channel.id]
+        },
+        None => readable_channels(&user, pool).await?,
+    };
+    if channels.is_empty() { return Ok(Json(SearchPage { results: Vec::new(), cursor: None })) }
+
+    let mut query = QueryBuilder::<MySql>::new(SELECT_MESSAGE);
+    query.push(" WHERE m.`deleted_at` IS NULL AND m.`channel_id` IN (");
+    let mut separated = query.separated(", ");
+    for id in &channels {
+        separated.push_bind(*id);
+    }
+    query.push(")");
+
+    if let Some(q) = &q {
+        query.push(" AND MATCH(m.`content`) AGAINST (").push_bind(q.as_str()).push(" IN NATURAL LANGUAGE MODE)");
+    }
+    if let Some(author) = author {
+        query.push(" AND m.`author_id`=").push_bind(author);
+    }
+    match has.as_deref() {
+        None => {},
+        Some("attachment") => { query.push(" AND EXISTS (SELECT 1 FROM message_attachments ha WHERE ha.`message_id`=m.`id`)"); },
+        Some("link") => { query.push(" AND (m.`content` LIKE '%http://%' OR m.`content` LIKE '%https://%')"); },
+        Some(other) => return Err(bad_request(&format!("Unknown has filter {other}, use attachment or link"))),
+    }
+    // the cursor is the last message of the previous page
+    if let Some(before) = [before, cursor].into_iter().flatten().min() {
+        query.push(" AND m.`id`<").push_bind(before);
+    }
+    if let Some(after) = after {
+        query.push(" AND m.`id`>").push_bind(after);
+    }
+    query.push(" GROUP BY m.`id` ORDER BY m.`id` DESC LIMIT ").push_bind(limit);
+
+    let rows = query.build().fetch_all(pool.inner()).await?;
+    let mut found = rows.iter().map(messages::from_row).collect::<Result<Vec<_>, _>>()?;
+
+    messages::fill(&mut found, pool).await?;
+
+    let terms = q.as_deref().map(terms).unwrap_or_default();
+    let cursor = if found.len() as u32 == limit { found.last().map(|m| m.id) } else { None };
+    let results = found.into_iter()
+        .map(|message| SearchResult { snippet: snippet(&message.content, &terms), message })
+        .collect();
+
+    Ok(Json(SearchPage { results, cursor }))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![search_messages]
+}
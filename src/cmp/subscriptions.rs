@@ -0,0 +1,897 @@
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rocket::futures::future::join_all;
+use rocket::tokio::{self, select, sync::{broadcast::error::RecvError, Notify}};
+use rocket::{fairing::AdHoc, http::Status, serde::json::Json, Route, State};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    channels::Channel,
+    errors::Error,
+    gateway::{Bus, Dispatch, Event},
+    ids::{self, Snowflake},
+    outgoing::Outgoing,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+const SECRET_LENGTH: usize = 32;
+const MAX_SUBSCRIPTIONS: i64 = 10;
+const MAX_URL_LENGTH: usize = 2048;
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+const BATCH_SIZE: u32 = 50;
+/// Longer than `REQUEST_TIMEOUT`, a delivery still locked after that has crashed.
+const LOCK_DURATION: i64 = 60;
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+/// 30s, 1m, 2m... about an hour between the first and the last attempt.
+const MAX_ATTEMPTS: u32 = 8;
+const RETRY_BASE_DELAY: i64 = 30;
+/// Failed deliveries in a row before the subscription is disabled.
+const MAX_FAILURES: u32 = 5;
+/// Kept from the response of the receiver for the delivery logs, enough for an error message.
+const MAX_LOGGED_BODY: usize = 100;
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    MessageCreate,
+    MemberJoin,
+    /// A message was sent with attachments.
+    FileUpload,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MessageCreate => "message_create",
+            Self::MemberJoin => "member_join",
+            Self::FileUpload => "file_upload",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    #[field(value = "pending")]
+    Pending,
+    #[field(value = "succeeded")]
+    Succeeded,
+    #[field(value = "failed")]
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn parse(source: &str) -> Option<Self> {
+        match source {
+            "pending" => Some(Self::Pending),
+            "succeeded" => Some(Self::Succeeded),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Events of a channel and its threads, POSTed as JSON to an external URL.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Subscription {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub url: String,
+    pub events: Vec<EventType>,
+    pub enabled: bool,
+    /// Failed deliveries in a row.
+    pub failure_count: u32,
+    /// Set when the subscription was disabled automatically.
+    pub disabled_reason: Option<String>,
+    pub creator_id: Snowflake,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Only returned when the subscription is created or its secret rotated.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SubscriptionWithSecret {
+    #[serde(flatten)]
+    pub subscription: Subscription,
+    /// Key of the HMAC-SHA256 in `X-Helix-Signature`.
+    pub secret: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SubscriptionInput {
+    pub url: String,
+    pub events: Vec<EventType>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SubscriptionPatch {
+    pub url: Option<String>,
+    pub events: Option<Vec<EventType>>,
+    /// Enabling again resets the failure count.
+    pub enabled: Option<bool>,
+}
+
+/// One event sent to one subscription, with the result of its last attempt.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Delivery {
+    pub id: Snowflake,
+    pub subscription_id: Snowflake,
+    pub event: EventType,
+    /// Body sent to the receiver.
+    pub payload: Value,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub response_status: Option<u16>,
+    pub response_body: Option<String>,
+    pub last_error: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Subscription, sqlx::error::Error> {
+    let events = serde_json::from_str(&row.try_get::<String, _>("events")?)
+        .map_err(|_| sqlx::Error::ColumnDecode { index: "events".to_string(), source: "invalid subscription events".into() })?;
+
+    Ok(Subscription {
+        id: row.try_get("id")?,
+        channel_id: row.try_get("channel_id")?,
+        url: row.try_get("url")?,
+        events,
+        enabled: row.try_get("enabled")?,
+        failure_count: row.try_get("failure_count")?,
+        disabled_reason: row.try_get("disabled_reason")?,
+        creator_id: row.try_get("creator_id")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn delivery_from_row(row: &MySqlRow) -> Result<Delivery, sqlx::error::Error> {
+    let decode = |column: &str| sqlx::Error::ColumnDecode { index: column.to_string(), source: "invalid event delivery".into() };
+
+    Ok(Delivery {
+        id: row.try_get("id")?,
+        subscription_id: row.try_get("subscription_id")?,
+        event: serde_json::from_value(Value::String(row.try_get("event")?)).map_err(|_| decode("event"))?,
+        payload: serde_json::from_str(&row.try_get::<String, _>("payload")?).map_err(|_| decode("payload"))?,
+        status: DeliveryStatus::parse(&row.try_get::<String, _>("status")?).ok_or_else(|| decode("status"))?,
+        attempts: row.try_get("attempts")?,
+        next_attempt_at: row.try_get("next_attempt_at")?,
+        response_status: row.try_get("response_status")?,
+        response_body: row.try_get("response_body")?,
+        last_error: row.try_get("last_error")?,
+        duration_ms: row.try_get("duration_ms")?,
+        created_at: row.try_get("created_at")?,
+        delivered_at: row.try_get("delivered_at")?,
+    })
+}
+
+impl Subscription {
+    pub async fn from_id(channel_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Subscription, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM event_subscriptions WHERE `channel_id`=? AND `id`=?;")
+            .bind(channel_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Subscription>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM event_subscriptions WHERE `channel_id`=? ORDER BY `id` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    /// Enabled subscriptions receiving the events of `channel_id`, set on the channel itself or on the parent of a thread.
+    async fn listening(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Subscription>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT s.* FROM event_subscriptions s JOIN channels c ON s.`channel_id`=COALESCE(c.`parent_id`, c.`id`) WHERE c.`id`=? AND s.`enabled`;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    pub async fn create(channel_id: Snowflake, creator_id: Snowflake, input: SubscriptionInput, pool: &Pool<MySql>) -> Result<SubscriptionWithSecret, sqlx::error::Error> {
+        let id = ids::generate();
+        let subscription = Subscription {
+            id,
+            channel_id,
+            url: input.url,
+            events: input.events,
+            enabled: true,
+            failure_count: 0,
+            disabled_reason: None,
+            creator_id,
+            created_at: id.created_at(),
+        };
+        let secret = ids::random_string(SECRET_LENGTH);
+
+        sqlx::query("INSERT INTO event_subscriptions (`id`, `channel_id`, `url`, `secret`, `events`, `creator_id`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+            .bind(subscription.id)
+            .bind(subscription.channel_id)
+            .bind(&subscription.url)
+            .bind(&secret)
+            .bind(serde_json::to_string(&subscription.events).unwrap_or_default())
+            .bind(subscription.creator_id)
+            .bind(subscription.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(SubscriptionWithSecret { subscription, secret })
+    }
+
+    pub async fn save(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE event_subscriptions SET `url`=?, `events`=?, `enabled`=?, `failure_count`=?, `disabled_reason`=? WHERE `id`=?;")
+            .bind(&self.url)
+            .bind(serde_json::to_string(&self.events).unwrap_or_default())
+            .bind(self.enabled)
+            .bind(self.failure_count)
+            .bind(&self.disabled_reason)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deliveries still pending are signed with the new secret.
+    pub async fn rotate(self, pool: &Pool<MySql>) -> Result<SubscriptionWithSecret, sqlx::error::Error> {
+        let secret = ids::random_string(SECRET_LENGTH);
+        sqlx::query("UPDATE event_subscriptions SET `secret`=? WHERE `id`=?;")
+            .bind(&secret)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(SubscriptionWithSecret { subscription: self, secret })
+    }
+
+    /// Its delivery logs go with it.
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM event_deliveries WHERE `subscription_id`=?;")
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM event_subscriptions WHERE `id`=?;")
+            .bind(self.id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await
+    }
+}
+
+impl Delivery {
+    pub async fn from_id(subscription_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Delivery, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM event_deliveries WHERE `subscription_id`=? AND `id`=?;")
+            .bind(subscription_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        delivery_from_row(&q)
+    }
+
+    /// Newest first.
+    pub async fn list(subscription_id: Snowflake, status: Option<DeliveryStatus>, before: Option<Snowflake>, limit: u32, pool: &Pool<MySql>) -> Result<Vec<Delivery>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM event_deliveries WHERE `subscription_id`=? AND (? IS NULL OR `status`=?) AND (? IS NULL OR `id`<?) ORDER BY `id` DESC LIMIT ?;")
+            .bind(subscription_id)
+            .bind(status.map(|s| s.as_str()))
+            .bind(status.map(|s| s.as_str()))
+            .bind(before)
+            .bind(before)
+            .bind(limit)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(delivery_from_row).collect()
+    }
+
+    async fn enqueue(subscription_id: Snowflake, event: EventType, channel_id: Snowflake, data: &Value, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let id = ids::generate();
+        let payload = json!({
+            "id": id,
+            "type": event,
+            "channel_id": channel_id,
+            "created_at": id.created_at(),
+            "data": data,
+        });
+
+        sqlx::query("INSERT INTO event_deliveries (`id`, `subscription_id`, `event`, `payload`, `next_attempt_at`, `created_at`) VALUES (?, ?, ?, ?, ?, ?);")
+            .bind(id)
+            .bind(subscription_id)
+            .bind(event.as_str())
+            .bind(payload.to_string())
+            .bind(id.created_at())
+            .bind(id.created_at())
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Queues the delivery again with a fresh set of attempts.
+    pub async fn redeliver(&mut self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        let now = Utc::now();
+        sqlx::query("UPDATE event_deliveries SET `status`='pending', `attempts`=0, `next_attempt_at`=?, `locked_until`=NULL WHERE `id`=?;")
+            .bind(now)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        self.status = DeliveryStatus::Pending;
+        self.attempts = 0;
+        self.next_attempt_at = Some(now);
+        Ok(())
+    }
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`, sent as `X-Helix-Signature: sha256=<hex>`.
+/// Receivers compute it again with the secret and reject old timestamps to prevent replays.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// What the subscriptions get from an event of the bus.
+fn events_of(event: &Event) -> Vec<(EventType, Value)> {
+    match event {
+        Event::MessageCreate(message) => {
+            let mut events = vec![(EventType::MessageCreate, json!(message))];
+            if !message.attachments.is_empty() {
+                events.push((EventType::FileUpload, json!({
+                    "message_id": message.id,
+                    "author_id": message.author_id,
+                    "files": message.attachments,
+                })));
+            }
+            events
+        },
+        Event::MemberJoin { user_id, .. } => vec![(EventType::MemberJoin, json!({ "user_id": user_id }))],
+        _ => Vec::new(),
+    }
+}
+
+/// Queues a delivery for every subscription listening to the dispatched event.
+async fn dispatch(dispatch: &Dispatch, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let Some(channel_id) = dispatch.channel_id else { return Ok(false) };
+    let events = events_of(&dispatch.event);
+    if events.is_empty() { return Ok(false) }
+
+    let mut queued = false;
+    for subscription in Subscription::listening(channel_id, pool).await? {
+        for (event, data) in events.iter().filter(|(event, _)| subscription.events.contains(event)) {
+            Delivery::enqueue(subscription.id, *event, channel_id, data, pool).await?;
+            queued = true;
+        }
+    }
+
+    Ok(queued)
+}
+
+struct Attempt {
+    result: Result<(u16, String), String>,
+    duration_ms: u32,
+}
+
+impl Attempt {
+    fn succeeded(&self) -> bool {
+        matches!(self.result, Ok((status, _)) if (200..300).contains(&status))
+    }
+
+    fn error(&self) -> Option<String> {
+        match &self.result {
+            Ok((status, _)) if !self.succeeded() => Some(format!("The receiver answered with status {status}")),
+            Ok(_) => None,
+            Err(e) => Some(e.clone()),
+        }
+    }
+}
+
+async fn send(outgoing: &Outgoing, url: &str, secret: &str, id: Snowflake, event: &str, payload: String) -> Attempt {
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(secret, timestamp, &payload);
+    let start = Instant::now();
+
+    let request = match outgoing.post(url) {
+        Ok(request) => request,
+        Err(e) => return Attempt { result: Err(e), duration_ms: 0 },
+    };
+    let result = request
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Helix-Delivery", id.to_string())
+        .header("X-Helix-Event", event)
+        .header("X-Helix-Timestamp", timestamp.to_string())
+        .header("X-Helix-Signature", format!("sha256={signature}"))
+        .body(payload)
+        .send()
+        .await;
+    let result = match result {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            Ok((status, body.chars().take(MAX_LOGGED_BODY).collect()))
+        },
+        Err(e) => Err(e.to_string()),
+    };
+
+    Attempt { result, duration_ms: start.elapsed().as_millis().min(u32::MAX as u128) as u32 }
+}
+
+/// Locks a due delivery so no other instance sends it at the same time.
+async fn claim(id: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+    let now = Utc::now();
+    let q = sqlx::query("UPDATE event_deliveries SET `locked_until`=? WHERE `id`=? AND `status`='pending' AND (`locked_until` IS NULL OR `locked_until`<?);")
+        .bind(now + Duration::seconds(LOCK_DURATION))
+        .bind(id)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    Ok(q.rows_affected() > 0)
+}
+
+/// Status of a delivery after its `attempts`-th attempt, and when to try again if it is still pending.
+fn next_state(attempts: u32, succeeded: bool, now: DateTime<Utc>) -> (DeliveryStatus, Option<DateTime<Utc>>) {
+    if succeeded {
+        (DeliveryStatus::Succeeded, None)
+    } else if attempts < MAX_ATTEMPTS {
+        (DeliveryStatus::Pending, Some(now + Duration::seconds(RETRY_BASE_DELAY << (attempts - 1))))
+    } else {
+        (DeliveryStatus::Failed, None)
+    }
+}
+
+/// Whether a subscription with that many failed deliveries in a row is disabled.
+fn disables(failure_count: u32) -> bool {
+    failure_count >= MAX_FAILURES
+}
+
+/// Logs the attempt, then schedules a retry or counts the failure against the subscription.
+async fn finish(id: Snowflake, subscription_id: Snowflake, attempts: u32, attempt: Attempt, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    let now = Utc::now();
+    let (status, next_attempt_at) = next_state(attempts, attempt.succeeded(), now);
+    let delivered_at = (status == DeliveryStatus::Succeeded).then_some(now);
+    let (response_status, response_body) = match &attempt.result {
+        Ok((status, body)) => (Some(*status), Some(body.as_str())),
+        Err(_) => (None, None),
+    };
+
+    sqlx::query("UPDATE event_deliveries SET `status`=?, `attempts`=?, `next_attempt_at`=?, `locked_until`=NULL, `response_status`=?, `response_body`=?, `last_error`=?, `duration_ms`=?, `delivered_at`=? WHERE `id`=?;")
+        .bind(status.as_str())
+        .bind(attempts)
+        .bind(next_attempt_at)
+        .bind(response_status)
+        .bind(response_body)
+        .bind(attempt.error())
+        .bind(attempt.duration_ms)
+        .bind(delivered_at)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    match status {
+        DeliveryStatus::Succeeded => {
+            sqlx::query("UPDATE event_subscriptions SET `failure_count`=0 WHERE `id`=?;")
+                .bind(subscription_id)
+                .execute(pool)
+                .await?;
+        },
+        DeliveryStatus::Failed => {
+            sqlx::query("UPDATE event_subscriptions SET `failure_count`=`failure_count`+1 WHERE `id`=?;")
+                .bind(subscription_id)
+                .execute(pool)
+                .await?;
+            let failure_count: u32 = sqlx::query("SELECT `failure_count` FROM event_subscriptions WHERE `id`=?;")
+                .bind(subscription_id)
+                .fetch_one(pool)
+                .await?
+                .try_get("failure_count")?;
+            if !disables(failure_count) { return Ok(()) }
+
+            let q = sqlx::query("UPDATE event_subscriptions SET `enabled`=FALSE, `disabled_reason`=? WHERE `id`=? AND `enabled`;")
+                .bind(format!("Disabled after {MAX_FAILURES} failed deliveries in a row"))
+                .bind(subscription_id)
+                .execute(pool)
+                .await?;
+            if q.rows_affected() > 0 {
+                eprintln!("\x1b[31mEvent subscription {subscription_id} disabled after {MAX_FAILURES} failed deliveries\x1b[0m");
+            }
+        },
+        DeliveryStatus::Pending => {},
+    }
+
+    Ok(())
+}
+
+/// Sends a claimed delivery, deliveries of a disabled subscription fail without being sent.
+async fn deliver(row: &MySqlRow, outgoing: &Outgoing, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    let id: Snowflake = row.try_get("id")?;
+    let subscription_id: Snowflake = row.try_get("subscription_id")?;
+    let attempts = row.try_get::<u32, _>("attempts")? + 1;
+
+    if !row.try_get::<bool, _>("enabled")? {
+        sqlx::query("UPDATE event_deliveries SET `status`='failed', `next_attempt_at`=NULL, `locked_until`=NULL, `last_error`=? WHERE `id`=?;")
+            .bind("The subscription is disabled")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        return Ok(());
+    }
+
+    let url: String = row.try_get("url")?;
+    let secret: String = row.try_get("secret")?;
+    let attempt = send(outgoing, &url, &secret, id, &row.try_get::<String, _>("event")?, row.try_get("payload")?).await;
+    finish(id, subscription_id, attempts, attempt, pool).await
+}
+
+async fn deliver_due(outgoing: &Outgoing, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+    let now = Utc::now();
+    let q = sqlx::query("SELECT d.`id`, d.`subscription_id`, d.`event`, d.`payload`, d.`attempts`, s.`url`, s.`secret`, s.`enabled` FROM event_deliveries d JOIN event_subscriptions s ON s.`id`=d.`subscription_id` WHERE d.`status`='pending' AND d.`next_attempt_at`<=? AND (d.`locked_until` IS NULL OR d.`locked_until`<?) ORDER BY d.`next_attempt_at` ASC LIMIT ?;")
+        .bind(now)
+        .bind(now)
+        .bind(BATCH_SIZE)
+        .fetch_all(pool)
+        .await?;
+
+    let mut claimed = Vec::with_capacity(q.len());
+    for row in &q {
+        if claim(row.try_get("id")?, pool).await? { claimed.push(row) }
+    }
+
+    // a slow receiver does not hold back the others
+    for result in join_all(claimed.into_iter().map(|row| deliver(row, outgoing, pool))).await {
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Background tasks queuing deliveries from the bus and sending them.
+pub fn runner() -> AdHoc {
+    AdHoc::on_liftoff("Event deliveries", |rocket| Box::pin(async move {
+        let (Some(pool), Some(bus)) = (rocket.state::<Pool<MySql>>(), rocket.state::<Bus>()) else { return };
+        let outgoing = match Outgoing::new(REQUEST_TIMEOUT, Outgoing::allowed_hosts(rocket.figment())) {
+            Ok(outgoing) => outgoing,
+            Err(e) => return eprintln!("\x1b[31mCannot build the event delivery client: {e}\x1b[0m"),
+        };
+        let queued = Arc::new(Notify::new());
+
+        let (pool_, mut events, queued_) = (pool.clone(), bus.subscribe(), queued.clone());
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => match dispatch(&event, &pool_).await {
+                        Ok(true) => queued_.notify_one(),
+                        Ok(false) => {},
+                        Err(e) => eprintln!("\x1b[31mCannot queue event deliveries: {e}\x1b[0m"),
+                    },
+                    Err(RecvError::Lagged(missed)) => eprintln!("\x1b[31mEvent deliveries missed {missed} events\x1b[0m"),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                select! {
+                    _ = interval.tick() => {},
+                    _ = queued.notified() => {},
+                }
+                if let Err(e) = deliver_due(&outgoing, &pool).await {
+                    eprintln!("\x1b[31mCannot send event deliveries: {e}\x1b[0m");
+                }
+            }
+        });
+    }))
+}
+
+fn bad_request(message: String) -> Error {
+    Error::new(Status::BadRequest, message, "Check the body of your request".to_string())
+}
+
+fn check_input(url: &str, events: &mut Vec<EventType>) -> Result<(), Error> {
+    let valid = url.len() <= MAX_URL_LENGTH
+        && (url.starts_with("https://") || url.starts_with("http://"))
+        && reqwest::Url::parse(url).is_ok_and(|url| url.host().is_some());
+    if !valid {
+        return Err(bad_request(format!("A subscription url must be an http link of at most {MAX_URL_LENGTH} caracters")));
+    }
+
+    events.sort_by_key(EventType::as_str);
+    events.dedup();
+    if events.is_empty() {
+        return Err(bad_request("A subscription needs at least one event".to_string()));
+    }
+
+    Ok(())
+}
+
+#[get("/channels/<id>/event-subscriptions")]
+async fn get_subscriptions(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Subscription>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Ok(Json(Subscription::list(channel.id, pool).await?))
+}
+
+#[post("/channels/<id>/event-subscriptions", data = "<input>")]
+async fn create_subscription(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<SubscriptionInput>) -> Result<Json<SubscriptionWithSecret>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    if channel.parent_id.is_some() {
+        return Err(bad_request("Subscribe to the parent channel, it receives the events of its threads".to_string()));
+    }
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let mut input = input.into_inner();
+    check_input(&input.url, &mut input.events)?;
+
+    let count: i64 = sqlx::query("SELECT COUNT(*) AS `count` FROM event_subscriptions WHERE `channel_id`=?;")
+        .bind(channel.id)
+        .fetch_one(pool.inner())
+        .await?
+        .try_get("count")?;
+    if count >= MAX_SUBSCRIPTIONS {
+        return Err(Error::new(Status::BadRequest, format!("A channel can have at most {MAX_SUBSCRIPTIONS} event subscriptions"), "Delete a subscription first".to_string()));
+    }
+
+    Ok(Json(Subscription::create(channel.id, user.id, input, pool).await?))
+}
+
+#[patch("/channels/<id>/event-subscriptions/<sid>", data = "<input>")]
+async fn edit_subscription(pool: &State<Pool<MySql>>, user: User, id: Snowflake, sid: Snowflake, input: Json<SubscriptionPatch>) -> Result<Json<Subscription>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let mut subscription = Subscription::from_id(channel.id, sid, pool).await?;
+    let input = input.into_inner();
+
+    if let Some(url) = input.url { subscription.url = url }
+    if let Some(events) = input.events { subscription.events = events }
+    if let Some(enabled) = input.enabled {
+        if enabled && !subscription.enabled {
+            subscription.failure_count = 0;
+            subscription.disabled_reason = None;
+        }
+        subscription.enabled = enabled;
+    }
+    check_input(&subscription.url, &mut subscription.events)?;
+    subscription.save(pool).await?;
+
+    Ok(Json(subscription))
+}
+
+#[post("/channels/<id>/event-subscriptions/<sid>/secret")]
+async fn rotate_secret(pool: &State<Pool<MySql>>, user: User, id: Snowflake, sid: Snowflake) -> Result<Json<SubscriptionWithSecret>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let subscription = Subscription::from_id(channel.id, sid, pool).await?;
+
+    Ok(Json(subscription.rotate(pool).await?))
+}
+
+#[delete("/channels/<id>/event-subscriptions/<sid>")]
+async fn delete_subscription(pool: &State<Pool<MySql>>, user: User, id: Snowflake, sid: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Subscription::from_id(channel.id, sid, pool).await?.delete(pool).await?;
+    Ok(Status::NoContent)
+}
+
+/// Delivery logs of the subscription.
+#[get("/channels/<id>/event-subscriptions/<sid>/deliveries?<status>&<before>&<limit>")]
+async fn get_deliveries(pool: &State<Pool<MySql>>, user: User, id: Snowflake, sid: Snowflake, status: Option<DeliveryStatus>, before: Option<Snowflake>, limit: Option<u32>) -> Result<Json<Vec<Delivery>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let subscription = Subscription::from_id(channel.id, sid, pool).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    Ok(Json(Delivery::list(subscription.id, status, before, limit, pool).await?))
+}
+
+#[post("/channels/<id>/event-subscriptions/<sid>/deliveries/<did>/redeliver")]
+async fn redeliver(pool: &State<Pool<MySql>>, user: User, id: Snowflake, sid: Snowflake, did: Snowflake) -> Result<Json<Delivery>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let subscription = Subscription::from_id(channel.id, sid, pool).await?;
+    if !subscription.enabled {
+        return Err(Error::new(Status::Conflict, "The subscription is disabled".to_string(), "Enable it again first".to_string()));
+    }
+    let mut delivery = Delivery::from_id(subscription.id, did, pool).await?;
+    if delivery.status == DeliveryStatus::Pending {
+        return Err(Error::new(Status::Conflict, "The delivery is still pending".to_string(), "Wait for its next attempt".to_string()));
+    }
+
+    delivery.redeliver(pool).await?;
+    Ok(Json(delivery))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_subscriptions, create_subscription, edit_subscription, rotate_secret, delete_subscription, get_deliveries, redeliver]
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::TcpListener;
+    use rocket::tokio::sync::mpsc;
+
+    use super::*;
+
+    /// Headers, lowercased, and body of a request the receiver got.
+    type Received = (Vec<(String, String)>, String);
+
+    /// Local HTTP receiver answering every request with `status` and `body`.
+    async fn receiver(status: u16, body: String) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = Vec::new();
+                let mut chunk = [0; 4096];
+                let (headers, length, start) = loop {
+                    let n = socket.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else { continue };
+                    let headers = String::from_utf8_lossy(&buf[..end]).lines().skip(1)
+                        .filter_map(|line| line.split_once(": ").map(|(k, v)| (k.to_lowercase(), v.to_string())))
+                        .collect::<Vec<_>>();
+                    let length = headers.iter().find(|(k, _)| k == "content-length").map(|(_, v)| v.parse().unwrap()).unwrap_or(0);
+                    break (headers, length, end + 4);
+                };
+                while buf.len() < start + length {
+                    let n = socket.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let _ = tx.send((headers, String::from_utf8_lossy(&buf[start..start + length]).to_string()));
+
+                let response = format!("HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn local() -> Outgoing {
+        Outgoing::new(REQUEST_TIMEOUT, vec!["127.0.0.1".to_string()]).unwrap()
+    }
+
+    fn header<'a>(received: &'a Received, name: &str) -> &'a str {
+        received.0.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()).unwrap_or_default()
+    }
+
+    #[test]
+    fn sign_matches_a_known_vector() {
+        let body = r#"{"id":"1","type":"member_join"}"#;
+        assert_eq!(sign("whsec_test", 1700000000, body), "32154478da4dfd1c8d9539b6dd6bdf74f736f0bfbba01968bb1cdd5ba11a23c6");
+        assert_ne!(sign("whsec_test", 1700000001, body), sign("whsec_test", 1700000000, body));
+    }
+
+    #[rocket::async_test]
+    async fn deliveries_are_signed() {
+        let (url, mut rx) = receiver(204, String::new()).await;
+        let id = ids::generate();
+        let attempt = send(&local(), &url, "secret", id, "member_join", r#"{"user_id":"1"}"#.to_string()).await;
+        assert!(attempt.succeeded());
+        assert_eq!(attempt.error(), None);
+
+        let received = rx.recv().await.unwrap();
+        assert_eq!(received.1, r#"{"user_id":"1"}"#);
+        assert_eq!(header(&received, "x-helix-delivery"), id.to_string());
+        assert_eq!(header(&received, "x-helix-event"), "member_join");
+        let timestamp = header(&received, "x-helix-timestamp").parse().unwrap();
+        assert_eq!(header(&received, "x-helix-signature"), format!("sha256={}", sign("secret", timestamp, &received.1)));
+    }
+
+    #[rocket::async_test]
+    async fn private_addresses_are_refused() {
+        let (url, mut rx) = receiver(200, String::new()).await;
+        let public_only = Outgoing::new(REQUEST_TIMEOUT, Vec::new()).unwrap();
+
+        for url in [url.clone(), url.replace("127.0.0.1", "localhost"), "http://169.254.169.254/latest/meta-data".to_string()] {
+            let attempt = send(&public_only, &url, "secret", ids::generate(), "member_join", "{}".to_string()).await;
+            assert!(!attempt.succeeded(), "{url} was reached");
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[rocket::async_test]
+    async fn only_the_start_of_an_error_answer_is_logged() {
+        let (url, mut rx) = receiver(500, "e".repeat(MAX_LOGGED_BODY * 2)).await;
+        let attempt = send(&local(), &url, "secret", ids::generate(), "member_join", "{}".to_string()).await;
+        assert!(rx.recv().await.is_some());
+        assert!(!attempt.succeeded());
+        assert_eq!(attempt.error().as_deref(), Some("The receiver answered with status 500"));
+        assert!(matches!(&attempt.result, Ok((500, body)) if body.chars().count() == MAX_LOGGED_BODY));
+    }
+
+    #[test]
+    fn failed_attempts_are_retried_with_a_growing_delay() {
+        let now = Utc::now();
+        let delays = (1..MAX_ATTEMPTS).map(|attempts| match next_state(attempts, false, now) {
+            (DeliveryStatus::Pending, Some(at)) => (at - now).num_seconds(),
+            state => panic!("unexpected state {state:?} after attempt {attempts}"),
+        }).collect::<Vec<_>>();
+        assert_eq!(delays, vec![30, 60, 120, 240, 480, 960, 1920]);
+        assert_eq!(next_state(MAX_ATTEMPTS, false, now), (DeliveryStatus::Failed, None));
+        assert_eq!(next_state(1, true, now), (DeliveryStatus::Succeeded, None));
+    }
+
+    #[test]
+    fn subscriptions_are_disabled_after_too_many_failures() {
+        assert!(!disables(MAX_FAILURES - 1));
+        assert!(disables(MAX_FAILURES));
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs a MySQL database in DATABASE_URL"]
+    async fn failed_deliveries_disable_the_subscription() {
+        let pool = sqlx::mysql::MySqlPoolOptions::new().connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let (url, mut rx) = receiver(500, String::new()).await;
+        let input = SubscriptionInput { url, events: vec![EventType::MemberJoin] };
+        let subscription = Subscription::create(ids::generate(), ids::generate(), input, &pool).await.unwrap().subscription;
+
+        // each delivery is on its last attempt, so one failure each counts against the subscription
+        for _ in 0..MAX_FAILURES {
+            Delivery::enqueue(subscription.id, EventType::MemberJoin, subscription.channel_id, &json!({}), &pool).await.unwrap();
+        }
+        sqlx::query("UPDATE event_deliveries SET `attempts`=? WHERE `subscription_id`=?;")
+            .bind(MAX_ATTEMPTS - 1)
+            .bind(subscription.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        deliver_due(&local(), &pool).await.unwrap();
+        for _ in 0..MAX_FAILURES {
+            assert!(rx.recv().await.is_some());
+        }
+
+        let subscription = Subscription::from_id(subscription.channel_id, subscription.id, &pool).await.unwrap();
+        assert_eq!(subscription.failure_count, MAX_FAILURES);
+        assert!(!subscription.enabled);
+        assert!(subscription.disabled_reason.is_some());
+        let failed = Delivery::list(subscription.id, Some(DeliveryStatus::Failed), None, MAX_PAGE_SIZE, &pool).await.unwrap();
+        assert_eq!(failed.len(), MAX_FAILURES as usize);
+        assert!(failed.iter().all(|delivery| delivery.attempts == MAX_ATTEMPTS && delivery.response_status == Some(500)));
+
+        // deliveries of a disabled subscription fail without being sent
+        Delivery::enqueue(subscription.id, EventType::MemberJoin, subscription.channel_id, &json!({}), &pool).await.unwrap();
+        deliver_due(&local(), &pool).await.unwrap();
+        assert!(rx.try_recv().is_err());
+        let pending = Delivery::list(subscription.id, Some(DeliveryStatus::Pending), None, MAX_PAGE_SIZE, &pool).await.unwrap();
+        assert!(pending.is_empty());
+
+        subscription.delete(&pool).await.unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rocket::{fairing::AdHoc, http::Status, serde::json::Json, tokio, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    applications::check_not_token,
+    channels::{check_input, Channel, ChannelKind},
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    members::Member,
+    messages::Message,
+    permissions::{check_permission, Permissions},
+    users::User,
+};
+
+/// Inactivity delays a thread can be archived after, in minutes.
+const AUTO_ARCHIVE_MINUTES: [u32; 4] = [60, 1440, 4320, 10080];
+const DEFAULT_AUTO_ARCHIVE_MINUTES: u32 = 1440;
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SELECT_THREAD: &str = "SELECT t.`channel_id`, t.`parent_id`, t.`message_id`, t.`archived`, t.`auto_archive_minutes`, t.`last_activity_at`, \
+    c.`name`, c.`owner` FROM threads t JOIN channels c ON c.`id`=t.`channel_id`";
+
+/// Thread metadata, the thread itself is a channel with the same id.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Thread {
+    pub id: Snowflake,
+    pub parent_id: Snowflake,
+    /// Message the thread was spawned from.
+    pub message_id: Snowflake,
+    pub name: String,
+    pub owner: Snowflake,
+    pub archived: bool,
+    pub auto_archive_minutes: u32,
+    pub last_activity_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ThreadInput {
+    pub name: String,
+    pub auto_archive_minutes: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ThreadPatch {
+    pub archived: Option<bool>,
+    pub auto_archive_minutes: Option<u32>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Thread, sqlx::error::Error> {
+    Ok(Thread {
+        id: row.try_get("channel_id")?,
+        parent_id: row.try_get("parent_id")?,
+        message_id: row.try_get("message_id")?,
+        name: row.try_get("name")?,
+        owner: row.try_get("owner")?,
+        archived: row.try_get("archived")?,
+        auto_archive_minutes: row.try_get("auto_archive_minutes")?,
+        last_activity_at: row.try_get("last_activity_at")?,
+    })
+}
+
+impl Thread {
+    pub async fn from_id(id: Snowflake, pool: &Pool<MySql>) -> Result<Thread, sqlx::error::Error> {
+        let q = sqlx::query(&format!("{SELECT_THREAD} WHERE t.`channel_id`=?;"))
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(parent_id: Snowflake, archived: bool, pool: &Pool<MySql>) -> Result<Vec<Thread>, sqlx::error::Error> {
+        let q = sqlx::query(&format!("{SELECT_THREAD} WHERE t.`parent_id`=? AND t.`archived`=? ORDER BY t.`last_activity_at` DESC;"))
+            .bind(parent_id)
+            .bind(archived)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    /// Creates the thread channel with its owner as first member.
+    pub async fn create(parent: &Channel, message_id: Snowflake, owner: Snowflake, name: String, auto_archive_minutes: u32, pool: &Pool<MySql>) -> Result<Thread, sqlx::error::Error> {
+        let thread = Thread {
+            id: ids::generate(),
+            parent_id: parent.id,
+            message_id,
+            name,
+            owner,
+            archived: false,
+            auto_archive_minutes,
+            last_activity_at: Utc::now(),
+        };
+
+        let mut tx = pool.begin().await?;
+        sqlx::query("INSERT INTO channels (`id`, `kind`, `name`, `owner`, `default_permissions`, `parent_id`) VALUES (?, ?, ?, ?, ?, ?);")
+            .bind(thread.id)
+            .bind(ChannelKind::Thread.as_str())
+            .bind(&thread.name)
+            .bind(thread.owner)
+            .bind(Permissions::DEFAULT.bits())
+            .bind(thread.parent_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("INSERT INTO channel_members (`channel_id`, `user_id`) VALUES (?, ?);")
+            .bind(thread.id)
+            .bind(thread.owner)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("INSERT INTO threads (`channel_id`, `parent_id`, `message_id`, `auto_archive_minutes`, `last_activity_at`) VALUES (?, ?, ?, ?, ?);")
+            .bind(thread.id)
+            .bind(thread.parent_id)
+            .bind(thread.message_id)
+            .bind(thread.auto_archive_minutes)
+            .bind(thread.last_activity_at)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(thread)
+    }
+
+    pub async fn save(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("UPDATE threads SET `archived`=?, `auto_archive_minutes`=?, `last_activity_at`=? WHERE `channel_id`=?;")
+            .bind(self.archived)
+            .bind(self.auto_archive_minutes)
+            .bind(self.last_activity_at)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Records activity in a thread, returns it if it had to be unarchived.
+pub async fn touch(id: Snowflake, pool: &Pool<MySql>) -> Result<Option<Thread>, sqlx::error::Error> {
+    let q = sqlx::query("UPDATE threads SET `archived`=FALSE, `last_activity_at`=? WHERE `channel_id`=? AND `archived`=TRUE;")
+        .bind(Utc::now())
+        .bind(id)
+        .execute(pool)
+        .await?;
+    if q.rows_affected() > 0 { return Ok(Some(Thread::from_id(id, pool).await?)) }
+
+    sqlx::query("UPDATE threads SET `last_activity_at`=? WHERE `channel_id`=?;")
+        .bind(Utc::now())
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(None)
+}
+
+/// Archives every thread inactive for longer than its delay.
+async fn archive_inactive(pool: &Pool<MySql>, bus: &Bus) -> Result<(), sqlx::error::Error> {
+    const EXPIRED: &str = "`archived`=FALSE AND DATE_ADD(`last_activity_at`, INTERVAL `auto_archive_minutes` MINUTE)<?";
+
+    let now = Utc::now();
+    let q = sqlx::query(&format!("SELECT `channel_id` FROM threads WHERE {EXPIRED};"))
+        .bind(now)
+        .fetch_all(pool)
+        .await?;
+
+    for row in &q {
+        let id: Snowflake = row.try_get("channel_id")?;
+        // a message may have been sent in the meantime
+        let archived = sqlx::query(&format!("UPDATE threads SET `archived`=TRUE WHERE `channel_id`=? AND {EXPIRED};"))
+            .bind(id)
+            .bind(now)
+            .execute(pool)
+            .await?;
+
+        if archived.rows_affected() > 0 {
+            let thread = Thread::from_id(id, pool).await?;
+            bus.publish(thread.parent_id, Event::ThreadUpdate(thread));
+        }
+    }
+
+    Ok(())
+}
+
+/// Background task archiving inactive threads.
+pub fn archiver() -> AdHoc {
+    AdHoc::on_liftoff("Thread archiver", |rocket| Box::pin(async move {
+        let (Some(pool), Some(bus)) = (rocket.state::<Pool<MySql>>(), rocket.state::<Bus>()) else { return };
+        let (pool, bus) = (pool.clone(), bus.clone());
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ARCHIVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = archive_inactive(&pool, &bus).await {
+                    eprintln!("\x1b[31mCannot archive inactive threads: {e}\x1b[0m");
+                }
+            }
+        });
+    }))
+}
+
+fn check_auto_archive(minutes: u32) -> Result<(), Error> {
+    if AUTO_ARCHIVE_MINUTES.contains(&minutes) { return Ok(()) }
+
+    Err(Error::new(Status::BadRequest, format!("auto_archive_minutes must be one of {AUTO_ARCHIVE_MINUTES:?}"), "Check the body of your request".to_string()))
+}
+
+async fn thread_channel(id: Snowflake, pool: &Pool<MySql>) -> Result<Channel, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    if channel.kind != ChannelKind::Thread {
+        return Err(Error::new(Status::BadRequest, "This channel is not a thread".to_string(), "Check the channel id".to_string()));
+    }
+
+    Ok(channel)
+}
+
+#[post("/channels/<id>/messages/<mid>/threads", data = "<input>")]
+async fn create_thread(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, mid: Snowflake, input: Json<ThreadInput>) -> Result<Json<Thread>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    if channel.kind != ChannelKind::Text {
+        return Err(Error::new(Status::BadRequest, "Threads can only be started in text channels".to_string(), "Reply to the message instead".to_string()));
+    }
+    check_permission(&user, &channel, Permissions::SEND, pool).await?;
+    let message = Message::from_id(channel.id, mid, pool).await?;
+    if message.thread_id.is_some() {
+        return Err(Error::new(Status::Conflict, "A thread was already started from this message".to_string(), "Join the existing thread".to_string()));
+    }
+
+    let input = input.into_inner();
+    check_input(Some(&input.name), None, pool).await?;
+    let auto_archive_minutes = input.auto_archive_minutes.unwrap_or(DEFAULT_AUTO_ARCHIVE_MINUTES);
+    check_auto_archive(auto_archive_minutes)?;
+
+    let thread = Thread::create(&channel, message.id, user.id, input.name, auto_archive_minutes, pool).await?;
+    bus.publish(channel.id, Event::ThreadCreate(thread.clone()));
+    Ok(Json(thread))
+}
+
+#[get("/channels/<id>/threads?<archived>")]
+async fn get_threads(pool: &State<Pool<MySql>>, user: User, id: Snowflake, archived: Option<bool>) -> Result<Json<Vec<Thread>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Thread::list(channel.id, archived.unwrap_or(false), pool).await?))
+}
+
+#[get("/channels/<id>/thread")]
+async fn get_thread(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Thread>, Error> {
+    let channel = thread_channel(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    Ok(Json(Thread::from_id(channel.id, pool).await?))
+}
+
+/// The thread owner or anyone managing the parent channel can archive it.
+#[patch("/channels/<id>/thread", data = "<input>")]
+async fn edit_thread(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake, input: Json<ThreadPatch>) -> Result<Json<Thread>, Error> {
+    let channel = thread_channel(id, pool).await?;
+    let required = if channel.owner == user.id { Permissions::SEND } else { Permissions::MANAGE_CHANNEL };
+    check_permission(&user, &channel, required, pool).await?;
+    let mut thread = Thread::from_id(channel.id, pool).await?;
+
+    if let Some(minutes) = input.auto_archive_minutes {
+        check_auto_archive(minutes)?;
+        thread.auto_archive_minutes = minutes;
+    }
+    if let Some(archived) = input.archived {
+        // unarchiving restarts the inactivity delay
+        if thread.archived && !archived { thread.last_activity_at = Utc::now() }
+        thread.archived = archived;
+    }
+
+    thread.save(pool).await?;
+    bus.publish(thread.parent_id, Event::ThreadUpdate(thread.clone()));
+    Ok(Json(thread))
+}
+
+#[put("/channels/<id>/thread-members/@me")]
+async fn join_thread(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake) -> Result<Status, Error> {
+    let channel = thread_channel(id, pool).await?;
+    check_permission(&user, &channel, Permissions::READ, pool).await?;
+
+    if Member::add(channel.id, user.id, pool).await? {
+        bus.publish(channel.id, Event::MemberJoin { channel_id: channel.id, user_id: user.id });
+    }
+    Ok(Status::NoContent)
+}
+
+#[delete("/channels/<id>/thread-members/@me")]
+async fn leave_thread(pool: &State<Pool<MySql>>, bus: &State<Bus>, user: User, id: Snowflake) -> Result<Status, Error> {
+    check_not_token(&user)?;
+    let channel = thread_channel(id, pool).await?;
+
+    if Member::remove(channel.id, user.id, pool).await? {
+        bus.publish(channel.id, Event::MemberLeave { channel_id: channel.id, user_id: user.id });
+    }
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create_thread, get_threads, get_thread, edit_thread, join_thread, leave_thread]
+}
@@ -0,0 +1,190 @@
+use chrono::Utc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{serde::json::Json, Request, Route, State};
+use sha3::{Sha3_256, Digest};
+use sqlx::{mysql::MySqlRow, Pool, MySql, Row};
+
+use super::{applications::{self, Scope}, errors::Error, ids::Snowflake, permissions::Permissions};
+
+/// Sessions are stored hashed so a database leak does not leak usable tokens.
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(token.as_bytes());
+
+    format!("{:x}", hasher.finalize())
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct User {
+    pub id: Snowflake,
+    pub name: String,
+    /// Bot users act for an application and authenticate with `Authorization: Bot <token>`.
+    pub bot: bool,
+    /// What the credentials allow, `None` for a user acting through their own session.
+    #[serde(skip)]
+    pub scopes: Option<Vec<Scope>>,
+}
+
+/// Expects the `id`, `name` and `bot` columns of the user, and the `scopes` of their application or OAuth2 token.
+fn from_row(row: &MySqlRow) -> Result<User, sqlx::error::Error> {
+    let bot = row.try_get("bot")?;
+    let scopes = row.try_get::<Option<String>, _>("scopes")?;
+    // a bot whose application was deleted keeps no scope
+    let scopes = if bot || scopes.is_some() { Some(applications::parse_scopes(scopes.as_deref())) } else { None };
+
+    Ok(User {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        bot,
+        scopes,
+    })
+}
+
+impl User {
+    pub async fn from_id(id: Snowflake, pool: &Pool<MySql>) -> Result<User, sqlx::error::Error> {
+        let q = sqlx::query("SELECT u.`id`, u.`name`, u.`bot`, a.`scopes` FROM users u LEFT JOIN applications a ON a.`id`=u.`id` WHERE u.`id`=?;")
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    /// Channel permissions the credentials let through.
+    pub fn allowed_permissions(&self) -> Permissions {
+        match &self.scopes {
+            Some(scopes) => applications::scope_permissions(scopes),
+            None => Permissions::ALL,
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().map(|scopes| scopes.contains(&scope)).unwrap_or(true)
+    }
+
+    /// Whether either user blocked the other.
+    pub async fn is_blocked_between(a: Snowflake, b: Snowflake, pool: &Pool<MySql>) -> Result<bool, sqlx::error::Error> {
+        let row = sqlx::query("SELECT `user_id` FROM user_blocks WHERE (`user_id`=? AND `blocked_id`=?) OR (`user_id`=? AND `blocked_id`=?);")
+            .bind(a)
+            .bind(b)
+            .bind(b)
+            .bind(a)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Sessions and OAuth2 access tokens, bots have no session so they cannot log in.
+    pub async fn from_token(token: &str, pool: &Pool<MySql>) -> Result<User, sqlx::error::Error> {
+        let q = sqlx::query("SELECT users.`id`, users.`name`, users.`bot`, sessions.`scopes` FROM sessions JOIN users ON users.`id`=sessions.`user_id` \
+            WHERE sessions.`token`=? AND sessions.`kind`<>'refresh' AND (sessions.`expires_at` IS NULL OR sessions.`expires_at`>?) AND NOT users.`bot`;")
+            .bind(hash_token(token))
+            .bind(Utc::now())
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn from_bot_token(token: &str, pool: &Pool<MySql>) -> Result<User, sqlx::error::Error> {
+        let q = sqlx::query("SELECT u.`id`, u.`name`, u.`bot`, a.`scopes` FROM applications a JOIN users u ON u.`id`=a.`id` WHERE a.`bot_token`=?;")
+            .bind(hash_token(token))
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    /// Reads an `Authorization` value, `Bearer <token>` for sessions and `Bot <token>` for bots.
+    pub async fn from_authorization(authorization: &str, pool: &Pool<MySql>) -> Result<User, Error> {
+        let (user, hint) = if let Some(token) = authorization.strip_prefix("Bot ") {
+            (User::from_bot_token(token, pool).await, "Reset the token of the application")
+        } else if let Some(token) = authorization.strip_prefix("Bearer ") {
+            (User::from_token(token, pool).await, "Log in again to get a new token")
+        } else {
+            return Err(Error::new(Status::Unauthorized, "Invalid authorization type".to_string(), "Send \"Bearer <token>\" or \"Bot <token>\"".to_string()));
+        };
+
+        match user {
+            Ok(user) => Ok(user),
+            Err(sqlx::Error::RowNotFound) => Err(Error::new(Status::Unauthorized, "Invalid authorization token".to_string(), hint.to_string())),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+async fn authenticate(req: &Request<'_>) -> Result<User, Error> {
+    let authorization = req.headers().get_one("Authorization").ok_or_else(|| Error::new(
+        Status::Unauthorized,
+        "Missing authorization token".to_string(),
+        "Send the header \"Authorization: Bearer <token>\", or \"Authorization: Bot <token>\" for bots".to_string(),
+    ))?;
+
+    let pool = req.rocket().state::<Pool<MySql>>().ok_or_else(|| Error::new(
+        Status::InternalServerError,
+        "Unable to acquire intern connection".to_string(),
+        "Retry later".to_string(),
+    ))?;
+
+    User::from_authorization(authorization, pool).await
+}
+
+/// Authenticated user, read from an `Authorization: Bearer <token>` or `Authorization: Bot <token>` header.
+/// The lookup is cached for the request, the rate limiter and the route share it.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.local_cache_async(authenticate(req)).await {
+            Ok(user) => Outcome::Success(user.clone()),
+            Err(err) => Outcome::Error((err.status(), err.clone())),
+        }
+    }
+}
+
+#[get("/users/@me/blocks")]
+async fn get_blocks(pool: &State<Pool<MySql>>, user: User) -> Result<Json<Vec<Snowflake>>, Error> {
+    applications::check_session(&user)?;
+    let q = sqlx::query("SELECT `blocked_id` FROM user_blocks WHERE `user_id`=? ORDER BY `created_at` DESC;")
+        .bind(user.id)
+        .fetch_all(pool.inner())
+        .await?;
+
+    Ok(Json(q.iter().map(|row| row.try_get("blocked_id")).collect::<Result<_, _>>()?))
+}
+
+#[put("/users/@me/blocks/<user_id>")]
+async fn block_user(pool: &State<Pool<MySql>>, user: User, user_id: Snowflake) -> Result<Status, Error> {
+    applications::check_session(&user)?;
+    if user_id == user.id {
+        return Err(Error::new(Status::BadRequest, "You cannot block yourself".to_string(), "Check the user id".to_string()));
+    }
+    let target = User::from_id(user_id, pool).await?;
+
+    sqlx::query("INSERT IGNORE INTO user_blocks (`user_id`, `blocked_id`) VALUES (?, ?);")
+        .bind(user.id)
+        .bind(target.id)
+        .execute(pool.inner())
+        .await?;
+
+    Ok(Status::NoContent)
+}
+
+#[delete("/users/@me/blocks/<user_id>")]
+async fn unblock_user(pool: &State<Pool<MySql>>, user: User, user_id: Snowflake) -> Result<Status, Error> {
+    applications::check_session(&user)?;
+    sqlx::query("DELETE FROM user_blocks WHERE `user_id`=? AND `blocked_id`=?;")
+        .bind(user.id)
+        .bind(user_id)
+        .execute(pool.inner())
+        .await?;
+
+    Ok(Status::NoContent)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_blocks, block_user, unblock_user]
+}
@@ -0,0 +1,259 @@
+use chrono::{DateTime, Utc};
+use rocket::{http::Status, serde::json::Json, Route, State};
+use sqlx::{mysql::MySqlRow, MySql, Pool, Row};
+
+use super::{
+    automod,
+    cdn::CdnId,
+    channels::{self, Channel},
+    embeds::{self, Embed},
+    errors::Error,
+    gateway::{Bus, Event},
+    ids::{self, Snowflake},
+    members::check_text_channel,
+    messages::{self, Message, MessageInput},
+    permissions::{check_permission, Permissions},
+    users::{hash_token, User},
+};
+
+const TOKEN_LENGTH: usize = 64;
+const MAX_NAME_LENGTH: usize = 80;
+const MAX_WEBHOOKS: i64 = 15;
+
+/// Posts into a channel through a secret URL, without a user account.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Webhook {
+    pub id: Snowflake,
+    pub channel_id: Snowflake,
+    pub name: String,
+    pub avatar: Option<CdnId>,
+    pub creator_id: Snowflake,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Only returned when the webhook is created or its token rotated, the token is stored hashed.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct WebhookWithToken {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub token: String,
+    /// Path to post messages to.
+    pub url: String,
+}
+
+/// How a webhook message is shown, overrides included.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WebhookAuthor {
+    pub id: Snowflake,
+    pub name: String,
+    pub avatar: Option<CdnId>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct WebhookInput {
+    pub name: String,
+    pub avatar: Option<CdnId>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct WebhookMessageInput {
+    #[serde(default)]
+    pub content: String,
+    /// Overrides the name of the webhook for this message.
+    pub username: Option<String>,
+    /// Overrides the avatar of the webhook for this message.
+    pub avatar: Option<CdnId>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+}
+
+fn from_row(row: &MySqlRow) -> Result<Webhook, sqlx::error::Error> {
+    Ok(Webhook {
+        id: row.try_get("id")?,
+        channel_id: row.try_get("channel_id")?,
+        name: row.try_get("name")?,
+        avatar: row.try_get::<Option<String>, _>("avatar")?.map(CdnId::new),
+        creator_id: row.try_get("creator_id")?,
+        created_at: row.try_get("created_at")?,
+    })
+}
+
+fn with_token(webhook: Webhook, token: String) -> WebhookWithToken {
+    let url = format!("/webhooks/{}/{}", webhook.id, token);
+    WebhookWithToken { webhook, token, url }
+}
+
+impl Webhook {
+    pub async fn from_id(channel_id: Snowflake, id: Snowflake, pool: &Pool<MySql>) -> Result<Webhook, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM webhooks WHERE `channel_id`=? AND `id`=?;")
+            .bind(channel_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    /// Fails with `RowNotFound` if the token does not match.
+    pub async fn from_token(id: Snowflake, token: &str, pool: &Pool<MySql>) -> Result<Webhook, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM webhooks WHERE `id`=? AND `token`=?;")
+            .bind(id)
+            .bind(hash_token(token))
+            .fetch_one(pool)
+            .await?;
+
+        from_row(&q)
+    }
+
+    pub async fn list(channel_id: Snowflake, pool: &Pool<MySql>) -> Result<Vec<Webhook>, sqlx::error::Error> {
+        let q = sqlx::query("SELECT * FROM webhooks WHERE `channel_id`=? ORDER BY `id` ASC;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+
+        q.iter().map(from_row).collect()
+    }
+
+    pub async fn create(channel_id: Snowflake, creator_id: Snowflake, input: WebhookInput, pool: &Pool<MySql>) -> Result<WebhookWithToken, sqlx::error::Error> {
+        let id = ids::generate();
+        let webhook = Webhook { id, channel_id, name: input.name, avatar: input.avatar, creator_id, created_at: id.created_at() };
+        let token = ids::random_string(TOKEN_LENGTH);
+
+        sqlx::query("INSERT INTO webhooks (`id`, `channel_id`, `name`, `avatar`, `token`, `creator_id`, `created_at`) VALUES (?, ?, ?, ?, ?, ?, ?);")
+            .bind(webhook.id)
+            .bind(webhook.channel_id)
+            .bind(&webhook.name)
+            .bind(webhook.avatar.as_ref().map(CdnId::as_str))
+            .bind(hash_token(&token))
+            .bind(webhook.creator_id)
+            .bind(webhook.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(with_token(webhook, token))
+    }
+
+    /// The previous URL stops working right away.
+    pub async fn rotate(self, pool: &Pool<MySql>) -> Result<WebhookWithToken, sqlx::error::Error> {
+        let token = ids::random_string(TOKEN_LENGTH);
+        sqlx::query("UPDATE webhooks SET `token`=? WHERE `id`=?;")
+            .bind(hash_token(&token))
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(with_token(self, token))
+    }
+
+    /// Messages it posted are kept.
+    pub async fn delete(&self, pool: &Pool<MySql>) -> Result<(), sqlx::error::Error> {
+        sqlx::query("DELETE FROM webhooks WHERE `id`=?;")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Error::new(Status::BadRequest, format!("A webhook name must be between 1 and {MAX_NAME_LENGTH} caracters"), "Check the body of your request".to_string()));
+    }
+
+    Ok(())
+}
+
+fn invalid_webhook() -> Error {
+    Error::new(Status::NotFound, "Unknown webhook".to_string(), "Check the webhook URL, its token may have been rotated".to_string())
+}
+
+#[get("/channels/<id>/webhooks")]
+async fn get_webhooks(pool: &State<Pool<MySql>>, user: User, id: Snowflake) -> Result<Json<Vec<Webhook>>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Ok(Json(Webhook::list(channel.id, pool).await?))
+}
+
+#[post("/channels/<id>/webhooks", data = "<input>")]
+async fn create_webhook(pool: &State<Pool<MySql>>, user: User, id: Snowflake, input: Json<WebhookInput>) -> Result<Json<WebhookWithToken>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_text_channel(&channel)?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let input = input.into_inner();
+    check_name(&input.name)?;
+    channels::check_input(None, input.avatar.as_ref(), pool).await?;
+
+    let count: i64 = sqlx::query("SELECT COUNT(*) AS `count` FROM webhooks WHERE `channel_id`=?;")
+        .bind(channel.id)
+        .fetch_one(pool.inner())
+        .await?
+        .try_get("count")?;
+    if count >= MAX_WEBHOOKS {
+        return Err(Error::new(Status::BadRequest, format!("A channel can have at most {MAX_WEBHOOKS} webhooks"), "Delete a webhook first".to_string()));
+    }
+
+    Ok(Json(Webhook::create(channel.id, user.id, input, pool).await?))
+}
+
+#[post("/channels/<id>/webhooks/<webhook_id>/token")]
+async fn rotate_token(pool: &State<Pool<MySql>>, user: User, id: Snowflake, webhook_id: Snowflake) -> Result<Json<WebhookWithToken>, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+    let webhook = Webhook::from_id(channel.id, webhook_id, pool).await?;
+
+    Ok(Json(webhook.rotate(pool).await?))
+}
+
+#[delete("/channels/<id>/webhooks/<webhook_id>")]
+async fn delete_webhook(pool: &State<Pool<MySql>>, user: User, id: Snowflake, webhook_id: Snowflake) -> Result<Status, Error> {
+    let channel = Channel::from_id(id, pool).await?;
+    check_permission(&user, &channel, Permissions::MANAGE_CHANNEL, pool).await?;
+
+    Webhook::from_id(channel.id, webhook_id, pool).await?.delete(pool).await?;
+    Ok(Status::NoContent)
+}
+
+/// Posts a message as the webhook. Rate limited per webhook by the `webhooks` group.
+#[post("/webhooks/<id>/<token>", data = "<input>")]
+async fn execute_webhook(pool: &State<Pool<MySql>>, bus: &State<Bus>, id: Snowflake, token: &str, input: Json<WebhookMessageInput>) -> Result<Json<Message>, Error> {
+    let webhook = match Webhook::from_token(id, token, pool).await {
+        Ok(webhook) => webhook,
+        Err(sqlx::Error::RowNotFound) => return Err(invalid_webhook()),
+        Err(err) => return Err(err.into()),
+    };
+    let input = input.into_inner();
+
+    messages::check_content(&input.content, 0, !input.embeds.is_empty())?;
+    embeds::check(&input.embeds, pool).await?;
+    if let Some(username) = &input.username { check_name(username)? }
+    channels::check_input(None, input.avatar.as_ref(), pool).await?;
+
+    let author = WebhookAuthor {
+        id: webhook.id,
+        name: input.username.unwrap_or(webhook.name),
+        avatar: input.avatar.or(webhook.avatar),
+    };
+    let message = MessageInput {
+        content: input.content,
+        attachments: Vec::new(),
+        reply_to: None,
+        poll: None,
+        ttl: None,
+        embeds: input.embeds,
+        webhook: Some(author),
+    };
+
+    // webhook messages follow the automod rules of the channel like any other
+    let channel = Channel::from_id(webhook.channel_id, pool).await?;
+    let flagged = automod::moderate_webhook(&channel, webhook.id, &message, pool).await?;
+    let message = Message::create(channel.id, webhook.id, message, pool).await?;
+    automod::flag(&message, &channel, &flagged, pool).await?;
+    bus.publish(message.channel_id, Event::MessageCreate(message.clone()));
+    Ok(Json(message))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get_webhooks, create_webhook, rotate_token, delete_webhook, execute_webhook]
+}
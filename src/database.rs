@@ -36,7 +36,13 @@ pub async fn init_database(archive: &Archive) -> Pool<MySql> {
         Ok(conn) => {
             let try_pool = MySqlPoolOptions::new().connect(conn.as_str()).await;
             match try_pool {
-                Ok(pool) => pool,
+                Ok(pool) => {
+                    if let Err(err) = sqlx::migrate!().run(&pool).await {
+                        println!("\x1b[31mCannot apply database migrations: {err:?}\x1b[0m");
+                        exit(3)
+                    }
+                    pool
+                },
                 Err(err) => {
                     println!("\x1b[31mCannot connect to MySQL pool: {err:?}\x1b[0m");
                     exit(3)
@@ -1,11 +1,12 @@
 use std::io::Cursor;
 
 use archive::Archive;
-use cmp::{cdn::{CdnId, string_to_content_type, self, CdnData}, errors::Error};
+use cmp::{cdn::{CdnId, string_to_content_type, self, CdnData}, dms::{self, DmConfig}, errors::Error, gateway::Bus, presence::Presences, ratelimit::{MemoryStore, RateLimiter}, applications, audit, automod, bookmarks, categories, channels, commands, gateway, ids, interactions::{self, Interactions}, invites, members, messages, moderation, oauth2, outgoing::Outgoing, pins, polls, presence, reactions, read_states, reports, scheduler, search, subscriptions, threads, users, webhooks};
 use database::init_database;
 use rocket::{http::{Status, ContentType}, Response, Request, response};
 use sqlx::{Pool, MySql};
 use rocket::response::Responder;
+use rocket::fairing::AdHoc;
 
 #[macro_use]
 extern crate rocket;
@@ -62,9 +63,49 @@ async fn main() -> Result<(), rocket::Error> {
     }
 
     // launch api
-    let _rocket = rocket::build()
+    let rocket = rocket::build();
+    ids::set_worker(rocket.figment().extract_inner::<u64>("worker_id").unwrap_or(0));
+    let allowed_hosts = Outgoing::allowed_hosts(rocket.figment());
+
+    let _rocket = rocket
         .manage(pool)
+        .manage(Bus::new())
+        .manage(Presences::new())
+        .manage(Interactions::new(allowed_hosts))
+        .attach(AdHoc::config::<DmConfig>())
+        .attach(RateLimiter::new(MemoryStore::new()))
+        .attach(threads::archiver())
+        .attach(polls::closer())
+        .attach(scheduler::runner())
+        .attach(subscriptions::runner())
         .mount("/", routes![index, get_cdn_test])
+        .mount("/", channels::routes())
+        .mount("/", categories::routes())
+        .mount("/", members::routes())
+        .mount("/", moderation::routes())
+        .mount("/", audit::routes())
+        .mount("/", automod::routes())
+        .mount("/", reports::routes())
+        .mount("/", webhooks::routes())
+        .mount("/", subscriptions::routes())
+        .mount("/", invites::routes())
+        .mount("/", users::routes())
+        .mount("/", applications::routes())
+        .mount("/", oauth2::routes())
+        .mount("/", commands::routes())
+        .mount("/", interactions::routes())
+        .mount("/", dms::routes())
+        .mount("/", messages::routes())
+        .mount("/", threads::routes())
+        .mount("/", reactions::routes())
+        .mount("/", search::routes())
+        .mount("/", read_states::routes())
+        .mount("/", presence::routes())
+        .mount("/", pins::routes())
+        .mount("/", polls::routes())
+        .mount("/", scheduler::routes())
+        .mount("/", bookmarks::routes())
+        .mount("/", gateway::routes())
         .launch()
         .await?;
 